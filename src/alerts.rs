@@ -0,0 +1,328 @@
+// Telemetry rules evaluated on every stored location: speeding, harsh braking and turning derived
+// from consecutive fixes, and long idling. Raised alerts are stored for the caller to publish.
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::geofence::contains;
+use crate::models::{Alert, AlertType, GeoPoint, Geofence, Location};
+
+// Class of the per-vehicle advisory locks, kept apart from the geofence and stop detection ones
+const TELEMETRY_LOCK_CLASS: i32 = 0x7465_6c65;
+
+// Fixes further apart than this say nothing about how the vehicle braked or turned in between
+const MAX_DERIVED_GAP_SECS: f64 = 30.0;
+
+// Below this speed headings are too noisy to judge turns by
+const HARSH_TURN_MIN_SPEED_KMH: f64 = 20.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AlertRules {
+    pub speed_limit_kmh: f64,
+    pub harsh_braking_kmh_per_sec: f64,
+    pub harsh_turn_degrees_per_sec: f64,
+    pub idle_alert: chrono::Duration,
+}
+
+impl AlertRules {
+    pub fn new(config: &Config) -> Self {
+        AlertRules {
+            speed_limit_kmh: config.speed_limit_kmh,
+            harsh_braking_kmh_per_sec: config.harsh_braking_kmh_per_sec,
+            harsh_turn_degrees_per_sec: config.harsh_turn_degrees_per_sec,
+            idle_alert: chrono::Duration::seconds(config.idle_alert_secs as i64),
+        }
+    }
+}
+
+// A vehicle's previous fix and ongoing episodes, as stored in `vehicle_telemetry`. Speeding and
+// idling raise one alert per episode rather than one per fix.
+#[derive(sqlx::FromRow)]
+struct Telemetry {
+    vehicle_id: Uuid,
+    speed: f64,
+    heading: f64,
+    recorded_at: DateTime<Utc>,
+    speeding: bool,
+    idle_since: Option<DateTime<Utc>>,
+    idle_reported: bool,
+}
+
+struct RaisedAlert<'a> {
+    alert_type: AlertType,
+    location: &'a Location,
+    geofence_id: Option<Uuid>,
+    value: f64,
+    threshold: f64,
+}
+
+// Smallest angle between two headings in degrees
+fn heading_change(from: f64, to: f64) -> f64 {
+    let difference = (to - from).rem_euclid(360.0);
+    difference.min(360.0 - difference)
+}
+
+// Check newly stored locations, in timestamp order per vehicle, and record the alerts they raise
+// inside the caller's transaction
+pub async fn evaluate_alerts(
+    conn: &mut sqlx::PgConnection,
+    locations: &[Location],
+    rules: &AlertRules,
+) -> Result<Vec<Alert>, actix_web::Error> {
+    if locations.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut vehicle_ids: Vec<Uuid> = locations.iter().map(|location| location.vehicle_id).collect();
+    vehicle_ids.sort();
+    vehicle_ids.dedup();
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext(v::text)) FROM UNNEST($2::uuid[]) AS v")
+        .bind(TELEMETRY_LOCK_CLASS)
+        .bind(&vehicle_ids)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    let type_limits: HashMap<Uuid, f64> = sqlx::query_as::<_, (Uuid, f64)>(
+        r#"
+        SELECT v.id, l.speed_limit_kmh FROM vehicles v
+        JOIN speed_limits l ON l.vehicle_type = v.vehicle_type
+        WHERE v.id = ANY($1)
+        "#
+    )
+    .bind(&vehicle_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .collect();
+
+    let limited_geofences = sqlx::query_as::<_, Geofence>(
+        "SELECT * FROM geofences WHERE speed_limit_kmh IS NOT NULL"
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut telemetry: HashMap<Uuid, Telemetry> = sqlx::query_as::<_, Telemetry>(
+        "SELECT * FROM vehicle_telemetry WHERE vehicle_id = ANY($1)"
+    )
+    .bind(&vehicle_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .map(|state| (state.vehicle_id, state))
+    .collect();
+
+    let mut ordered: Vec<&Location> = locations.iter().collect();
+    ordered.sort_by_key(|location| location.timestamp);
+
+    let mut raised = Vec::new();
+    for location in ordered {
+        let point = GeoPoint {
+            latitude: location.latitude,
+            longitude: location.longitude,
+        };
+
+        // The strictest geofence the vehicle is in sets the limit, then its type, then the default
+        let geofence_limit = limited_geofences
+            .iter()
+            .filter(|geofence| contains(geofence, point))
+            .filter_map(|geofence| geofence.speed_limit_kmh.map(|limit| (limit, geofence.id)))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        let (limit, geofence_id) = match geofence_limit {
+            Some((limit, geofence_id)) => (limit, Some(geofence_id)),
+            None => (type_limits.get(&location.vehicle_id).copied().unwrap_or(rules.speed_limit_kmh), None),
+        };
+
+        // Fixes arriving late would be compared against the wrong neighbours
+        if telemetry.get(&location.vehicle_id).is_some_and(|state| location.timestamp < state.recorded_at) {
+            continue;
+        }
+
+        let state = telemetry.entry(location.vehicle_id).or_insert_with(|| Telemetry {
+            vehicle_id: location.vehicle_id,
+            speed: location.speed,
+            heading: location.heading,
+            recorded_at: location.timestamp,
+            speeding: false,
+            idle_since: None,
+            idle_reported: false,
+        });
+
+        if location.speed > limit {
+            if !state.speeding {
+                raised.push(RaisedAlert {
+                    alert_type: AlertType::Speeding,
+                    location,
+                    geofence_id,
+                    value: location.speed,
+                    threshold: limit,
+                });
+            }
+            state.speeding = true;
+        } else {
+            state.speeding = false;
+        }
+
+        let elapsed = (location.timestamp - state.recorded_at).num_milliseconds() as f64 / 1000.0;
+        if elapsed > 0.0 && elapsed <= MAX_DERIVED_GAP_SECS {
+            let deceleration = (state.speed - location.speed) / elapsed;
+            if deceleration > rules.harsh_braking_kmh_per_sec {
+                raised.push(RaisedAlert {
+                    alert_type: AlertType::HarshBraking,
+                    location,
+                    geofence_id: None,
+                    value: deceleration,
+                    threshold: rules.harsh_braking_kmh_per_sec,
+                });
+            }
+
+            if state.speed >= HARSH_TURN_MIN_SPEED_KMH && location.speed >= HARSH_TURN_MIN_SPEED_KMH {
+                let turn_rate = heading_change(state.heading, location.heading) / elapsed;
+                if turn_rate > rules.harsh_turn_degrees_per_sec {
+                    raised.push(RaisedAlert {
+                        alert_type: AlertType::HarshTurn,
+                        location,
+                        geofence_id: None,
+                        value: turn_rate,
+                        threshold: rules.harsh_turn_degrees_per_sec,
+                    });
+                }
+            }
+        }
+
+        if location.speed <= 0.0 {
+            let idle_since = *state.idle_since.get_or_insert(location.timestamp);
+            let idle = location.timestamp - idle_since;
+            if !state.idle_reported && idle >= rules.idle_alert {
+                raised.push(RaisedAlert {
+                    alert_type: AlertType::Idling,
+                    location,
+                    geofence_id: None,
+                    value: idle.num_seconds() as f64,
+                    threshold: rules.idle_alert.num_seconds() as f64,
+                });
+                state.idle_reported = true;
+            }
+        } else {
+            state.idle_since = None;
+            state.idle_reported = false;
+        }
+
+        state.speed = location.speed;
+        state.heading = location.heading;
+        state.recorded_at = location.timestamp;
+    }
+
+    let mut alerts = Vec::with_capacity(raised.len());
+    for alert in &raised {
+        let alert = sqlx::query_as::<_, Alert>(
+            r#"
+            INSERT INTO alerts (
+                alert_type, vehicle_id, driver_id, route_id, location_id, geofence_id,
+                latitude, longitude, value, threshold, occurred_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING *
+            "#
+        )
+        .bind(alert.alert_type)
+        .bind(alert.location.vehicle_id)
+        .bind(alert.location.driver_id)
+        .bind(alert.location.route_id)
+        .bind(alert.location.id)
+        .bind(alert.geofence_id)
+        .bind(alert.location.latitude)
+        .bind(alert.location.longitude)
+        .bind(alert.value)
+        .bind(alert.threshold)
+        .bind(alert.location.timestamp)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        alerts.push(alert);
+    }
+
+    for state in telemetry.values() {
+        sqlx::query(
+            r#"
+            INSERT INTO vehicle_telemetry (vehicle_id, speed, heading, recorded_at, speeding, idle_since, idle_reported)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (vehicle_id) DO UPDATE
+            SET speed = EXCLUDED.speed,
+                heading = EXCLUDED.heading,
+                recorded_at = EXCLUDED.recorded_at,
+                speeding = EXCLUDED.speeding,
+                idle_since = EXCLUDED.idle_since,
+                idle_reported = EXCLUDED.idle_reported
+            "#
+        )
+        .bind(state.vehicle_id)
+        .bind(state.speed)
+        .bind(state.heading)
+        .bind(state.recorded_at)
+        .bind(state.speeding)
+        .bind(state.idle_since)
+        .bind(state.idle_reported)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+    }
+
+    Ok(alerts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turning_through_north_takes_the_short_way() {
+        assert_eq!(heading_change(359.0, 1.0), 2.0);
+        assert_eq!(heading_change(1.0, 359.0), 2.0);
+        assert_eq!(heading_change(359.5, 0.5), 1.0);
+    }
+
+    #[test]
+    fn change_is_symmetric_and_at_most_half_a_turn() {
+        assert_eq!(heading_change(0.0, 180.0), 180.0);
+        assert_eq!(heading_change(90.0, 270.0), 180.0);
+        assert_eq!(heading_change(10.0, 200.0), 170.0);
+        assert_eq!(heading_change(200.0, 10.0), 170.0);
+    }
+
+    #[test]
+    fn same_heading_is_no_change() {
+        assert_eq!(heading_change(42.0, 42.0), 0.0);
+        assert_eq!(heading_change(0.0, 360.0), 0.0);
+    }
+
+    #[test]
+    fn headings_outside_0_to_360_are_normalized() {
+        assert_eq!(heading_change(-10.0, 10.0), 20.0);
+        assert_eq!(heading_change(370.0, 10.0), 0.0);
+        assert_eq!(heading_change(-90.0, 90.0), 180.0);
+    }
+}
@@ -1,60 +1,338 @@
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
-use std::env;
-use chrono::{Duration, Utc};
-use bcrypt::{hash, verify, DEFAULT_COST};
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: String, // User ID
-    pub username: String,
-    pub role: String,
-    pub exp: i64, // Expiration time
-    pub iat: i64, // Issued at
-}
-
-pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
-    hash(password, DEFAULT_COST)
-}
-
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::BcryptError> {
-    verify(password, hash)
-}
-
-pub fn create_token(user_id: &str, username: &str, role: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
-    
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::hours(24))
-        .expect("valid timestamp")
-        .timestamp();
-    
-    let claims = Claims {
-        sub: user_id.to_string(),
-        username: username.to_string(),
-        role: role.to_string(),
-        exp: expiration,
-        iat: Utc::now().timestamp(),
-    };
-    
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
-}
-
-pub fn validate_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "your-secret-key".to_string());
-    
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::default(),
-    )
-    .map(|data| data.claims)
-}
-
-pub fn extract_token_from_header(auth_header: &str) -> Option<String> {
-    if auth_header.starts_with("Bearer ") {
-        Some(auth_header[7..].to_string())
-    } else {
-        None
-    }
-}
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::{OnceLock, RwLock};
+use chrono::{Duration, Utc};
+use bcrypt::{hash, verify, DEFAULT_COST};
+use jsonwebtoken::errors::ErrorKind;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::database::DbPool;
+use crate::models::UserRole;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String, // User ID
+    pub username: String,
+    pub role: UserRole,
+    pub jti: String, // Token ID, used for revocation
+    pub exp: i64, // Expiration time
+    pub iat: i64, // Issued at
+}
+
+pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
+    hash(password, DEFAULT_COST)
+}
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::BcryptError> {
+    verify(password, hash)
+}
+
+pub fn create_token(
+    user_id: &str,
+    username: &str,
+    role: UserRole,
+    config: &Config,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let expiration = Utc::now()
+        .checked_add_signed(config.access_token_ttl())
+        .expect("valid timestamp")
+        .timestamp();
+
+    let claims = Claims {
+        sub: user_id.to_string(),
+        username: username.to_string(),
+        role,
+        jti: Uuid::new_v4().to_string(),
+        exp: expiration,
+        iat: Utc::now().timestamp(),
+    };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(config.jwt_secret.as_ref()))
+}
+
+pub fn validate_token(token: &str, config: &Config) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)?;
+
+    let (Ok(user_id), Ok(jti)) = (Uuid::parse_str(&claims.sub), Uuid::parse_str(&claims.jti)) else {
+        return Err(ErrorKind::InvalidToken.into());
+    };
+
+    if is_revoked(user_id, jti, claims.iat) {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+
+    Ok(claims)
+}
+
+// Opaque tokens (refresh, invitation, email verification) are handed out as `<row id>.<secret>`;
+// only a bcrypt hash of the secret is stored
+pub fn generate_opaque_token(id: Uuid) -> (String, String) {
+    let secret = Uuid::new_v4().simple().to_string();
+    (format!("{}.{}", id, secret), secret)
+}
+
+pub fn split_opaque_token(token: &str) -> Option<(Uuid, &str)> {
+    let (id, secret) = token.split_once('.')?;
+    Some((Uuid::parse_str(id).ok()?, secret))
+}
+
+// Revoked access tokens, mirrored from the `revoked_tokens` table and `users.tokens_revoked_at`
+#[derive(Default)]
+struct RevocationList {
+    tokens: HashMap<Uuid, i64>, // jti -> token expiry
+    users: HashMap<Uuid, i64>, // user id -> tokens issued at or before this are revoked
+}
+
+static REVOCATIONS: OnceLock<RwLock<RevocationList>> = OnceLock::new();
+
+fn revocations() -> &'static RwLock<RevocationList> {
+    REVOCATIONS.get_or_init(Default::default)
+}
+
+fn is_revoked(user_id: Uuid, jti: Uuid, issued_at: i64) -> bool {
+    let Ok(list) = revocations().read() else {
+        return true;
+    };
+
+    list.tokens.contains_key(&jti)
+        || list.users.get(&user_id).is_some_and(|revoked_at| issued_at <= *revoked_at)
+}
+
+pub fn revoke_token(jti: Uuid, expires_at: i64) {
+    if let Ok(mut list) = revocations().write() {
+        let now = Utc::now().timestamp();
+        list.tokens.retain(|_, exp| *exp > now);
+        list.tokens.insert(jti, expires_at);
+    }
+}
+
+// A cutoff older than an access token's lifetime can no longer reject anything, so those are dropped
+pub fn revoke_user_tokens(user_id: Uuid, revoked_at: i64, access_token_ttl: Duration) {
+    if let Ok(mut list) = revocations().write() {
+        let oldest = (Utc::now() - access_token_ttl).timestamp();
+        list.users.retain(|_, cutoff| *cutoff > oldest);
+        if revoked_at > oldest {
+            list.users.insert(user_id, revoked_at);
+        }
+    }
+}
+
+// Revocations are applied locally right away and sent to every other instance on this channel
+pub const REVOCATION_CHANNEL: &str = "token_revocations";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Revocation {
+    Token { jti: Uuid, expires_at: i64 },
+    User { user_id: Uuid, revoked_at: i64 },
+}
+
+pub fn apply_revocation(revocation: &Revocation, access_token_ttl: Duration) {
+    match *revocation {
+        Revocation::Token { jti, expires_at } => revoke_token(jti, expires_at),
+        Revocation::User { user_id, revoked_at } => revoke_user_tokens(user_id, revoked_at, access_token_ttl),
+    }
+}
+
+// Apply a revocation already stored in the database here and notify every instance of it, whose
+// listener also disconnects the live tracking sessions opened with the revoked tokens
+pub async fn broadcast_revocation(pool: &DbPool, config: &Config, revocation: Revocation) -> Result<(), actix_web::Error> {
+    apply_revocation(&revocation, config.access_token_ttl());
+
+    let payload = serde_json::to_string(&revocation).map_err(|e| {
+        eprintln!("Failed to serialize revocation: {}", e);
+        actix_web::error::ErrorInternalServerError("Failed to revoke tokens")
+    })?;
+
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(REVOCATION_CHANNEL)
+        .bind(payload)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    Ok(())
+}
+
+// Also run whenever the listener reconnects, to pick up revocations notified while it was away
+pub async fn load_revocations(pool: &DbPool, access_token_ttl: Duration) -> Result<(), sqlx::Error> {
+    let tokens = sqlx::query_as::<_, (Uuid, chrono::DateTime<Utc>)>(
+        "SELECT jti, expires_at FROM revoked_tokens WHERE expires_at > NOW()"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // Only cutoffs younger than an access token can still reject anything
+    let users = sqlx::query_as::<_, (Uuid, chrono::DateTime<Utc>)>(
+        "SELECT id, tokens_revoked_at FROM users WHERE tokens_revoked_at > $1"
+    )
+    .bind(Utc::now() - access_token_ttl)
+    .fetch_all(pool)
+    .await?;
+
+    for (jti, expires_at) in tokens {
+        revoke_token(jti, expires_at.timestamp());
+    }
+    for (user_id, revoked_at) in users {
+        revoke_user_tokens(user_id, revoked_at.timestamp(), access_token_ttl);
+    }
+
+    Ok(())
+}
+
+pub fn extract_token_from_header(auth_header: &str) -> Option<String> {
+    auth_header.strip_prefix("Bearer ").map(|token| token.to_string())
+}
+
+// Authenticated caller, extracted from the `Authorization: Bearer <token>` header
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub username: String,
+    pub role: UserRole,
+    pub token_id: Uuid,
+    pub token_issued_at: i64,
+    pub token_expires_at: i64,
+}
+
+impl AuthUser {
+    pub fn from_request_headers(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let auth_header = req.headers()
+            .get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
+
+        let token = extract_token_from_header(auth_header)
+            .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
+
+        let config = req.app_data::<web::Data<Config>>()
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("Configuration not available"))?;
+
+        Self::from_token(&token, config)
+    }
+
+    // Validate a bare access token, e.g. one presented when opening a WebSocket
+    pub fn from_token(token: &str, config: &Config) -> Result<Self, actix_web::Error> {
+        let claims = validate_token(token, config)
+            .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
+
+        let id = Uuid::parse_str(&claims.sub)
+            .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
+
+        let token_id = Uuid::parse_str(&claims.jti)
+            .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
+
+        Ok(AuthUser {
+            id,
+            username: claims.username,
+            role: claims.role,
+            token_id,
+            token_issued_at: claims.iat,
+            token_expires_at: claims.exp,
+        })
+    }
+
+    // Whether the token this caller authenticated with has been revoked since
+    pub fn is_revoked(&self) -> bool {
+        is_revoked(self.id, self.token_id, self.token_issued_at)
+    }
+
+    pub fn has_role(&self, roles: &[UserRole]) -> bool {
+        roles.contains(&self.role)
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(AuthUser::from_request_headers(req))
+    }
+}
+
+// Declares which roles may call an endpoint guarded by `Authorized<G>`
+pub trait RoleGuard {
+    const ROLES: &'static [UserRole];
+    const DENIED: &'static str;
+}
+
+pub struct AdminOnly;
+
+impl RoleGuard for AdminOnly {
+    const ROLES: &'static [UserRole] = &[UserRole::Admin];
+    const DENIED: &'static str = "Only admins can perform this action";
+}
+
+pub struct AdminOrManager;
+
+impl RoleGuard for AdminOrManager {
+    const ROLES: &'static [UserRole] = &[UserRole::Admin, UserRole::Manager];
+    const DENIED: &'static str = "Only admins and managers can perform this action";
+}
+
+pub struct AdminManagerOrDispatcher;
+
+impl RoleGuard for AdminManagerOrDispatcher {
+    const ROLES: &'static [UserRole] = &[UserRole::Admin, UserRole::Manager, UserRole::Dispatcher];
+    const DENIED: &'static str = "Only admins, managers and dispatchers can perform this action";
+}
+
+pub struct DriverOnly;
+
+impl RoleGuard for DriverOnly {
+    const ROLES: &'static [UserRole] = &[UserRole::Driver];
+    const DENIED: &'static str = "Only drivers can perform this action";
+}
+
+// Everyone who works the fleet; customers only see their own shipments
+pub struct StaffOrDriver;
+
+impl RoleGuard for StaffOrDriver {
+    const ROLES: &'static [UserRole] = &[UserRole::Admin, UserRole::Manager, UserRole::Dispatcher, UserRole::Driver];
+    const DENIED: &'static str = "Customers cannot access fleet data";
+}
+
+// Authenticated caller whose role is accepted by `G`, rejected with 403 otherwise
+pub struct Authorized<G: RoleGuard> {
+    pub user: AuthUser,
+    _guard: PhantomData<G>,
+}
+
+impl<G: RoleGuard> Deref for Authorized<G> {
+    type Target = AuthUser;
+
+    fn deref(&self) -> &AuthUser {
+        &self.user
+    }
+}
+
+impl<G: RoleGuard> FromRequest for Authorized<G> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let result = AuthUser::from_request_headers(req).and_then(|user| {
+            if user.has_role(G::ROLES) {
+                Ok(Authorized { user, _guard: PhantomData })
+            } else {
+                Err(actix_web::error::ErrorForbidden(G::DENIED))
+            }
+        });
+
+        ready(result)
+    }
+}
@@ -1,196 +1,764 @@
-use sqlx::{PgPool, PgPoolOptions, postgres::PgPoolOptions as PgPoolOptionsPostgres};
-use log::info;
-use std::time::Duration;
-
-pub type DbPool = PgPool;
-
-pub async fn create_pool(database_url: &str) -> Result<DbPool, sqlx::Error> {
-    PgPoolOptions::new()
-        .max_connections(20)
-        .acquire_timeout(Duration::from_secs(3))
-        .idle_timeout(Duration::from_secs(10))
-        .max_lifetime(Duration::from_secs(30))
-        .connect(database_url)
-        .await
-}
-
-pub async fn init_database(database_url: &str) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Initializing database...");
-    
-    let pool = create_pool(database_url).await?;
-    
-    // Create custom types
-    sqlx::query(
-        r#"
-        DO $$ BEGIN
-            CREATE TYPE user_role AS ENUM ('admin', 'manager', 'driver', 'dispatcher');
-        EXCEPTION
-            WHEN duplicate_object THEN null;
-        END $$;
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        DO $$ BEGIN
-            CREATE TYPE vehicle_status AS ENUM ('available', 'inuse', 'maintenance', 'outofservice');
-        EXCEPTION
-            WHEN duplicate_object THEN null;
-        END $$;
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        DO $$ BEGIN
-            CREATE TYPE cargo_priority AS ENUM ('low', 'medium', 'high', 'urgent');
-        EXCEPTION
-            WHEN duplicate_object THEN null;
-        END $$;
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        DO $$ BEGIN
-            CREATE TYPE cargo_status AS ENUM ('pending', 'assigned', 'intransit', 'delivered', 'cancelled');
-        EXCEPTION
-            WHEN duplicate_object THEN null;
-        END $$;
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        DO $$ BEGIN
-            CREATE TYPE route_status AS ENUM ('planned', 'inprogress', 'completed', 'cancelled');
-        EXCEPTION
-            WHEN duplicate_object THEN null;
-        END $$;
-        "#
-    ).execute(&pool).await?;
-
-    // Create tables
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-            username VARCHAR(50) UNIQUE NOT NULL,
-            email VARCHAR(100) UNIQUE NOT NULL,
-            password_hash VARCHAR(255) NOT NULL,
-            role user_role NOT NULL,
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
-        );
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS vehicles (
-            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-            license_plate VARCHAR(20) UNIQUE NOT NULL,
-            make VARCHAR(50) NOT NULL,
-            model VARCHAR(50) NOT NULL,
-            year INTEGER NOT NULL,
-            capacity DOUBLE PRECISION NOT NULL,
-            fuel_type VARCHAR(20) NOT NULL,
-            status vehicle_status DEFAULT 'available',
-            driver_id UUID REFERENCES users(id),
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
-        );
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS cargo (
-            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-            description TEXT NOT NULL,
-            weight DOUBLE PRECISION NOT NULL,
-            volume DOUBLE PRECISION NOT NULL,
-            cargo_type VARCHAR(50) NOT NULL,
-            priority cargo_priority DEFAULT 'medium',
-            status cargo_status DEFAULT 'pending',
-            shipper_id UUID REFERENCES users(id),
-            consignee_id UUID REFERENCES users(id),
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
-        );
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS routes (
-            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-            source_address TEXT NOT NULL,
-            source_lat DOUBLE PRECISION NOT NULL,
-            source_lng DOUBLE PRECISION NOT NULL,
-            destination_address TEXT NOT NULL,
-            destination_lat DOUBLE PRECISION NOT NULL,
-            destination_lng DOUBLE PRECISION NOT NULL,
-            estimated_distance DOUBLE PRECISION,
-            estimated_duration INTEGER,
-            vehicle_id UUID REFERENCES vehicles(id),
-            driver_id UUID REFERENCES users(id),
-            cargo_id UUID REFERENCES cargo(id),
-            status route_status DEFAULT 'planned',
-            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
-            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
-        );
-        "#
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS locations (
-            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-            route_id UUID REFERENCES routes(id),
-            vehicle_id UUID REFERENCES vehicles(id),
-            driver_id UUID REFERENCES users(id),
-            latitude DOUBLE PRECISION NOT NULL,
-            longitude DOUBLE PRECISION NOT NULL,
-            speed DOUBLE PRECISION DEFAULT 0.0,
-            heading DOUBLE PRECISION DEFAULT 0.0,
-            timestamp TIMESTAMP WITH TIME ZONE DEFAULT NOW()
-        );
-        "#
-    ).execute(&pool).await?;
-
-    // Create indexes
-    sqlx::query(
-        "CREATE INDEX IF NOT EXISTS idx_locations_route_id ON locations(route_id);"
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        "CREATE INDEX IF NOT EXISTS idx_locations_timestamp ON locations(timestamp);"
-    ).execute(&pool).await?;
-
-    sqlx::query(
-        "CREATE INDEX IF NOT EXISTS idx_routes_status ON routes(status);"
-    ).execute(&pool).await?;
-
-    // Create admin user if it doesn't exist
-    let admin_exists = sqlx::query_scalar::<_, bool>(
-        "SELECT EXISTS(SELECT 1 FROM users WHERE role = 'admin')"
-    ).fetch_one(&pool).await?;
-
-    if !admin_exists {
-        info!("Creating default admin user...");
-        let password_hash = bcrypt::hash("admin123", bcrypt::DEFAULT_COST)?;
-        
-        sqlx::query(
-            r#"
-            INSERT INTO users (username, email, password_hash, role)
-            VALUES ('admin', 'admin@logistrans.com', $1, 'admin')
-            "#
-        ).bind(&password_hash).execute(&pool).await?;
-        
-        info!("Default admin user created: admin/admin123");
-    }
-
-    info!("Database initialization completed successfully");
-    Ok(())
-}
+use sqlx::{PgPool, postgres::PgPoolOptions};
+use log::{info, warn};
+use std::time::Duration;
+
+pub type DbPool = PgPool;
+
+pub async fn create_pool(database_url: &str) -> Result<DbPool, sqlx::Error> {
+    PgPoolOptions::new()
+        .max_connections(20)
+        .acquire_timeout(Duration::from_secs(3))
+        .idle_timeout(Duration::from_secs(10))
+        .max_lifetime(Duration::from_secs(30))
+        .connect(database_url)
+        .await
+}
+
+pub async fn init_database(database_url: &str) -> Result<DbPool, Box<dyn std::error::Error>> {
+    info!("Initializing database...");
+    
+    let pool = create_pool(database_url).await?;
+    
+    // Create custom types
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE user_role AS ENUM ('admin', 'manager', 'driver', 'dispatcher');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    // Customers are the shippers and consignees of cargo
+    sqlx::query(
+        "ALTER TYPE user_role ADD VALUE IF NOT EXISTS 'customer';"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE vehicle_status AS ENUM ('available', 'inuse', 'maintenance', 'outofservice');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE cargo_priority AS ENUM ('low', 'medium', 'high', 'urgent');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE cargo_status AS ENUM ('pending', 'assigned', 'intransit', 'delivered', 'cancelled');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE route_status AS ENUM ('planned', 'inprogress', 'completed', 'cancelled');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE stop_type AS ENUM ('pickup', 'dropoff', 'break');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE stop_cargo_action AS ENUM ('load', 'unload');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    // Create tables
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS users (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            username VARCHAR(50) UNIQUE NOT NULL,
+            email VARCHAR(100) UNIQUE NOT NULL,
+            password_hash VARCHAR(255) NOT NULL,
+            role user_role NOT NULL,
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS vehicles (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            license_plate VARCHAR(20) UNIQUE NOT NULL,
+            make VARCHAR(50) NOT NULL,
+            model VARCHAR(50) NOT NULL,
+            year INTEGER NOT NULL,
+            capacity DOUBLE PRECISION NOT NULL,
+            fuel_type VARCHAR(20) NOT NULL,
+            status vehicle_status DEFAULT 'available',
+            driver_id UUID REFERENCES users(id),
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS cargo (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            description TEXT NOT NULL,
+            weight DOUBLE PRECISION NOT NULL,
+            volume DOUBLE PRECISION NOT NULL,
+            cargo_type VARCHAR(50) NOT NULL,
+            priority cargo_priority DEFAULT 'medium',
+            status cargo_status DEFAULT 'pending',
+            shipper_id UUID REFERENCES users(id),
+            consignee_id UUID REFERENCES users(id),
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS routes (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            source_address TEXT NOT NULL,
+            source_lat DOUBLE PRECISION NOT NULL,
+            source_lng DOUBLE PRECISION NOT NULL,
+            destination_address TEXT NOT NULL,
+            destination_lat DOUBLE PRECISION NOT NULL,
+            destination_lng DOUBLE PRECISION NOT NULL,
+            estimated_distance DOUBLE PRECISION,
+            estimated_duration INTEGER,
+            vehicle_id UUID REFERENCES vehicles(id),
+            driver_id UUID REFERENCES users(id),
+            cargo_id UUID REFERENCES cargo(id),
+            status route_status DEFAULT 'planned',
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS locations (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            route_id UUID REFERENCES routes(id),
+            vehicle_id UUID REFERENCES vehicles(id),
+            driver_id UUID REFERENCES users(id),
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            speed DOUBLE PRECISION DEFAULT 0.0,
+            heading DOUBLE PRECISION DEFAULT 0.0,
+            timestamp TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Numbers every live tracking event across all server instances
+    sqlx::query(
+        "CREATE SEQUENCE IF NOT EXISTS tracking_event_seq;"
+    ).execute(&pool).await?;
+
+    // Tracking events waiting for their transaction to commit. Each is numbered and notified by a
+    // deferred trigger, under a lock held until the commit, so numbers are delivered in order.
+    sqlx::query(
+        r#"
+        CREATE UNLOGGED TABLE IF NOT EXISTS pending_tracking_events (
+            id BIGSERIAL PRIMARY KEY,
+            payload TEXT NOT NULL
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(&format!(
+        r#"
+        CREATE OR REPLACE FUNCTION notify_tracking_event() RETURNS trigger AS $$
+        BEGIN
+            PERFORM pg_advisory_xact_lock({lock_key});
+            PERFORM pg_notify('{channel}', json_build_object(
+                'seq', nextval('tracking_event_seq'), 'event', NEW.payload::json
+            )::text);
+            DELETE FROM pending_tracking_events WHERE id = NEW.id;
+            RETURN NULL;
+        END
+        $$ LANGUAGE plpgsql;
+        "#,
+        lock_key = crate::events::PUBLISH_LOCK_KEY,
+        channel = crate::events::TRACKING_CHANNEL,
+    )).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE CONSTRAINT TRIGGER notify_tracking_event
+                AFTER INSERT ON pending_tracking_events
+                DEFERRABLE INITIALLY DEFERRED
+                FOR EACH ROW EXECUTE FUNCTION notify_tracking_event();
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        ALTER TABLE routes
+            ADD COLUMN IF NOT EXISTS started_at TIMESTAMP WITH TIME ZONE,
+            ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP WITH TIME ZONE;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_stops (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            route_id UUID NOT NULL REFERENCES routes(id) ON DELETE CASCADE,
+            sequence INTEGER NOT NULL,
+            stop_type stop_type NOT NULL,
+            address TEXT NOT NULL,
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            planned_arrival TIMESTAMP WITH TIME ZONE,
+            planned_departure TIMESTAMP WITH TIME ZONE,
+            actual_arrival TIMESTAMP WITH TIME ZONE,
+            actual_departure TIMESTAMP WITH TIME ZONE,
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            UNIQUE (route_id, sequence)
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_stop_cargo (
+            stop_id UUID NOT NULL REFERENCES route_stops(id) ON DELETE CASCADE,
+            cargo_id UUID NOT NULL REFERENCES cargo(id),
+            action stop_cargo_action NOT NULL,
+            PRIMARY KEY (stop_id, cargo_id, action)
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Routes created before stops existed become a pickup at the source and a dropoff at the destination
+    sqlx::query(
+        r#"
+        INSERT INTO route_stops (route_id, sequence, stop_type, address, latitude, longitude)
+        SELECT r.id, s.sequence, s.stop_type::stop_type, s.address, s.latitude, s.longitude
+        FROM routes r
+        CROSS JOIN LATERAL (VALUES
+            (0, 'pickup', r.source_address, r.source_lat, r.source_lng),
+            (1, 'dropoff', r.destination_address, r.destination_lat, r.destination_lng)
+        ) AS s(sequence, stop_type, address, latitude, longitude)
+        WHERE NOT EXISTS (SELECT 1 FROM route_stops rs WHERE rs.route_id = r.id);
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO route_stop_cargo (stop_id, cargo_id, action)
+        SELECT rs.id, r.cargo_id,
+               CASE WHEN rs.stop_type = 'pickup' THEN 'load' ELSE 'unload' END::stop_cargo_action
+        FROM routes r
+        JOIN route_stops rs ON rs.route_id = r.id
+        WHERE r.cargo_id IS NOT NULL
+          AND rs.stop_type <> 'break'
+          AND NOT EXISTS (
+              SELECT 1 FROM route_stop_cargo c
+              JOIN route_stops s ON s.id = c.stop_id
+              WHERE s.route_id = r.id
+          );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS cargo_events (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            cargo_id UUID NOT NULL REFERENCES cargo(id) ON DELETE CASCADE,
+            from_status cargo_status,
+            to_status cargo_status NOT NULL,
+            actor_id UUID REFERENCES users(id),
+            route_id UUID REFERENCES routes(id),
+            latitude DOUBLE PRECISION,
+            longitude DOUBLE PRECISION,
+            note TEXT,
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS tokens_revoked_at TIMESTAMP WITH TIME ZONE;"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "ALTER TABLE vehicles ADD COLUMN IF NOT EXISTS volume_capacity DOUBLE PRECISION;"
+    ).execute(&pool).await?;
+
+    // Existing and admin-created accounts count as verified; self-registrations insert NULL explicitly
+    sqlx::query(
+        r#"
+        ALTER TABLE users
+            ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            ADD COLUMN IF NOT EXISTS email_verification_hash VARCHAR(255),
+            ADD COLUMN IF NOT EXISTS email_verification_expires_at TIMESTAMP WITH TIME ZONE;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS invitations (
+            id UUID PRIMARY KEY,
+            email VARCHAR(100),
+            role user_role NOT NULL,
+            token_hash VARCHAR(255) NOT NULL,
+            created_by UUID NOT NULL REFERENCES users(id),
+            expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            accepted_at TIMESTAMP WITH TIME ZONE,
+            accepted_by UUID REFERENCES users(id),
+            revoked_at TIMESTAMP WITH TIME ZONE,
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS refresh_tokens (
+            id UUID PRIMARY KEY,
+            user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            token_hash VARCHAR(255) NOT NULL,
+            expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            revoked_at TIMESTAMP WITH TIME ZONE,
+            replaced_by UUID REFERENCES refresh_tokens(id),
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS revoked_tokens (
+            jti UUID PRIMARY KEY,
+            user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            revoked_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE geofence_kind AS ENUM ('depot', 'customersite', 'restricted');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE geofence_shape AS ENUM ('circle', 'polygon');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE geofence_event_type AS ENUM ('enter', 'exit', 'dwell');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS geofences (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            name VARCHAR(100) NOT NULL,
+            kind geofence_kind NOT NULL,
+            shape geofence_shape NOT NULL,
+            center_latitude DOUBLE PRECISION,
+            center_longitude DOUBLE PRECISION,
+            radius_meters DOUBLE PRECISION,
+            vertices JSONB,
+            dwell_seconds INTEGER,
+            created_by UUID REFERENCES users(id),
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Which geofences each vehicle is inside, as of its latest location
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS geofence_presence (
+            geofence_id UUID NOT NULL REFERENCES geofences(id) ON DELETE CASCADE,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id) ON DELETE CASCADE,
+            entered_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            dwell_reported BOOLEAN NOT NULL DEFAULT FALSE,
+            PRIMARY KEY (geofence_id, vehicle_id)
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS geofence_events (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            geofence_id UUID NOT NULL REFERENCES geofences(id) ON DELETE CASCADE,
+            event_type geofence_event_type NOT NULL,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id),
+            driver_id UUID NOT NULL REFERENCES users(id),
+            route_id UUID NOT NULL REFERENCES routes(id),
+            location_id UUID NOT NULL REFERENCES locations(id),
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            occurred_at TIMESTAMP WITH TIME ZONE NOT NULL
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "ALTER TABLE geofences ADD COLUMN IF NOT EXISTS speed_limit_kmh DOUBLE PRECISION;"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "ALTER TABLE vehicles ADD COLUMN IF NOT EXISTS vehicle_type VARCHAR(50);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS speed_limits (
+            vehicle_type VARCHAR(50) PRIMARY KEY,
+            speed_limit_kmh DOUBLE PRECISION NOT NULL,
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE alert_type AS ENUM ('speeding', 'harshbraking', 'harshturn', 'idling');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    // `value` is what was measured and `threshold` the limit it broke, in the unit of the alert type
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS alerts (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            alert_type alert_type NOT NULL,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id),
+            driver_id UUID NOT NULL REFERENCES users(id),
+            route_id UUID NOT NULL REFERENCES routes(id),
+            location_id UUID NOT NULL REFERENCES locations(id),
+            geofence_id UUID REFERENCES geofences(id) ON DELETE SET NULL,
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            value DOUBLE PRECISION NOT NULL,
+            threshold DOUBLE PRECISION NOT NULL,
+            occurred_at TIMESTAMP WITH TIME ZONE NOT NULL
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Each vehicle's previous fix and ongoing speeding or idling, for rules spanning several fixes
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS vehicle_telemetry (
+            vehicle_id UUID PRIMARY KEY REFERENCES vehicles(id) ON DELETE CASCADE,
+            speed DOUBLE PRECISION NOT NULL,
+            heading DOUBLE PRECISION NOT NULL,
+            recorded_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            speeding BOOLEAN NOT NULL DEFAULT FALSE,
+            idle_since TIMESTAMP WITH TIME ZONE,
+            idle_reported BOOLEAN NOT NULL DEFAULT FALSE
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Road geometry of a route; without one its path runs straight between the stops
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_paths (
+            route_id UUID PRIMARY KEY REFERENCES routes(id) ON DELETE CASCADE,
+            points JSONB NOT NULL,
+            updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Whether the vehicle of a running route has been on its path yet, and since when it is outside
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_deviation (
+            route_id UUID PRIMARY KEY REFERENCES routes(id) ON DELETE CASCADE,
+            outside_since TIMESTAMP WITH TIME ZONE,
+            off_route BOOLEAN NOT NULL DEFAULT FALSE
+        );
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        DO $$ BEGIN
+            CREATE TYPE deviation_event_type AS ENUM ('offroute', 'onroute');
+        EXCEPTION
+            WHEN duplicate_object THEN null;
+        END $$;
+        "#
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_deviation_events (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            route_id UUID NOT NULL REFERENCES routes(id) ON DELETE CASCADE,
+            event_type deviation_event_type NOT NULL,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id),
+            driver_id UUID NOT NULL REFERENCES users(id),
+            location_id UUID NOT NULL REFERENCES locations(id),
+            distance_meters DOUBLE PRECISION NOT NULL,
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            occurred_at TIMESTAMP WITH TIME ZONE NOT NULL
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Live ETAs of running routes, one row each time the estimate changed noticeably
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_etas (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            route_id UUID NOT NULL REFERENCES routes(id) ON DELETE CASCADE,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id),
+            driver_id UUID NOT NULL REFERENCES users(id),
+            location_id UUID NOT NULL REFERENCES locations(id),
+            next_stop_id UUID NOT NULL REFERENCES route_stops(id) ON DELETE CASCADE,
+            next_stop_distance_km DOUBLE PRECISION NOT NULL,
+            next_stop_eta TIMESTAMP WITH TIME ZONE NOT NULL,
+            remaining_distance_km DOUBLE PRECISION NOT NULL,
+            destination_eta TIMESTAMP WITH TIME ZONE NOT NULL,
+            speed_kmh DOUBLE PRECISION NOT NULL,
+            computed_at TIMESTAMP WITH TIME ZONE NOT NULL
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Trip statistics of completed routes, recorded on completion
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS route_statistics (
+            route_id UUID PRIMARY KEY REFERENCES routes(id) ON DELETE CASCADE,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id),
+            driver_id UUID NOT NULL REFERENCES users(id),
+            distance_km DOUBLE PRECISION NOT NULL,
+            planned_distance_km DOUBLE PRECISION NOT NULL,
+            duration_secs BIGINT NOT NULL,
+            planned_duration_secs BIGINT NOT NULL,
+            moving_secs BIGINT NOT NULL,
+            stopped_secs BIGINT NOT NULL,
+            max_speed_kmh DOUBLE PRECISION NOT NULL,
+            avg_speed_kmh DOUBLE PRECISION NOT NULL,
+            stop_count BIGINT NOT NULL,
+            location_count BIGINT NOT NULL,
+            computed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Stationary periods detected from locations; `route_stop_id` is NULL for unplanned stops
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS detected_stops (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            route_id UUID NOT NULL REFERENCES routes(id) ON DELETE CASCADE,
+            route_stop_id UUID REFERENCES route_stops(id) ON DELETE SET NULL,
+            vehicle_id UUID NOT NULL REFERENCES vehicles(id),
+            driver_id UUID NOT NULL REFERENCES users(id),
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            arrived_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            departed_at TIMESTAMP WITH TIME ZONE,
+            created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Where each vehicle on a running route has stayed since it last moved
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS vehicle_motion (
+            vehicle_id UUID PRIMARY KEY REFERENCES vehicles(id) ON DELETE CASCADE,
+            route_id UUID NOT NULL REFERENCES routes(id) ON DELETE CASCADE,
+            latitude DOUBLE PRECISION NOT NULL,
+            longitude DOUBLE PRECISION NOT NULL,
+            since TIMESTAMP WITH TIME ZONE NOT NULL,
+            last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL,
+            detected_stop_id UUID REFERENCES detected_stops(id) ON DELETE SET NULL
+        );
+        "#
+    ).execute(&pool).await?;
+
+    // Create indexes
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_locations_route_id ON locations(route_id);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_locations_timestamp ON locations(timestamp);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_routes_status ON routes(status);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_refresh_tokens_user_id ON refresh_tokens(user_id);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_cargo_events_cargo_id ON cargo_events(cargo_id, created_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_route_stop_cargo_cargo_id ON route_stop_cargo(cargo_id);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_geofence_events_geofence_id ON geofence_events(geofence_id, occurred_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_geofence_events_vehicle_id ON geofence_events(vehicle_id, occurred_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_detected_stops_route_id ON detected_stops(route_id, arrived_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_detected_stops_vehicle_id ON detected_stops(vehicle_id, arrived_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_alerts_vehicle_id ON alerts(vehicle_id, occurred_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_alerts_driver_id ON alerts(driver_id, occurred_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_route_deviation_events_route_id ON route_deviation_events(route_id, occurred_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_route_etas_route_id ON route_etas(route_id, computed_at);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_locations_route_id_timestamp ON locations(route_id, timestamp);"
+    ).execute(&pool).await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS idx_locations_vehicle_id_timestamp ON locations(vehicle_id, timestamp);"
+    ).execute(&pool).await?;
+
+    // Before the index below existed a driver could end up on several vehicles; they keep the most
+    // recently updated one
+    let detached = sqlx::query(
+        r#"
+        UPDATE vehicles SET driver_id = NULL, updated_at = NOW()
+        WHERE id IN (
+            SELECT id FROM (
+                SELECT id, ROW_NUMBER() OVER (
+                    PARTITION BY driver_id ORDER BY updated_at DESC NULLS LAST, id DESC
+                ) AS n
+                FROM vehicles WHERE driver_id IS NOT NULL
+            ) assignments
+            WHERE n > 1
+        );
+        "#
+    ).execute(&pool).await?.rows_affected();
+
+    if detached > 0 {
+        warn!("Detached {} duplicate driver assignments from vehicles", detached);
+    }
+
+    // A driver is assigned to at most one vehicle, even when two assignments race
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_vehicles_driver_id ON vehicles(driver_id) WHERE driver_id IS NOT NULL;"
+    ).execute(&pool).await?;
+
+    // Expired revocations can no longer match a valid token
+    sqlx::query(
+        "DELETE FROM revoked_tokens WHERE expires_at <= NOW();"
+    ).execute(&pool).await?;
+
+    // Create admin user if it doesn't exist
+    let admin_exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE role = 'admin')"
+    ).fetch_one(&pool).await?;
+
+    if !admin_exists {
+        info!("Creating default admin user...");
+        let password_hash = bcrypt::hash("admin123", bcrypt::DEFAULT_COST)?;
+        
+        sqlx::query(
+            r#"
+            INSERT INTO users (username, email, password_hash, role)
+            VALUES ('admin', 'admin@logistrans.com', $1, 'admin')
+            "#
+        ).bind(&password_hash).execute(&pool).await?;
+        
+        info!("Default admin user created: admin/admin123");
+    }
+
+    info!("Database initialization completed successfully");
+    Ok(pool)
+}
@@ -0,0 +1,316 @@
+// Route deviation: compares the locations of running routes with their planned path and records
+// when a vehicle has stayed outside the corridor around it for too long, and when it comes back
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::models::{DeviationEventType, GeoPoint, Location, RouteDeviationEvent};
+
+// Class of the per-route advisory locks, kept apart from the per-vehicle ones
+const DEVIATION_LOCK_CLASS: i32 = 0x6465_7669;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviationRules {
+    pub corridor_meters: f64,
+    pub off_route_after: chrono::Duration,
+}
+
+impl DeviationRules {
+    pub fn new(config: &Config) -> Self {
+        DeviationRules {
+            corridor_meters: config.route_corridor_meters,
+            off_route_after: chrono::Duration::seconds(config.off_route_secs as i64),
+        }
+    }
+}
+
+// Distance in meters from a point to the nearest leg of a path. Legs are projected onto a plane
+// centered on the point, which is accurate to well under a meter over a few kilometers; longitude
+// differences are wrapped so paths may cross the antimeridian.
+pub fn distance_to_path(path: &[GeoPoint], point: GeoPoint) -> f64 {
+    let scale = point.latitude.to_radians().cos();
+    let project = |p: GeoPoint| {
+        (
+            ((p.longitude - point.longitude + 180.0).rem_euclid(360.0) - 180.0).to_radians() * scale * EARTH_RADIUS_METERS,
+            (p.latitude - point.latitude).to_radians() * EARTH_RADIUS_METERS,
+        )
+    };
+
+    let distance_to_leg = |from: GeoPoint, to: GeoPoint| {
+        let (ax, ay) = project(from);
+        let (bx, by) = project(to);
+        let (dx, dy) = (bx - ax, by - ay);
+        let length_squared = dx * dx + dy * dy;
+        let t = if length_squared > 0.0 {
+            (-(ax * dx + ay * dy) / length_squared).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (ax + t * dx).hypot(ay + t * dy)
+    };
+
+    match path {
+        [] => f64::INFINITY,
+        [only] => distance_to_leg(*only, *only),
+        _ => path
+            .windows(2)
+            .map(|leg| distance_to_leg(leg[0], leg[1]))
+            .fold(f64::INFINITY, f64::min),
+    }
+}
+
+// A route is only watched once its vehicle has been within the corridor, so the drive to the
+// first stop does not count as a deviation
+struct Deviation {
+    outside_since: Option<DateTime<Utc>>,
+    off_route: bool,
+}
+
+// Check newly stored locations of running routes against their planned paths and record the
+// resulting events inside the caller's transaction. Locations are taken in timestamp order.
+pub async fn evaluate_deviations(
+    conn: &mut sqlx::PgConnection,
+    locations: &[Location],
+    rules: &DeviationRules,
+) -> Result<Vec<RouteDeviationEvent>, actix_web::Error> {
+    if locations.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut route_ids: Vec<Uuid> = locations.iter().map(|location| location.route_id).collect();
+    route_ids.sort();
+    route_ids.dedup();
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext(r::text)) FROM UNNEST($2::uuid[]) AS r")
+        .bind(DEVIATION_LOCK_CLASS)
+        .bind(&route_ids)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    // A stored road path wins over the straight legs between stops
+    let rows = sqlx::query_as::<_, (Uuid, sqlx::types::Json<Vec<GeoPoint>>)>(
+        r#"
+        SELECT r.id, COALESCE(
+            p.points,
+            (
+                SELECT jsonb_agg(jsonb_build_object('latitude', s.latitude, 'longitude', s.longitude) ORDER BY s.sequence)
+                FROM route_stops s WHERE s.route_id = r.id
+            ),
+            '[]'::jsonb
+        )
+        FROM routes r
+        LEFT JOIN route_paths p ON p.route_id = r.id
+        WHERE r.id = ANY($1) AND r.status = 'inprogress'
+        "#
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let paths: HashMap<Uuid, Vec<GeoPoint>> = rows
+        .into_iter()
+        .map(|(route_id, points)| (route_id, points.0))
+        .filter(|(_, points)| !points.is_empty())
+        .collect();
+
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let rows = sqlx::query_as::<_, (Uuid, Option<DateTime<Utc>>, bool)>(
+        "SELECT route_id, outside_since, off_route FROM route_deviation WHERE route_id = ANY($1)"
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut deviations: HashMap<Uuid, Deviation> = rows
+        .into_iter()
+        .map(|(route_id, outside_since, off_route)| (route_id, Deviation { outside_since, off_route }))
+        .collect();
+
+    let mut ordered: Vec<&Location> = locations.iter().collect();
+    ordered.sort_by_key(|location| location.timestamp);
+
+    let mut changes: Vec<(DeviationEventType, f64, &Location)> = Vec::new();
+    for location in ordered {
+        let Some(path) = paths.get(&location.route_id) else {
+            continue;
+        };
+
+        let point = GeoPoint {
+            latitude: location.latitude,
+            longitude: location.longitude,
+        };
+        let distance = distance_to_path(path, point);
+        let inside = distance <= rules.corridor_meters;
+
+        let Some(state) = deviations.get_mut(&location.route_id) else {
+            if inside {
+                deviations.insert(location.route_id, Deviation { outside_since: None, off_route: false });
+            }
+            continue;
+        };
+
+        if inside {
+            state.outside_since = None;
+            if state.off_route {
+                state.off_route = false;
+                changes.push((DeviationEventType::OnRoute, distance, location));
+            }
+            continue;
+        }
+
+        let outside_since = *state.outside_since.get_or_insert(location.timestamp);
+        if !state.off_route && location.timestamp - outside_since >= rules.off_route_after {
+            state.off_route = true;
+            changes.push((DeviationEventType::OffRoute, distance, location));
+        }
+    }
+
+    let mut events = Vec::with_capacity(changes.len());
+    for (event_type, distance, location) in &changes {
+        let event = sqlx::query_as::<_, RouteDeviationEvent>(
+            r#"
+            INSERT INTO route_deviation_events (
+                route_id, event_type, vehicle_id, driver_id, location_id, distance_meters, latitude, longitude, occurred_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING *
+            "#
+        )
+        .bind(location.route_id)
+        .bind(event_type)
+        .bind(location.vehicle_id)
+        .bind(location.driver_id)
+        .bind(location.id)
+        .bind(distance)
+        .bind(location.latitude)
+        .bind(location.longitude)
+        .bind(location.timestamp)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        events.push(event);
+    }
+
+    for (route_id, state) in &deviations {
+        sqlx::query(
+            r#"
+            INSERT INTO route_deviation (route_id, outside_since, off_route)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (route_id) DO UPDATE
+            SET outside_since = EXCLUDED.outside_since, off_route = EXCLUDED.off_route
+            "#
+        )
+        .bind(route_id)
+        .bind(state.outside_since)
+        .bind(state.off_route)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::routes::haversine_distance;
+
+    fn point(latitude: f64, longitude: f64) -> GeoPoint {
+        GeoPoint { latitude, longitude }
+    }
+
+    fn meters_between(a: GeoPoint, b: GeoPoint) -> f64 {
+        haversine_distance(a.latitude, a.longitude, b.latitude, b.longitude) * 1000.0
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.5, "{} is not within 0.5 m of {}", actual, expected);
+    }
+
+    #[test]
+    fn empty_path_is_infinitely_far() {
+        assert_eq!(distance_to_path(&[], point(52.0, 4.0)), f64::INFINITY);
+    }
+
+    #[test]
+    fn single_point_path_is_measured_to_that_point() {
+        let only = point(52.0, 4.0);
+        let vehicle = point(52.001, 4.001);
+
+        assert_close(distance_to_path(&[only], vehicle), meters_between(only, vehicle));
+    }
+
+    #[test]
+    fn degenerate_leg_is_measured_to_its_point() {
+        let stop = point(52.0, 4.0);
+        let vehicle = point(52.0, 4.002);
+
+        let distance = distance_to_path(&[stop, stop], vehicle);
+
+        assert!(distance.is_finite());
+        assert_close(distance, meters_between(stop, vehicle));
+    }
+
+    #[test]
+    fn repeated_points_inside_a_path_are_harmless() {
+        let path = [point(52.0, 4.0), point(52.01, 4.0), point(52.01, 4.0), point(52.02, 4.0)];
+
+        assert_close(distance_to_path(&path, point(52.015, 4.0)), 0.0);
+    }
+
+    #[test]
+    fn beside_a_leg_is_measured_square_to_it() {
+        let path = [point(52.0, 4.0), point(52.01, 4.0)];
+        let vehicle = point(52.005, 4.001);
+
+        assert_close(distance_to_path(&path, vehicle), meters_between(point(52.005, 4.0), vehicle));
+    }
+
+    #[test]
+    fn beyond_the_end_is_measured_to_the_end() {
+        let end = point(52.01, 4.0);
+        let vehicle = point(52.02, 4.0);
+
+        assert_close(distance_to_path(&[point(52.0, 4.0), end], vehicle), meters_between(end, vehicle));
+    }
+
+    #[test]
+    fn nearest_leg_wins() {
+        let path = [point(52.0, 4.0), point(52.01, 4.0), point(52.01, 4.01)];
+
+        assert_close(distance_to_path(&path, point(52.0105, 4.005)), meters_between(point(52.01, 4.005), point(52.0105, 4.005)));
+    }
+
+    #[test]
+    fn path_across_the_antimeridian() {
+        let path = [point(-17.0, 179.99), point(-17.0, -179.99)];
+        let vehicle = point(-17.001, 180.0);
+
+        assert_close(distance_to_path(&path, vehicle), meters_between(point(-17.0, 180.0), vehicle));
+    }
+}
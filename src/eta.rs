@@ -0,0 +1,293 @@
+// Live ETAs: on each location of a running route, re-estimate arrival at the next stop and at the
+// last one from the remaining distance and a blend of recently observed and planned speed
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::handlers::routes::{calculate_distance, haversine_distance};
+use crate::models::{Location, Route, RouteEta, RouteStop};
+
+// Class of the per-route advisory locks, kept apart from the deviation ones
+const ETA_LOCK_CLASS: i32 = 0x6574_6121;
+
+// Assumed for routes without a planned duration, as when routes are created
+const DEFAULT_SPEED_KMH: f64 = 50.0;
+
+// A vehicle stuck in traffic still gets somewhere eventually
+const MIN_SPEED_KMH: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EtaRules {
+    pub speed_window: chrono::Duration,
+    pub observed_weight: f64,
+    pub min_change: chrono::Duration,
+}
+
+impl EtaRules {
+    pub fn new(config: &Config) -> Self {
+        EtaRules {
+            speed_window: chrono::Duration::seconds(config.eta_speed_window_secs as i64),
+            observed_weight: config.eta_observed_weight,
+            min_change: chrono::Duration::seconds(config.eta_min_change_secs as i64),
+        }
+    }
+}
+
+fn planned_speed(route: &Route) -> f64 {
+    if route.estimated_duration > 0 && route.estimated_distance > 0.0 {
+        route.estimated_distance / (route.estimated_duration as f64 / 60.0)
+    } else {
+        DEFAULT_SPEED_KMH
+    }
+}
+
+// Share `observed_weight` of the recently observed speed and the rest of the planned one, or just
+// the planned speed before anything was observed
+fn blended_speed(observed: Option<f64>, planned: f64, observed_weight: f64) -> f64 {
+    match observed {
+        Some(observed) => observed_weight * observed + (1.0 - observed_weight) * planned,
+        None => planned,
+    }
+    .max(MIN_SPEED_KMH)
+}
+
+fn arrival(from: DateTime<Utc>, distance_km: f64, speed_kmh: f64) -> DateTime<Utc> {
+    from + chrono::Duration::seconds((distance_km / speed_kmh * 3600.0).round() as i64)
+}
+
+fn moved(previous: DateTime<Utc>, current: DateTime<Utc>, min_change: chrono::Duration) -> bool {
+    (current - previous).abs() >= min_change
+}
+
+// Re-estimate the routes of newly stored locations from each route's latest one, inside the
+// caller's transaction. Estimates are stored and returned only when they changed noticeably.
+pub async fn estimate_arrivals(
+    conn: &mut sqlx::PgConnection,
+    locations: &[Location],
+    rules: &EtaRules,
+) -> Result<Vec<RouteEta>, actix_web::Error> {
+    let mut latest: HashMap<Uuid, &Location> = HashMap::new();
+    for location in locations {
+        let entry = latest.entry(location.route_id).or_insert(location);
+        if location.timestamp > entry.timestamp {
+            *entry = location;
+        }
+    }
+
+    if latest.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut route_ids: Vec<Uuid> = latest.keys().copied().collect();
+    route_ids.sort();
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext(r::text)) FROM UNNEST($2::uuid[]) AS r")
+        .bind(ETA_LOCK_CLASS)
+        .bind(&route_ids)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    let routes = sqlx::query_as::<_, Route>(
+        "SELECT * FROM routes WHERE id = ANY($1) AND status = 'inprogress'"
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if routes.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut stops: HashMap<Uuid, Vec<RouteStop>> = HashMap::new();
+    for stop in sqlx::query_as::<_, RouteStop>(
+        "SELECT * FROM route_stops WHERE route_id = ANY($1) AND actual_departure IS NULL ORDER BY route_id, sequence"
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })? {
+        stops.entry(stop.route_id).or_default().push(stop);
+    }
+
+    // The newest location stored for each route, this batch included
+    let newest: HashMap<Uuid, DateTime<Utc>> = sqlx::query_as::<_, (Uuid, DateTime<Utc>)>(
+        "SELECT route_id, MAX(timestamp) FROM locations WHERE route_id = ANY($1) GROUP BY route_id"
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .collect();
+
+    let windows: Vec<DateTime<Utc>> = route_ids
+        .iter()
+        .map(|route_id| latest[route_id].timestamp - rules.speed_window)
+        .collect();
+
+    let observed: HashMap<Uuid, f64> = sqlx::query_as::<_, (Uuid, Option<f64>)>(
+        r#"
+        SELECT w.route_id, AVG(l.speed)
+        FROM UNNEST($1::uuid[], $2::timestamptz[]) AS w(route_id, since)
+        JOIN locations l ON l.route_id = w.route_id AND l.timestamp > w.since
+        GROUP BY w.route_id
+        "#
+    )
+    .bind(&route_ids)
+    .bind(&windows)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .filter_map(|(route_id, speed)| speed.map(|speed| (route_id, speed)))
+    .collect();
+
+    let previous: HashMap<Uuid, RouteEta> = sqlx::query_as::<_, RouteEta>(
+        "SELECT DISTINCT ON (route_id) * FROM route_etas WHERE route_id = ANY($1) ORDER BY route_id, computed_at DESC"
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .map(|eta| (eta.route_id, eta))
+    .collect();
+
+    let mut etas = Vec::new();
+    for route in &routes {
+        let location = latest[&route.id];
+        let Some(remaining) = stops.get(&route.id) else {
+            continue;
+        };
+        let next = &remaining[0];
+
+        // An older fix arriving late would move the estimate backwards
+        if newest.get(&route.id).is_some_and(|newest| *newest > location.timestamp) {
+            continue;
+        }
+
+        let next_stop_distance = match next.actual_arrival {
+            Some(_) => 0.0,
+            None => haversine_distance(location.latitude, location.longitude, next.latitude, next.longitude),
+        };
+        let legs: Vec<(f64, f64)> = remaining.iter().map(|stop| (stop.latitude, stop.longitude)).collect();
+        let remaining_distance = next_stop_distance + calculate_distance(&legs);
+
+        let speed = blended_speed(observed.get(&route.id).copied(), planned_speed(route), rules.observed_weight);
+
+        let next_stop_eta = arrival(location.timestamp, next_stop_distance, speed);
+        let destination_eta = arrival(location.timestamp, remaining_distance, speed);
+
+        let changed = previous.get(&route.id).is_none_or(|eta| {
+            eta.next_stop_id != next.id
+                || moved(eta.next_stop_eta, next_stop_eta, rules.min_change)
+                || moved(eta.destination_eta, destination_eta, rules.min_change)
+        });
+        if !changed {
+            continue;
+        }
+
+        let eta = sqlx::query_as::<_, RouteEta>(
+            r#"
+            INSERT INTO route_etas (
+                route_id, vehicle_id, driver_id, location_id, next_stop_id, next_stop_distance_km,
+                next_stop_eta, remaining_distance_km, destination_eta, speed_kmh, computed_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            RETURNING *
+            "#
+        )
+        .bind(route.id)
+        .bind(route.vehicle_id)
+        .bind(route.driver_id)
+        .bind(location.id)
+        .bind(next.id)
+        .bind(next_stop_distance)
+        .bind(next_stop_eta)
+        .bind(remaining_distance)
+        .bind(destination_eta)
+        .bind(speed)
+        .bind(location.timestamp)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        etas.push(eta);
+    }
+
+    Ok(etas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn weight_zero_ignores_the_observed_speed() {
+        assert_eq!(blended_speed(Some(20.0), 60.0, 0.0), 60.0);
+    }
+
+    #[test]
+    fn weight_one_ignores_the_planned_speed() {
+        assert_eq!(blended_speed(Some(20.0), 60.0, 1.0), 20.0);
+    }
+
+    #[test]
+    fn weights_in_between_blend_both() {
+        assert_eq!(blended_speed(Some(20.0), 60.0, 0.5), 40.0);
+        assert_eq!(blended_speed(Some(80.0), 40.0, 0.25), 50.0);
+    }
+
+    #[test]
+    fn planned_speed_is_used_until_something_is_observed() {
+        assert_eq!(blended_speed(None, 60.0, 1.0), 60.0);
+    }
+
+    #[test]
+    fn standing_still_never_stops_the_estimate() {
+        assert_eq!(blended_speed(Some(0.0), 60.0, 1.0), MIN_SPEED_KMH);
+        assert_eq!(blended_speed(None, 0.0, 0.0), MIN_SPEED_KMH);
+    }
+
+    #[test]
+    fn arrival_is_distance_over_speed() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 23, 30, 0).unwrap();
+
+        assert_eq!(arrival(now, 60.0, 60.0), now + chrono::Duration::hours(1));
+        assert_eq!(arrival(now, 0.0, 60.0), now);
+    }
+
+    #[test]
+    fn estimates_move_by_at_least_the_minimum_change_either_way() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let minute = chrono::Duration::minutes(1);
+
+        assert!(moved(now, now + minute, minute));
+        assert!(moved(now, now - minute, minute));
+        assert!(!moved(now, now + chrono::Duration::seconds(59), minute));
+    }
+}
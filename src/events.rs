@@ -0,0 +1,303 @@
+// Live tracking events, shared between server instances through Postgres LISTEN/NOTIFY. Every
+// instance, the publishing one included, fans events out to its own sessions from its listener.
+use actix::Addr;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use std::time::Duration;
+use uuid::Uuid;
+use crate::auth::{apply_revocation, load_revocations, AuthUser, Revocation, REVOCATION_CHANNEL};
+use crate::database::DbPool;
+use crate::models::{
+    Alert, Cargo, CargoStatus, GeofenceEvent, Location, Route, RouteDeviationEvent, RouteEta, RouteStatus, UserRole, Vehicle,
+    VehicleStatus,
+};
+use crate::websocket::{DropRevoked, Publish, ResetHistory, TrackingHub};
+
+pub const TRACKING_CHANNEL: &str = "tracking_events";
+
+// Held from numbering a transaction's events until it has committed, so sequence numbers are
+// delivered in increasing order. Taken at commit by the trigger on `pending_tracking_events`.
+pub const PUBLISH_LOCK_KEY: i64 = 0x7472_6163_6b69_6e67;
+
+// How long to wait before listening again after the listener connection fails
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// A committed status change of a route, vehicle or cargo item
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "entity", rename_all = "snake_case")]
+pub enum StatusChange {
+    Route {
+        route_id: Uuid,
+        vehicle_id: Uuid,
+        driver_id: Uuid,
+        status: RouteStatus,
+    },
+    Vehicle {
+        vehicle_id: Uuid,
+        driver_id: Option<Uuid>,
+        status: VehicleStatus,
+    },
+    Cargo {
+        cargo_id: Uuid,
+        route_id: Option<Uuid>,
+        status: CargoStatus,
+        shipper_id: Uuid,
+        consignee_id: Uuid,
+    },
+}
+
+impl StatusChange {
+    pub fn route(route: &Route) -> Self {
+        StatusChange::Route {
+            route_id: route.id,
+            vehicle_id: route.vehicle_id,
+            driver_id: route.driver_id,
+            status: route.status,
+        }
+    }
+
+    pub fn vehicle(vehicle: &Vehicle) -> Self {
+        StatusChange::Vehicle {
+            vehicle_id: vehicle.id,
+            driver_id: vehicle.driver_id,
+            status: vehicle.status,
+        }
+    }
+
+    pub fn cargo(cargo: &Cargo, route_id: Option<Uuid>) -> Self {
+        StatusChange::Cargo {
+            cargo_id: cargo.id,
+            route_id,
+            status: cargo.status,
+            shipper_id: cargo.shipper_id,
+            consignee_id: cargo.consignee_id,
+        }
+    }
+
+    // Customers may follow a route carrying someone else's cargo too
+    pub fn visible_to(&self, user: &AuthUser) -> bool {
+        match self {
+            StatusChange::Cargo { shipper_id, consignee_id, .. } if user.role == UserRole::Customer => {
+                *shipper_id == user.id || *consignee_id == user.id
+            }
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TrackingEvent {
+    Location { location: Location },
+    StatusChange { change: StatusChange },
+    Geofence { event: GeofenceEvent },
+    Alert { alert: Alert },
+    Deviation { event: RouteDeviationEvent },
+    Eta { eta: RouteEta },
+}
+
+impl TrackingEvent {
+    pub fn visible_to(&self, user: &AuthUser) -> bool {
+        match self {
+            TrackingEvent::Location { .. } => true,
+            TrackingEvent::StatusChange { change } => change.visible_to(user),
+            // Geofences are fleet configuration; customers only see where the vehicle is
+            TrackingEvent::Geofence { .. } => user.role != UserRole::Customer,
+            // Alerts are for dispatch, not for drivers or customers
+            TrackingEvent::Alert { .. } => {
+                matches!(user.role, UserRole::Admin | UserRole::Manager | UserRole::Dispatcher)
+            }
+            // Customers following a route carrying their cargo are told when it leaves its path
+            TrackingEvent::Deviation { .. } | TrackingEvent::Eta { .. } => true,
+        }
+    }
+
+    // Location updates for the same vehicle supersede each other
+    pub fn vehicle_id(&self) -> Option<Uuid> {
+        match self {
+            TrackingEvent::Location { location } => Some(location.vehicle_id),
+            TrackingEvent::StatusChange { .. }
+            | TrackingEvent::Geofence { .. }
+            | TrackingEvent::Alert { .. }
+            | TrackingEvent::Deviation { .. }
+            | TrackingEvent::Eta { .. } => None,
+        }
+    }
+}
+
+// Payload of a notification on `TRACKING_CHANNEL`. `seq` comes from the `tracking_event_seq`
+// sequence as the publishing transaction commits, so it increases across every instance.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SequencedEvent {
+    pub seq: i64,
+    pub event: TrackingEvent,
+}
+
+// Notify every instance of status changes. The notifications are only delivered once the
+// caller's transaction commits, and not at all if it rolls back.
+pub async fn publish_status_changes(
+    conn: &mut sqlx::PgConnection,
+    changes: &[StatusChange],
+) -> Result<(), actix_web::Error> {
+    let events: Vec<TrackingEvent> = changes
+        .iter()
+        .map(|change| TrackingEvent::StatusChange { change: change.clone() })
+        .collect();
+
+    publish_events(conn, &events).await
+}
+
+pub async fn publish_locations(
+    conn: &mut sqlx::PgConnection,
+    locations: &[Location],
+) -> Result<(), actix_web::Error> {
+    let events: Vec<TrackingEvent> = locations
+        .iter()
+        .map(|location| TrackingEvent::Location { location: location.clone() })
+        .collect();
+
+    publish_events(conn, &events).await
+}
+
+pub async fn publish_geofence_events(
+    conn: &mut sqlx::PgConnection,
+    geofence_events: &[GeofenceEvent],
+) -> Result<(), actix_web::Error> {
+    let events: Vec<TrackingEvent> = geofence_events
+        .iter()
+        .map(|event| TrackingEvent::Geofence { event: event.clone() })
+        .collect();
+
+    publish_events(conn, &events).await
+}
+
+pub async fn publish_alerts(conn: &mut sqlx::PgConnection, alerts: &[Alert]) -> Result<(), actix_web::Error> {
+    let events: Vec<TrackingEvent> = alerts
+        .iter()
+        .map(|alert| TrackingEvent::Alert { alert: alert.clone() })
+        .collect();
+
+    publish_events(conn, &events).await
+}
+
+pub async fn publish_deviation_events(
+    conn: &mut sqlx::PgConnection,
+    deviation_events: &[RouteDeviationEvent],
+) -> Result<(), actix_web::Error> {
+    let events: Vec<TrackingEvent> = deviation_events
+        .iter()
+        .map(|event| TrackingEvent::Deviation { event: event.clone() })
+        .collect();
+
+    publish_events(conn, &events).await
+}
+
+pub async fn publish_etas(conn: &mut sqlx::PgConnection, etas: &[RouteEta]) -> Result<(), actix_web::Error> {
+    let events: Vec<TrackingEvent> = etas
+        .iter()
+        .map(|eta| TrackingEvent::Eta { eta: eta.clone() })
+        .collect();
+
+    publish_events(conn, &events).await
+}
+
+// Queue events to be numbered and notified when the caller's transaction commits
+async fn publish_events(conn: &mut sqlx::PgConnection, events: &[TrackingEvent]) -> Result<(), actix_web::Error> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let payloads = events
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| {
+            eprintln!("Failed to serialize tracking event: {}", e);
+            actix_web::error::ErrorInternalServerError("Failed to publish update")
+        })?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO pending_tracking_events (payload)
+        SELECT e.payload FROM UNNEST($1::text[]) WITH ORDINALITY AS e(payload, n)
+        ORDER BY e.n
+        "#
+    )
+    .bind(payloads)
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(())
+}
+
+async fn reload_revocations(pool: &DbPool, hub: &Addr<TrackingHub>, access_token_ttl: chrono::Duration) {
+    match load_revocations(pool, access_token_ttl).await {
+        Ok(()) => hub.do_send(DropRevoked),
+        Err(e) => eprintln!("Failed to load token revocations: {}", e),
+    }
+}
+
+// Forward every notification on `TRACKING_CHANNEL` to this instance's hub and apply every one on
+// `REVOCATION_CHANNEL`, disconnecting the sessions opened with revoked tokens. Runs for the life
+// of the server; events notified while the listener is reconnecting are missed.
+pub async fn listen(pool: DbPool, hub: Addr<TrackingHub>, access_token_ttl: chrono::Duration) {
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to connect tracking listener: {}", e);
+                actix_web::rt::time::sleep(LISTEN_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = listener.listen_all([TRACKING_CHANNEL, REVOCATION_CHANNEL]).await {
+            eprintln!("Failed to listen on {}: {}", TRACKING_CHANNEL, e);
+            actix_web::rt::time::sleep(LISTEN_RETRY_DELAY).await;
+            continue;
+        }
+
+        // Whatever was notified while not listening is lost, so replay starts over and
+        // revocations are read back from the database
+        hub.do_send(ResetHistory);
+        reload_revocations(&pool, &hub, access_token_ttl).await;
+
+        loop {
+            let notification = match listener.try_recv().await {
+                Ok(Some(notification)) => notification,
+                // The listener reconnects on the next call, but notifications sent meanwhile are gone
+                Ok(None) => {
+                    hub.do_send(ResetHistory);
+                    reload_revocations(&pool, &hub, access_token_ttl).await;
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Tracking listener error: {}", e);
+                    break;
+                }
+            };
+
+            if notification.channel() == REVOCATION_CHANNEL {
+                match serde_json::from_str::<Revocation>(notification.payload()) {
+                    Ok(revocation) => {
+                        apply_revocation(&revocation, access_token_ttl);
+                        hub.do_send(DropRevoked);
+                    }
+                    Err(e) => eprintln!("Invalid token revocation: {}", e),
+                }
+                continue;
+            }
+
+            match serde_json::from_str::<SequencedEvent>(notification.payload()) {
+                Ok(event) => hub.do_send(Publish(event)),
+                Err(e) => eprintln!("Invalid tracking event: {}", e),
+            }
+        }
+
+        actix_web::rt::time::sleep(LISTEN_RETRY_DELAY).await;
+    }
+}
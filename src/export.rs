@@ -0,0 +1,282 @@
+// Location history rendered as GPX, KML or GeoJSON. Rows are read in keyset-paged chunks and
+// written out as they arrive, so exports of long histories never sit in memory whole.
+use actix_web::web;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::Stream;
+use std::fmt::Write;
+use uuid::Uuid;
+use crate::database::DbPool;
+use crate::models::{Location, TrackFormat};
+
+const EXPORT_CHUNK_SIZE: i64 = 1000;
+
+// Locations of a route or a vehicle, optionally limited to a time range and to one driver's
+#[derive(Debug, Clone, Copy)]
+pub struct TrackFilter {
+    pub route_id: Option<Uuid>,
+    pub vehicle_id: Option<Uuid>,
+    pub driver_id: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl TrackFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "application/gpx+xml",
+            TrackFormat::Kml => "application/vnd.google-earth.kml+xml",
+            TrackFormat::GeoJson => "application/geo+json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "gpx",
+            TrackFormat::Kml => "kml",
+            TrackFormat::GeoJson => "geojson",
+        }
+    }
+
+    // KML draws the track as a line first, then lists every position with its time and readings
+    fn passes(self) -> usize {
+        match self {
+            TrackFormat::Kml => 2,
+            TrackFormat::Gpx | TrackFormat::GeoJson => 1,
+        }
+    }
+
+    fn header(self, out: &mut String, name: &str) {
+        match self {
+            TrackFormat::Gpx => {
+                out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+                out.push_str(
+                    "<gpx version=\"1.1\" creator=\"LogisTrans\" xmlns=\"http://www.topografix.com/GPX/1/1\" \
+                     xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v2\">\n",
+                );
+                let _ = write!(out, "<trk>\n<name>{}</name>\n", name);
+            }
+            TrackFormat::Kml => {
+                out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+                out.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n");
+                let _ = write!(out, "<Document>\n<name>{}</name>\n", name);
+            }
+            TrackFormat::GeoJson => {
+                out.push_str("{\"type\":\"FeatureCollection\",\"features\":[\n");
+            }
+        }
+    }
+
+    fn footer(self, out: &mut String) {
+        match self {
+            TrackFormat::Gpx => out.push_str("</trk>\n</gpx>\n"),
+            TrackFormat::Kml => out.push_str("</Document>\n</kml>\n"),
+            TrackFormat::GeoJson => out.push_str("\n]}\n"),
+        }
+    }
+
+    fn pass_header(self, out: &mut String, pass: usize) {
+        match (self, pass) {
+            (TrackFormat::Kml, 0) => out.push_str("<Placemark>\n<name>Track</name>\n<MultiGeometry>\n"),
+            (TrackFormat::Kml, _) => out.push_str("<Folder>\n<name>Positions</name>\n"),
+            _ => {}
+        }
+    }
+
+    fn pass_footer(self, out: &mut String, pass: usize, previous: Option<&Location>) {
+        match (self, pass) {
+            (TrackFormat::Gpx, _) if previous.is_some() => out.push_str("</trkseg>\n"),
+            (TrackFormat::Kml, 0) => {
+                if previous.is_some() {
+                    out.push_str("</coordinates>\n</LineString>\n");
+                }
+                out.push_str("</MultiGeometry>\n</Placemark>\n");
+            }
+            (TrackFormat::Kml, _) => out.push_str("</Folder>\n"),
+            _ => {}
+        }
+    }
+
+    // Each route a vehicle drove becomes its own segment of the track
+    fn row(self, out: &mut String, pass: usize, previous: Option<&Location>, location: &Location) {
+        let new_segment = previous.is_none_or(|previous| previous.route_id != location.route_id);
+        let time = location.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        match (self, pass) {
+            (TrackFormat::Gpx, _) => {
+                if new_segment {
+                    if previous.is_some() {
+                        out.push_str("</trkseg>\n");
+                    }
+                    out.push_str("<trkseg>\n");
+                }
+                // The extension takes speed in meters per second
+                let _ = writeln!(
+                    out,
+                    "<trkpt lat=\"{}\" lon=\"{}\"><time>{}</time><extensions><gpxtpx:TrackPointExtension>\
+                     <gpxtpx:speed>{}</gpxtpx:speed><gpxtpx:course>{}</gpxtpx:course>\
+                     </gpxtpx:TrackPointExtension></extensions></trkpt>",
+                    location.latitude,
+                    location.longitude,
+                    time,
+                    location.speed / 3.6,
+                    location.heading,
+                );
+            }
+            (TrackFormat::Kml, 0) => {
+                if new_segment {
+                    if previous.is_some() {
+                        out.push_str("</coordinates>\n</LineString>\n");
+                    }
+                    out.push_str("<LineString>\n<tessellate>1</tessellate>\n<coordinates>\n");
+                }
+                let _ = writeln!(out, "{},{}", location.longitude, location.latitude);
+            }
+            (TrackFormat::Kml, _) => {
+                let _ = writeln!(
+                    out,
+                    "<Placemark><TimeStamp><when>{}</when></TimeStamp><ExtendedData>\
+                     <Data name=\"route_id\"><value>{}</value></Data>\
+                     <Data name=\"speed\"><value>{}</value></Data>\
+                     <Data name=\"heading\"><value>{}</value></Data>\
+                     </ExtendedData><Point><coordinates>{},{}</coordinates></Point></Placemark>",
+                    time,
+                    location.route_id,
+                    location.speed,
+                    location.heading,
+                    location.longitude,
+                    location.latitude,
+                );
+            }
+            (TrackFormat::GeoJson, _) => {
+                if previous.is_some() {
+                    out.push_str(",\n");
+                }
+                let feature = serde_json::json!({
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "Point",
+                        "coordinates": [location.longitude, location.latitude],
+                    },
+                    "properties": {
+                        "id": location.id,
+                        "route_id": location.route_id,
+                        "vehicle_id": location.vehicle_id,
+                        "driver_id": location.driver_id,
+                        "timestamp": time,
+                        "speed": location.speed,
+                        "heading": location.heading,
+                    },
+                });
+                out.push_str(&feature.to_string());
+            }
+        }
+    }
+}
+
+struct Export {
+    pool: DbPool,
+    filter: TrackFilter,
+    format: TrackFormat,
+    name: String,
+    started: bool,
+    done: bool,
+    pass: usize,
+    // The last location written in this pass, which is also where the next chunk starts
+    previous: Option<Location>,
+}
+
+impl Export {
+    async fn next_chunk(&self) -> Result<Vec<Location>, actix_web::Error> {
+        sqlx::query_as::<_, Location>(
+            r#"
+            SELECT * FROM locations
+            WHERE ($1::uuid IS NULL OR route_id = $1)
+              AND ($2::uuid IS NULL OR vehicle_id = $2)
+              AND ($3::timestamptz IS NULL OR timestamp >= $3)
+              AND ($4::timestamptz IS NULL OR timestamp <= $4)
+              AND ($5::timestamptz IS NULL OR (timestamp, id) > ($5, $6))
+              AND ($8::uuid IS NULL OR driver_id = $8)
+            ORDER BY timestamp, id
+            LIMIT $7
+            "#
+        )
+        .bind(self.filter.route_id)
+        .bind(self.filter.vehicle_id)
+        .bind(self.filter.from)
+        .bind(self.filter.to)
+        .bind(self.previous.as_ref().map(|location| location.timestamp))
+        .bind(self.previous.as_ref().map(|location| location.id))
+        .bind(EXPORT_CHUNK_SIZE)
+        .bind(self.filter.driver_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })
+    }
+
+    fn finish_pass(&mut self, out: &mut String) {
+        self.format.pass_footer(out, self.pass, self.previous.as_ref());
+        self.pass += 1;
+        self.previous = None;
+
+        if self.pass < self.format.passes() {
+            self.format.pass_header(out, self.pass);
+        } else {
+            self.format.footer(out);
+            self.done = true;
+        }
+    }
+}
+
+// The document as a response body; a database error midway ends the body early
+pub fn export_track(
+    pool: DbPool,
+    filter: TrackFilter,
+    format: TrackFormat,
+    name: String,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let export = Export {
+        pool,
+        filter,
+        format,
+        name,
+        started: false,
+        done: false,
+        pass: 0,
+        previous: None,
+    };
+
+    futures::stream::unfold(export, |mut export| async move {
+        if export.done {
+            return None;
+        }
+
+        let mut out = String::new();
+        if !export.started {
+            export.started = true;
+            export.format.header(&mut out, &export.name);
+            export.format.pass_header(&mut out, 0);
+        }
+
+        let chunk = match export.next_chunk().await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                export.done = true;
+                return Some((Err(e), export));
+            }
+        };
+
+        for location in &chunk {
+            export.format.row(&mut out, export.pass, export.previous.as_ref(), location);
+            export.previous = Some(location.clone());
+        }
+
+        if (chunk.len() as i64) < EXPORT_CHUNK_SIZE {
+            export.finish_pass(&mut out);
+        }
+
+        Some((Ok(web::Bytes::from(out)), export))
+    })
+}
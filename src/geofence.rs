@@ -0,0 +1,366 @@
+// Geofence evaluation: works out which geofences each stored location is inside and records
+// the enter, exit and dwell events that follow from the vehicle's previous position
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use crate::handlers::routes::haversine_distance;
+use crate::models::{GeoPoint, Geofence, GeofenceEvent, GeofenceEventType, GeofenceShape, Location};
+
+// Class of the per-vehicle advisory locks taken while evaluating locations
+const PRESENCE_LOCK_CLASS: i32 = 0x6765_6f66;
+
+pub fn contains(geofence: &Geofence, point: GeoPoint) -> bool {
+    match geofence.shape {
+        GeofenceShape::Circle => match (geofence.center_latitude, geofence.center_longitude, geofence.radius_meters) {
+            (Some(latitude), Some(longitude), Some(radius)) => {
+                haversine_distance(latitude, longitude, point.latitude, point.longitude) * 1000.0 <= radius
+            }
+            _ => false,
+        },
+        GeofenceShape::Polygon => geofence
+            .vertices
+            .as_ref()
+            .is_some_and(|vertices| polygon_contains(vertices, point)),
+    }
+}
+
+// Even-odd ray casting with latitude and longitude as plane coordinates. Close enough for areas
+// the size of a depot or a district. Points on an edge are inside, as on a circle's rim, and
+// longitudes are taken relative to the first vertex so a polygon may cross the antimeridian.
+fn polygon_contains(vertices: &[GeoPoint], point: GeoPoint) -> bool {
+    if vertices.len() < 3 {
+        return false;
+    }
+
+    let reference = vertices[0].longitude;
+    let unwrap = |point: GeoPoint| GeoPoint {
+        latitude: point.latitude,
+        longitude: reference + wrap_longitude(point.longitude - reference),
+    };
+
+    let point = unwrap(point);
+    let mut inside = false;
+    let mut previous = unwrap(vertices[vertices.len() - 1]);
+    for &vertex in vertices {
+        let vertex = unwrap(vertex);
+        if on_edge(previous, vertex, point) {
+            return true;
+        }
+        if (vertex.latitude > point.latitude) != (previous.latitude > point.latitude) {
+            let crossing = vertex.longitude
+                + (point.latitude - vertex.latitude) * (previous.longitude - vertex.longitude)
+                    / (previous.latitude - vertex.latitude);
+            if point.longitude < crossing {
+                inside = !inside;
+            }
+        }
+        previous = vertex;
+    }
+
+    inside
+}
+
+// A longitude difference brought into [-180, 180)
+fn wrap_longitude(delta: f64) -> f64 {
+    (delta + 180.0).rem_euclid(360.0) - 180.0
+}
+
+fn on_edge(from: GeoPoint, to: GeoPoint, point: GeoPoint) -> bool {
+    let cross = (to.longitude - from.longitude) * (point.latitude - from.latitude)
+        - (to.latitude - from.latitude) * (point.longitude - from.longitude);
+
+    cross.abs() <= 1e-12
+        && point.latitude >= from.latitude.min(to.latitude)
+        && point.latitude <= from.latitude.max(to.latitude)
+        && point.longitude >= from.longitude.min(to.longitude)
+        && point.longitude <= from.longitude.max(to.longitude)
+}
+
+struct Presence {
+    entered_at: DateTime<Utc>,
+    dwell_reported: bool,
+}
+
+// Check newly stored locations against every geofence and record the resulting events, inside
+// the caller's transaction. Locations are taken in timestamp order per vehicle.
+pub async fn evaluate_locations(
+    conn: &mut sqlx::PgConnection,
+    locations: &[Location],
+) -> Result<Vec<GeofenceEvent>, actix_web::Error> {
+    if locations.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let geofences = sqlx::query_as::<_, Geofence>(
+        "SELECT * FROM geofences"
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if geofences.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut vehicle_ids: Vec<Uuid> = locations.iter().map(|location| location.vehicle_id).collect();
+    vehicle_ids.sort();
+    vehicle_ids.dedup();
+
+    // Writers handling the same vehicle at once would otherwise both see it outside and both
+    // report it entering. Locks are taken in id order so two batches cannot deadlock.
+    sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext(v::text)) FROM UNNEST($2::uuid[]) AS v")
+        .bind(PRESENCE_LOCK_CLASS)
+        .bind(&vehicle_ids)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    let rows = sqlx::query_as::<_, (Uuid, Uuid, DateTime<Utc>, bool)>(
+        "SELECT geofence_id, vehicle_id, entered_at, dwell_reported FROM geofence_presence WHERE vehicle_id = ANY($1)"
+    )
+    .bind(&vehicle_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut presence: HashMap<(Uuid, Uuid), Presence> = rows
+        .into_iter()
+        .map(|(geofence_id, vehicle_id, entered_at, dwell_reported)| {
+            ((geofence_id, vehicle_id), Presence { entered_at, dwell_reported })
+        })
+        .collect();
+
+    let mut ordered: Vec<&Location> = locations.iter().collect();
+    ordered.sort_by_key(|location| location.timestamp);
+
+    let mut changes: Vec<(Uuid, GeofenceEventType, &Location)> = Vec::new();
+    for location in ordered {
+        let point = GeoPoint {
+            latitude: location.latitude,
+            longitude: location.longitude,
+        };
+
+        for geofence in &geofences {
+            let key = (geofence.id, location.vehicle_id);
+            let inside = contains(geofence, point);
+
+            let Some(state) = presence.get_mut(&key) else {
+                if inside {
+                    presence.insert(key, Presence { entered_at: location.timestamp, dwell_reported: false });
+                    changes.push((geofence.id, GeofenceEventType::Enter, location));
+                }
+                continue;
+            };
+
+            if !inside {
+                presence.remove(&key);
+                changes.push((geofence.id, GeofenceEventType::Exit, location));
+                continue;
+            }
+
+            let dwelled = geofence.dwell_seconds.is_some_and(|dwell| {
+                location.timestamp - state.entered_at >= chrono::Duration::seconds(dwell as i64)
+            });
+            if dwelled && !state.dwell_reported {
+                state.dwell_reported = true;
+                changes.push((geofence.id, GeofenceEventType::Dwell, location));
+            }
+        }
+    }
+
+    let mut events = Vec::with_capacity(changes.len());
+    for (geofence_id, event_type, location) in &changes {
+        let event = sqlx::query_as::<_, GeofenceEvent>(
+            r#"
+            INSERT INTO geofence_events (geofence_id, event_type, vehicle_id, driver_id, route_id, location_id, latitude, longitude, occurred_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING *
+            "#
+        )
+        .bind(geofence_id)
+        .bind(event_type)
+        .bind(location.vehicle_id)
+        .bind(location.driver_id)
+        .bind(location.route_id)
+        .bind(location.id)
+        .bind(location.latitude)
+        .bind(location.longitude)
+        .bind(location.timestamp)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        events.push(event);
+    }
+
+    // Only presence that produced an event can have changed
+    let touched: HashSet<(Uuid, Uuid)> = changes
+        .iter()
+        .map(|(geofence_id, _, location)| (*geofence_id, location.vehicle_id))
+        .collect();
+
+    for (geofence_id, vehicle_id) in touched {
+        let result = match presence.get(&(geofence_id, vehicle_id)) {
+            Some(state) => sqlx::query(
+                r#"
+                INSERT INTO geofence_presence (geofence_id, vehicle_id, entered_at, dwell_reported)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (geofence_id, vehicle_id)
+                DO UPDATE SET entered_at = EXCLUDED.entered_at, dwell_reported = EXCLUDED.dwell_reported
+                "#
+            )
+            .bind(geofence_id)
+            .bind(vehicle_id)
+            .bind(state.entered_at)
+            .bind(state.dwell_reported)
+            .execute(&mut *conn)
+            .await,
+            None => sqlx::query(
+                "DELETE FROM geofence_presence WHERE geofence_id = $1 AND vehicle_id = $2"
+            )
+            .bind(geofence_id)
+            .bind(vehicle_id)
+            .execute(&mut *conn)
+            .await,
+        };
+
+        result.map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::GeofenceKind;
+
+    fn point(latitude: f64, longitude: f64) -> GeoPoint {
+        GeoPoint { latitude, longitude }
+    }
+
+    fn geofence(shape: GeofenceShape) -> Geofence {
+        Geofence {
+            id: Uuid::new_v4(),
+            name: "Depot".to_string(),
+            kind: GeofenceKind::Depot,
+            shape,
+            center_latitude: None,
+            center_longitude: None,
+            radius_meters: None,
+            vertices: None,
+            dwell_seconds: None,
+            speed_limit_kmh: None,
+            created_by: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn polygon(vertices: &[(f64, f64)]) -> Geofence {
+        Geofence {
+            vertices: Some(sqlx::types::Json(vertices.iter().map(|&(latitude, longitude)| point(latitude, longitude)).collect())),
+            ..geofence(GeofenceShape::Polygon)
+        }
+    }
+
+    fn circle(latitude: f64, longitude: f64, radius_meters: f64) -> Geofence {
+        Geofence {
+            center_latitude: Some(latitude),
+            center_longitude: Some(longitude),
+            radius_meters: Some(radius_meters),
+            ..geofence(GeofenceShape::Circle)
+        }
+    }
+
+    #[test]
+    fn square_contains_its_inside_only() {
+        let square = polygon(&[(52.0, 4.0), (52.0, 4.1), (52.1, 4.1), (52.1, 4.0)]);
+
+        assert!(contains(&square, point(52.05, 4.05)));
+        assert!(!contains(&square, point(52.05, 4.2)));
+        assert!(!contains(&square, point(51.9, 4.05)));
+    }
+
+    #[test]
+    fn points_on_every_edge_and_vertex_are_inside() {
+        let square = polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)]);
+
+        for edge in [point(0.0, 0.5), point(1.0, 0.5), point(0.5, 0.0), point(0.5, 1.0)] {
+            assert!(contains(&square, edge), "{:?} is on an edge", edge);
+        }
+        for vertex in [point(0.0, 0.0), point(0.0, 1.0), point(1.0, 1.0), point(1.0, 0.0)] {
+            assert!(contains(&square, vertex), "{:?} is a vertex", vertex);
+        }
+        assert!(!contains(&square, point(1.000001, 0.5)));
+    }
+
+    #[test]
+    fn concave_notch_is_outside() {
+        // A U shape opening north
+        let u = polygon(&[(0.0, 0.0), (0.0, 3.0), (3.0, 3.0), (3.0, 2.0), (1.0, 2.0), (1.0, 1.0), (3.0, 1.0), (3.0, 0.0)]);
+
+        assert!(!contains(&u, point(2.0, 1.5)));
+        assert!(contains(&u, point(2.0, 0.5)));
+        assert!(contains(&u, point(0.5, 1.5)));
+    }
+
+    #[test]
+    fn polygon_across_the_antimeridian() {
+        let strait = polygon(&[(65.0, 179.0), (65.0, -179.0), (66.0, -179.0), (66.0, 179.0)]);
+
+        assert!(contains(&strait, point(65.5, 179.5)));
+        assert!(contains(&strait, point(65.5, -179.5)));
+        assert!(contains(&strait, point(65.5, 180.0)));
+        assert!(contains(&strait, point(65.5, -180.0)));
+        assert!(!contains(&strait, point(65.5, 0.0)));
+        assert!(!contains(&strait, point(65.5, 178.5)));
+        assert!(!contains(&strait, point(65.5, -178.5)));
+    }
+
+    #[test]
+    fn polygons_without_three_vertices_contain_nothing() {
+        assert!(!contains(&polygon(&[(0.0, 0.0), (1.0, 1.0)]), point(0.5, 0.5)));
+        assert!(!contains(&geofence(GeofenceShape::Polygon), point(0.0, 0.0)));
+    }
+
+    #[test]
+    fn circle_includes_its_rim() {
+        let depot = circle(52.0, 4.0, 1000.0);
+        let rim = haversine_distance(52.0, 4.0, 52.0, 4.01) * 1000.0;
+
+        assert!(contains(&circle(52.0, 4.0, rim), point(52.0, 4.01)));
+        assert!(contains(&depot, point(52.005, 4.0)));
+        assert!(!contains(&depot, point(52.01, 4.0)));
+    }
+
+    #[test]
+    fn circle_across_the_antimeridian() {
+        let harbour = circle(-17.0, 179.999, 500.0);
+
+        assert!(contains(&harbour, point(-17.0, -179.999)));
+        assert!(!contains(&harbour, point(-17.0, 179.9)));
+    }
+
+    #[test]
+    fn circle_without_a_radius_contains_nothing() {
+        let incomplete = Geofence { radius_meters: None, ..circle(52.0, 4.0, 100.0) };
+
+        assert!(!contains(&incomplete, point(52.0, 4.0)));
+    }
+}
@@ -0,0 +1,113 @@
+use actix_web::{delete, get, put, web, HttpResponse};
+use crate::models::{Alert, AlertQuery, SetSpeedLimitRequest, SpeedLimit};
+use crate::auth::{AdminManagerOrDispatcher, AdminOrManager, Authorized};
+use crate::database::DbPool;
+use crate::validation::validate_speed_limit;
+
+// Most recent alerts first, capped so a wide query cannot return the whole history
+#[get("/alerts")]
+pub async fn get_alerts(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    query: web::Query<AlertQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let alerts = sqlx::query_as::<_, Alert>(
+        r#"
+        SELECT * FROM alerts
+        WHERE ($1::uuid IS NULL OR vehicle_id = $1)
+          AND ($2::uuid IS NULL OR driver_id = $2)
+          AND ($3::alert_type IS NULL OR alert_type = $3)
+          AND ($4::timestamptz IS NULL OR occurred_at >= $4)
+          AND ($5::timestamptz IS NULL OR occurred_at <= $5)
+        ORDER BY occurred_at DESC
+        LIMIT 1000
+        "#
+    )
+    .bind(query.vehicle_id)
+    .bind(query.driver_id)
+    .bind(query.alert_type)
+    .bind(query.from)
+    .bind(query.to)
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(alerts))
+}
+
+#[get("/speed-limits")]
+pub async fn get_speed_limits(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let limits = sqlx::query_as::<_, SpeedLimit>(
+        "SELECT * FROM speed_limits ORDER BY vehicle_type"
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(limits))
+}
+
+// Applies to vehicles whose vehicle_type matches exactly
+#[put("/speed-limits/{vehicle_type}")]
+pub async fn set_speed_limit(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOrManager>,
+    vehicle_type: web::Path<String>,
+    limit_data: web::Json<SetSpeedLimitRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    validate_speed_limit(&limit_data)?;
+
+    let limit = sqlx::query_as::<_, SpeedLimit>(
+        r#"
+        INSERT INTO speed_limits (vehicle_type, speed_limit_kmh)
+        VALUES ($1, $2)
+        ON CONFLICT (vehicle_type) DO UPDATE
+        SET speed_limit_kmh = EXCLUDED.speed_limit_kmh, updated_at = NOW()
+        RETURNING *
+        "#
+    )
+    .bind(vehicle_type.into_inner())
+    .bind(limit_data.speed_limit_kmh)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(limit))
+}
+
+// Vehicles of this type fall back to the default limit
+#[delete("/speed-limits/{vehicle_type}")]
+pub async fn delete_speed_limit(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOrManager>,
+    vehicle_type: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let result = sqlx::query(
+        "DELETE FROM speed_limits WHERE vehicle_type = $1"
+    )
+    .bind(vehicle_type.into_inner())
+    .execute(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(actix_web::error::ErrorNotFound("Speed limit not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
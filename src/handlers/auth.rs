@@ -1,128 +1,536 @@
-use actix_web::{web, HttpResponse, HttpRequest};
-use serde_json::json;
-use sqlx::PgPool;
-use crate::models::{LoginRequest, CreateUserRequest, AuthResponse, User};
-use crate::auth::{hash_password, verify_password, create_token, extract_token_from_header, validate_token};
-use crate::database::DbPool;
-
-pub async fn login(
-    pool: web::Data<DbPool>,
-    login_data: web::Json<LoginRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    let user = sqlx::query_as::<_, User>(
-        "SELECT * FROM users WHERE username = $1"
-    )
-    .bind(&login_data.username)
-    .fetch_optional(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    let user = user.ok_or_else(|| {
-        actix_web::error::ErrorUnauthorized("Invalid credentials")
-    })?;
-
-    if !verify_password(&login_data.password, &user.password_hash)
-        .map_err(|e| {
-            eprintln!("Password verification error: {}", e);
-            actix_web::error::ErrorInternalServerError("Password verification error")
-        })? {
-        return Err(actix_web::error::ErrorUnauthorized("Invalid credentials"));
-    }
-
-    let token = create_token(&user.id.to_string(), &user.username, &format!("{:?}", user.role))
-        .map_err(|e| {
-            eprintln!("Token creation error: {}", e);
-            actix_web::error::ErrorInternalServerError("Token creation error")
-        })?;
-
-    let response = AuthResponse {
-        token,
-        user,
-    };
-
-    Ok(HttpResponse::Ok().json(response))
-}
-
-pub async fn register(
-    pool: web::Data<DbPool>,
-    user_data: web::Json<CreateUserRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if user already exists
-    let existing_user = sqlx::query_as::<_, User>(
-        "SELECT * FROM users WHERE username = $1 OR email = $2"
-    )
-    .bind(&user_data.username)
-    .bind(&user_data.email)
-    .fetch_optional(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    if existing_user.is_some() {
-        return Err(actix_web::error::ErrorBadRequest("Username or email already exists"));
-    }
-
-    let password_hash = hash_password(&user_data.password)
-        .map_err(|e| {
-            eprintln!("Password hashing error: {}", e);
-            actix_web::error::ErrorInternalServerError("Password hashing error")
-        })?;
-
-    let user = sqlx::query_as::<_, User>(
-        r#"
-        INSERT INTO users (username, email, password_hash, role)
-        VALUES ($1, $2, $3, $4)
-        RETURNING *
-        "#
-    )
-    .bind(&user_data.username)
-    .bind(&user_data.email)
-    .bind(&password_hash)
-    .bind(&user_data.role)
-    .fetch_one(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    let token = create_token(&user.id.to_string(), &user.username, &format!("{:?}", user.role))
-        .map_err(|e| {
-            eprintln!("Token creation error: {}", e);
-            actix_web::error::ErrorInternalServerError("Token creation error")
-        })?;
-
-    let response = AuthResponse {
-        token,
-        user,
-    };
-
-    Ok(HttpResponse::Created().json(response))
-}
-
-pub async fn verify_token(
-    req: HttpRequest,
-) -> Result<HttpResponse, actix_web::Error> {
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    Ok(HttpResponse::Ok().json(json!({
-        "valid": true,
-        "user_id": claims.sub,
-        "username": claims.username,
-        "role": claims.role
-    })))
-}
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use serde_json::json;
+use uuid::Uuid;
+use crate::models::{
+    LoginRequest, RegisterRequest, VerifyEmailRequest, AuthResponse, User, UserRole,
+    RefreshToken, RefreshTokenRequest, LogoutRequest,
+};
+use crate::config::{Config, RegistrationMode};
+use crate::handlers::invitations::find_valid_invitation;
+use crate::auth::{
+    hash_password, verify_password, create_token, generate_opaque_token, split_opaque_token,
+    broadcast_revocation, AuthUser, Revocation,
+};
+use crate::database::DbPool;
+
+// How long registration waits for the mail service to take a verification email
+const VERIFICATION_WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[post("/login")]
+pub async fn login(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    login_data: web::Json<LoginRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE username = $1"
+    )
+    .bind(&login_data.username)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let user = user.ok_or_else(|| {
+        actix_web::error::ErrorUnauthorized("Invalid credentials")
+    })?;
+
+    if !verify_password(&login_data.password, &user.password_hash)
+        .map_err(|e| {
+            eprintln!("Password verification error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password verification error")
+        })? {
+        return Err(actix_web::error::ErrorUnauthorized("Invalid credentials"));
+    }
+
+    if user.email_verified_at.is_none() {
+        return Err(actix_web::error::ErrorForbidden("Email address not verified"));
+    }
+
+    let response = issue_session(pool.get_ref(), &config, user).await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// Callers never choose their own role: it is pinned by the invitation, or is `Customer` when
+// registration is open, so fleet and driver access always takes an invitation
+#[post("/register")]
+pub async fn register(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    user_data: web::Json<RegisterRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    match (config.registration_mode, &user_data.invitation_token) {
+        (RegistrationMode::Disabled, _) => {
+            return Err(actix_web::error::ErrorForbidden("Registration is disabled"));
+        }
+        (RegistrationMode::InviteOnly, None) => {
+            return Err(actix_web::error::ErrorForbidden("Registration requires an invitation"));
+        }
+        _ => {}
+    }
+
+    let invitation = match &user_data.invitation_token {
+        Some(token) => Some(find_valid_invitation(pool.get_ref(), token, &user_data.email).await?),
+        None => None,
+    };
+
+    let role = invitation.as_ref().map(|i| i.role).unwrap_or(UserRole::Customer);
+
+    // An invitation addressed to this email already proves ownership of the address
+    let email_verified_at = invitation.as_ref()
+        .and_then(|i| i.email.as_ref())
+        .map(|_| Utc::now());
+
+    // Check if user already exists
+    let existing_user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE username = $1 OR email = $2"
+    )
+    .bind(&user_data.username)
+    .bind(&user_data.email)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if existing_user.is_some() {
+        return Err(actix_web::error::ErrorBadRequest("Username or email already exists"));
+    }
+
+    let password_hash = hash_password(&user_data.password)
+        .map_err(|e| {
+            eprintln!("Password hashing error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password hashing error")
+        })?;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (username, email, password_hash, role, email_verified_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING *
+        "#
+    )
+    .bind(&user_data.username)
+    .bind(&user_data.email)
+    .bind(&password_hash)
+    .bind(role)
+    .bind(email_verified_at)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if let Some(invitation) = &invitation {
+        let accepted = sqlx::query(
+            r#"
+            UPDATE invitations SET accepted_at = NOW(), accepted_by = $2
+            WHERE id = $1 AND accepted_at IS NULL AND revoked_at IS NULL
+            "#
+        )
+        .bind(invitation.id)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?
+        .rows_affected();
+
+        // Lost a race with another registration using the same invitation
+        if accepted == 0 {
+            return Err(actix_web::error::ErrorBadRequest("Invitation has already been used"));
+        }
+    }
+
+    let verification_token = match user.email_verified_at {
+        Some(_) => None,
+        None => Some(store_email_verification(&mut tx, user.id).await?),
+    };
+
+    // Before committing, so an account whose token could not be sent is not left unverifiable
+    if let Some(token) = &verification_token {
+        deliver_verification_token(&config, &user, token).await?;
+    }
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match verification_token {
+        None => {
+            let response = issue_session(pool.get_ref(), &config, user).await?;
+            Ok(HttpResponse::Created().json(response))
+        }
+        Some(_) => {
+            Ok(HttpResponse::Accepted().json(json!({
+                "user": user,
+                "email_verification_required": true
+            })))
+        }
+    }
+}
+
+#[post("/verify-email")]
+pub async fn verify_email(
+    pool: web::Data<DbPool>,
+    verify_data: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (user_id, secret) = split_opaque_token(&verify_data.token)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid verification token"))?;
+
+    let pending = sqlx::query_as::<_, (Option<String>, Option<DateTime<Utc>>)>(
+        "SELECT email_verification_hash, email_verification_expires_at FROM users WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let Some((Some(verification_hash), Some(expires_at))) = pending else {
+        return Err(actix_web::error::ErrorBadRequest("Invalid verification token"));
+    };
+
+    if !verify_password(secret, &verification_hash)
+        .map_err(|e| {
+            eprintln!("Password verification error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password verification error")
+        })? {
+        return Err(actix_web::error::ErrorBadRequest("Invalid verification token"));
+    }
+
+    if expires_at <= Utc::now() {
+        return Err(actix_web::error::ErrorBadRequest("Verification token has expired"));
+    }
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET email_verified_at = NOW(), email_verification_hash = NULL,
+            email_verification_expires_at = NULL, updated_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(user_id)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[get("/verify")]
+pub async fn verify_token(
+    user: AuthUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().json(json!({
+        "valid": true,
+        "user_id": user.id,
+        "username": user.username,
+        "role": user.role
+    })))
+}
+
+#[post("/token/refresh")]
+pub async fn refresh_session(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    refresh_data: web::Json<RefreshTokenRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (token_id, secret) = split_opaque_token(&refresh_data.refresh_token)
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid refresh token"))?;
+
+    let stored = sqlx::query_as::<_, RefreshToken>(
+        "SELECT * FROM refresh_tokens WHERE id = $1"
+    )
+    .bind(token_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid refresh token"))?;
+
+    if !verify_password(secret, &stored.token_hash)
+        .map_err(|e| {
+            eprintln!("Password verification error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password verification error")
+        })? {
+        return Err(actix_web::error::ErrorUnauthorized("Invalid refresh token"));
+    }
+
+    if stored.revoked_at.is_some() {
+        // A rotated token being replayed means it leaked, so end every session of this user
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL"
+        )
+        .bind(stored.user_id)
+        .execute(pool.get_ref())
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        return Err(actix_web::error::ErrorUnauthorized("Refresh token has been revoked"));
+    }
+
+    if stored.expires_at <= Utc::now() {
+        return Err(actix_web::error::ErrorUnauthorized("Refresh token has expired"));
+    }
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(stored.user_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid refresh token"))?;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    // Rotate: the presented token is consumed and replaced by a fresh one
+    let (new_token_id, new_refresh_token) = store_refresh_token(&mut tx, &config, user.id).await?;
+
+    let rotated = sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = NOW(), replaced_by = $2 WHERE id = $1 AND revoked_at IS NULL"
+    )
+    .bind(stored.id)
+    .bind(new_token_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .rows_affected();
+
+    // Lost a race with a concurrent refresh of the same token
+    if rotated == 0 {
+        return Err(actix_web::error::ErrorUnauthorized("Refresh token has been revoked"));
+    }
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let token = create_token(&user.id.to_string(), &user.username, user.role, &config)
+        .map_err(|e| {
+            eprintln!("Token creation error: {}", e);
+            actix_web::error::ErrorInternalServerError("Token creation error")
+        })?;
+
+    Ok(HttpResponse::Ok().json(AuthResponse {
+        token,
+        refresh_token: new_refresh_token,
+        user,
+    }))
+}
+
+#[post("/logout")]
+pub async fn logout(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    user: AuthUser,
+    logout_data: Option<web::Json<LogoutRequest>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let expires_at = DateTime::<Utc>::from_timestamp(user.token_expires_at, 0)
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid token"))?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO revoked_tokens (jti, user_id, expires_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (jti) DO NOTHING
+        "#
+    )
+    .bind(user.token_id)
+    .bind(user.id)
+    .bind(expires_at)
+    .execute(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    broadcast_revocation(
+        pool.get_ref(),
+        &config,
+        Revocation::Token {
+            jti: user.token_id,
+            expires_at: user.token_expires_at,
+        },
+    )
+    .await?;
+
+    let refresh_token = logout_data.and_then(|data| data.into_inner().refresh_token);
+    if let Some((token_id, _)) = refresh_token.as_deref().and_then(split_opaque_token) {
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"
+        )
+        .bind(token_id)
+        .bind(user.id)
+        .execute(pool.get_ref())
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Issue an access token and a new refresh token for a freshly authenticated user
+async fn issue_session(pool: &DbPool, config: &Config, user: User) -> Result<AuthResponse, actix_web::Error> {
+    let token = create_token(&user.id.to_string(), &user.username, user.role, config)
+        .map_err(|e| {
+            eprintln!("Token creation error: {}", e);
+            actix_web::error::ErrorInternalServerError("Token creation error")
+        })?;
+
+    let mut conn = pool.acquire().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let (_, refresh_token) = store_refresh_token(&mut conn, config, user.id).await?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        user,
+    })
+}
+
+async fn store_refresh_token(
+    conn: &mut sqlx::PgConnection,
+    config: &Config,
+    user_id: Uuid,
+) -> Result<(Uuid, String), actix_web::Error> {
+    let token_id = Uuid::new_v4();
+    let (refresh_token, secret) = generate_opaque_token(token_id);
+
+    let token_hash = hash_password(&secret)
+        .map_err(|e| {
+            eprintln!("Password hashing error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password hashing error")
+        })?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO refresh_tokens (id, user_id, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#
+    )
+    .bind(token_id)
+    .bind(user_id)
+    .bind(&token_hash)
+    .bind(Utc::now() + config.refresh_token_ttl())
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok((token_id, refresh_token))
+}
+
+// Hand a verification token to the mail service behind `VERIFICATION_WEBHOOK_URL`, which answers
+// with a 2xx status once it has taken the email. Development setups may log the token instead,
+// since anyone reading the log could verify the address with it.
+async fn deliver_verification_token(config: &Config, user: &User, token: &str) -> Result<(), actix_web::Error> {
+    if !config.delivers_verification_tokens() {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "Email verification is not available; register with an invitation addressed to your email",
+        ));
+    }
+
+    if config.log_verification_tokens {
+        info!("Email verification token for {}: {}", user.email, token);
+    }
+
+    if let Some(url) = &config.verification_webhook_url {
+        reqwest::Client::new()
+            .post(url)
+            .timeout(VERIFICATION_WEBHOOK_TIMEOUT)
+            .json(&json!({
+                "user_id": user.id,
+                "username": user.username,
+                "email": user.email,
+                "token": token
+            }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                eprintln!("Verification delivery error: {}", e);
+                actix_web::error::ErrorServiceUnavailable("Could not send the verification email")
+            })?;
+    }
+
+    Ok(())
+}
+
+async fn store_email_verification(
+    conn: &mut sqlx::PgConnection,
+    user_id: Uuid,
+) -> Result<String, actix_web::Error> {
+    let (token, secret) = generate_opaque_token(user_id);
+
+    let verification_hash = hash_password(&secret)
+        .map_err(|e| {
+            eprintln!("Password hashing error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password hashing error")
+        })?;
+
+    sqlx::query(
+        r#"
+        UPDATE users SET email_verification_hash = $2, email_verification_expires_at = $3
+        WHERE id = $1
+        "#
+    )
+    .bind(user_id)
+    .bind(&verification_hash)
+    .bind(Utc::now() + Duration::hours(48))
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(token)
+}
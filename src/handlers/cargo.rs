@@ -1,75 +1,320 @@
-use actix_web::{web, HttpResponse, HttpRequest};
-use sqlx::PgPool;
-use crate::models::{CreateCargoRequest, Cargo};
-use crate::auth::{extract_token_from_header, validate_token};
-use crate::database::DbPool;
-
-pub async fn create_cargo(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-    cargo_data: web::Json<CreateCargoRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let _claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    let cargo = sqlx::query_as::<_, Cargo>(
-        r#"
-        INSERT INTO cargo (description, weight, volume, cargo_type, priority, shipper_id, consignee_id)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
-        RETURNING *
-        "#
-    )
-    .bind(&cargo_data.description)
-    .bind(&cargo_data.weight)
-    .bind(&cargo_data.volume)
-    .bind(&cargo_data.cargo_type)
-    .bind(&cargo_data.priority)
-    .bind(&cargo_data.shipper_id)
-    .bind(&cargo_data.consignee_id)
-    .fetch_one(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Created().json(cargo))
-}
-
-pub async fn get_cargo(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let _claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    let cargo = sqlx::query_as::<_, Cargo>(
-        "SELECT * FROM cargo ORDER BY created_at DESC"
-    )
-    .fetch_all(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Ok().json(cargo))
-}
+use actix_web::{get, post, web, HttpResponse};
+use uuid::Uuid;
+use crate::models::{CreateCargoRequest, Cargo, CargoEvent, CargoStatus, CargoTransitionRequest, UserRole};
+use crate::auth::{AuthUser, Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::events::{publish_status_changes, StatusChange};
+
+// Who caused a cargo status change and where; `actor_id` is None for automatic transitions
+#[derive(Debug, Default)]
+pub struct CargoEventDetails {
+    pub actor_id: Option<Uuid>,
+    pub route_id: Option<Uuid>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub note: Option<String>,
+}
+
+#[post("/cargo")]
+pub async fn create_cargo(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+    cargo_data: web::Json<CreateCargoRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let cargo = sqlx::query_as::<_, Cargo>(
+        r#"
+        INSERT INTO cargo (description, weight, volume, cargo_type, priority, shipper_id, consignee_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING *
+        "#
+    )
+    .bind(&cargo_data.description)
+    .bind(cargo_data.weight)
+    .bind(cargo_data.volume)
+    .bind(&cargo_data.cargo_type)
+    .bind(cargo_data.priority)
+    .bind(cargo_data.shipper_id)
+    .bind(cargo_data.consignee_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let details = CargoEventDetails {
+        actor_id: Some(user.id),
+        ..Default::default()
+    };
+    insert_cargo_event(&mut tx, &cargo, None, &details).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Created().json(cargo))
+}
+
+// Customers only see cargo they ship or receive
+#[get("/cargo")]
+pub async fn get_cargo(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let cargo = sqlx::query_as::<_, Cargo>(
+        r#"
+        SELECT * FROM cargo
+        WHERE $1::uuid IS NULL OR shipper_id = $1 OR consignee_id = $1
+        ORDER BY created_at DESC
+        "#
+    )
+    .bind(customer_scope(&user))
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(cargo))
+}
+
+#[get("/cargo/{cargo_id}")]
+pub async fn get_cargo_item(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    cargo_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let cargo = sqlx::query_as::<_, Cargo>(
+        "SELECT * FROM cargo WHERE id = $1 AND ($2::uuid IS NULL OR shipper_id = $2 OR consignee_id = $2)"
+    )
+    .bind(cargo_id.into_inner())
+    .bind(customer_scope(&user))
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match cargo {
+        Some(cargo) => Ok(HttpResponse::Ok().json(cargo)),
+        None => Err(actix_web::error::ErrorNotFound("Cargo not found")),
+    }
+}
+
+// Staff can cancel cargo and deliver cargo in transit; drivers can only deliver cargo on the route
+// they are driving
+#[post("/cargo/{cargo_id}/transitions")]
+pub async fn transition_cargo_status(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    cargo_id: web::Path<Uuid>,
+    transition: web::Json<CargoTransitionRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let cargo_id = cargo_id.into_inner();
+    let transition = transition.into_inner();
+
+    if user.role == UserRole::Customer {
+        return Err(actix_web::error::ErrorForbidden("Customers cannot change cargo status"));
+    }
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    // Locked first, so the cargo cannot be put on or taken off a route while this runs
+    let status = sqlx::query_scalar::<_, CargoStatus>(
+        "SELECT status FROM cargo WHERE id = $1 FOR UPDATE"
+    )
+    .bind(cargo_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Cargo not found"))?;
+
+    // The planned or running route carrying the cargo, if any
+    let route = sqlx::query_as::<_, (Uuid, Uuid)>(
+        r#"
+        SELECT r.id, r.driver_id FROM routes r
+        JOIN route_stops s ON s.route_id = r.id
+        JOIN route_stop_cargo sc ON sc.stop_id = s.id
+        WHERE sc.cargo_id = $1 AND r.status IN ('planned', 'inprogress')
+        ORDER BY r.created_at DESC
+        LIMIT 1
+        "#
+    )
+    .bind(cargo_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if user.role == UserRole::Driver {
+        if route.is_none_or(|(_, driver_id)| driver_id != user.id) {
+            return Err(actix_web::error::ErrorForbidden("Drivers can only update cargo on their own routes"));
+        }
+        if transition.status != CargoStatus::Delivered {
+            return Err(actix_web::error::ErrorForbidden("Drivers can only mark cargo delivered"));
+        }
+    }
+
+    if status.can_transition_to(transition.status) && !status.can_set_manually(transition.status) {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "Cargo goes from {:?} to {:?} with its route; start, complete or cancel the route instead",
+            status, transition.status
+        )));
+    }
+
+    let route_id = route.map(|(route_id, _)| route_id);
+    let details = CargoEventDetails {
+        actor_id: Some(user.id),
+        route_id,
+        latitude: transition.latitude,
+        longitude: transition.longitude,
+        note: transition.note,
+    };
+
+    let cargo = transition_cargo(&mut tx, cargo_id, transition.status, &details).await?;
+
+    publish_status_changes(&mut tx, &[StatusChange::cargo(&cargo, route_id)]).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(cargo))
+}
+
+#[get("/cargo/{cargo_id}/events")]
+pub async fn get_cargo_timeline(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    cargo_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let cargo_id = cargo_id.into_inner();
+
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM cargo WHERE id = $1 AND ($2::uuid IS NULL OR shipper_id = $2 OR consignee_id = $2))"
+    )
+    .bind(cargo_id)
+    .bind(customer_scope(&user))
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if !exists {
+        return Err(actix_web::error::ErrorNotFound("Cargo not found"));
+    }
+
+    let events = sqlx::query_as::<_, CargoEvent>(
+        "SELECT * FROM cargo_events WHERE cargo_id = $1 ORDER BY created_at ASC"
+    )
+    .bind(cargo_id)
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
+
+// The customer whose cargo a caller is limited to, or None for fleet users who see everything
+fn customer_scope(user: &AuthUser) -> Option<Uuid> {
+    match user.role {
+        UserRole::Customer => Some(user.id),
+        _ => None,
+    }
+}
+
+// Move cargo to `next` if the state machine allows it and record the event; used by the
+// transition endpoint and by route operations, inside the caller's transaction
+pub async fn transition_cargo(
+    conn: &mut sqlx::PgConnection,
+    cargo_id: Uuid,
+    next: CargoStatus,
+    details: &CargoEventDetails,
+) -> Result<Cargo, actix_web::Error> {
+    let cargo = sqlx::query_as::<_, Cargo>(
+        "SELECT * FROM cargo WHERE id = $1 FOR UPDATE"
+    )
+    .bind(cargo_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Cargo not found"))?;
+
+    if !cargo.status.can_transition_to(next) {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "Cannot change cargo status from {:?} to {:?}",
+            cargo.status, next
+        )));
+    }
+
+    let updated = sqlx::query_as::<_, Cargo>(
+        "UPDATE cargo SET status = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(cargo_id)
+    .bind(next)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    insert_cargo_event(conn, &updated, Some(cargo.status), details).await?;
+
+    Ok(updated)
+}
+
+async fn insert_cargo_event(
+    conn: &mut sqlx::PgConnection,
+    cargo: &Cargo,
+    from_status: Option<CargoStatus>,
+    details: &CargoEventDetails,
+) -> Result<(), actix_web::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO cargo_events (cargo_id, from_status, to_status, actor_id, route_id, latitude, longitude, note)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#
+    )
+    .bind(cargo.id)
+    .bind(from_status)
+    .bind(cargo.status)
+    .bind(details.actor_id)
+    .bind(details.route_id)
+    .bind(details.latitude)
+    .bind(details.longitude)
+    .bind(&details.note)
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(())
+}
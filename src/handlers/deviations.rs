@@ -0,0 +1,104 @@
+use actix_web::{delete, get, put, web, HttpResponse};
+use uuid::Uuid;
+use crate::models::{RouteDeviationEvent, RoutePath, SetRoutePathRequest};
+use crate::auth::{AdminManagerOrDispatcher, Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::validation::validate_route_path;
+
+// The road geometry a route is expected to follow, replacing the straight legs between its stops
+#[put("/routes/{route_id}/path")]
+pub async fn set_route_path(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    route_id: web::Path<Uuid>,
+    path_data: web::Json<SetRoutePathRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    validate_route_path(&path_data)?;
+
+    let path = sqlx::query_as::<_, RoutePath>(
+        r#"
+        INSERT INTO route_paths (route_id, points)
+        SELECT id, $2::jsonb FROM routes WHERE id = $1
+        ON CONFLICT (route_id) DO UPDATE
+        SET points = EXCLUDED.points, updated_at = NOW()
+        RETURNING *
+        "#
+    )
+    .bind(route_id.into_inner())
+    .bind(sqlx::types::Json(&path_data.points))
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Route not found"))?;
+
+    Ok(HttpResponse::Ok().json(path))
+}
+
+#[get("/routes/{route_id}/path")]
+pub async fn get_route_path(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let path = sqlx::query_as::<_, RoutePath>(
+        "SELECT * FROM route_paths WHERE route_id = $1"
+    )
+    .bind(route_id.into_inner())
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Route path not found"))?;
+
+    Ok(HttpResponse::Ok().json(path))
+}
+
+// The route falls back to straight legs between its stops
+#[delete("/routes/{route_id}/path")]
+pub async fn delete_route_path(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let result = sqlx::query(
+        "DELETE FROM route_paths WHERE route_id = $1"
+    )
+    .bind(route_id.into_inner())
+    .execute(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(actix_web::error::ErrorNotFound("Route path not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/routes/{route_id}/deviations")]
+pub async fn get_route_deviations(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let events = sqlx::query_as::<_, RouteDeviationEvent>(
+        "SELECT * FROM route_deviation_events WHERE route_id = $1 ORDER BY occurred_at ASC"
+    )
+    .bind(route_id.into_inner())
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
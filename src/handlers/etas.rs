@@ -0,0 +1,86 @@
+use actix_web::{get, web, HttpResponse};
+use uuid::Uuid;
+use crate::models::{RouteEta, UserRole};
+use crate::auth::AuthUser;
+use crate::database::DbPool;
+
+// The latest estimate for a running route
+#[get("/routes/{route_id}/eta")]
+pub async fn get_route_eta(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let route_id = route_id.into_inner();
+    ensure_eta_reader(pool.get_ref(), &user, route_id).await?;
+
+    let eta = sqlx::query_as::<_, RouteEta>(
+        "SELECT * FROM route_etas WHERE route_id = $1 ORDER BY computed_at DESC LIMIT 1"
+    )
+    .bind(route_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("No ETA for this route yet"))?;
+
+    Ok(HttpResponse::Ok().json(eta))
+}
+
+// Every estimate stored for a route, oldest first
+#[get("/routes/{route_id}/eta/history")]
+pub async fn get_route_eta_history(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let route_id = route_id.into_inner();
+    ensure_eta_reader(pool.get_ref(), &user, route_id).await?;
+
+    let etas = sqlx::query_as::<_, RouteEta>(
+        "SELECT * FROM route_etas WHERE route_id = $1 ORDER BY computed_at ASC"
+    )
+    .bind(route_id)
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(etas))
+}
+
+// Customers may read the ETAs of routes carrying cargo they ship or receive, everyone else any route's
+async fn ensure_eta_reader(pool: &DbPool, user: &AuthUser, route_id: Uuid) -> Result<(), actix_web::Error> {
+    if user.role != UserRole::Customer {
+        return Ok(());
+    }
+
+    let allowed = sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM route_stops s
+            JOIN route_stop_cargo sc ON sc.stop_id = s.id
+            JOIN cargo c ON c.id = sc.cargo_id
+            WHERE s.route_id = $1 AND (c.shipper_id = $2 OR c.consignee_id = $2)
+        )
+        "#
+    )
+    .bind(route_id)
+    .bind(user.id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorNotFound("Route not found"))
+    }
+}
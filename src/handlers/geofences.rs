@@ -0,0 +1,136 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use uuid::Uuid;
+use crate::models::{CreateGeofenceRequest, Geofence, GeofenceEvent};
+use crate::auth::{AdminManagerOrDispatcher, Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::validation::validate_geofence;
+
+// Geofences apply to locations stored after they are created
+#[post("/geofences")]
+pub async fn create_geofence(
+    pool: web::Data<DbPool>,
+    user: Authorized<AdminManagerOrDispatcher>,
+    geofence_data: web::Json<CreateGeofenceRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    validate_geofence(&geofence_data)?;
+
+    let geofence = sqlx::query_as::<_, Geofence>(
+        r#"
+        INSERT INTO geofences (
+            name, kind, shape, center_latitude, center_longitude, radius_meters, vertices, dwell_seconds,
+            speed_limit_kmh, created_by
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING *
+        "#
+    )
+    .bind(geofence_data.name.trim())
+    .bind(geofence_data.kind)
+    .bind(geofence_data.shape)
+    .bind(geofence_data.center_latitude)
+    .bind(geofence_data.center_longitude)
+    .bind(geofence_data.radius_meters)
+    .bind(geofence_data.vertices.clone().map(sqlx::types::Json))
+    .bind(geofence_data.dwell_seconds)
+    .bind(geofence_data.speed_limit_kmh)
+    .bind(user.id)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Created().json(geofence))
+}
+
+#[get("/geofences")]
+pub async fn get_geofences(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let geofences = sqlx::query_as::<_, Geofence>(
+        "SELECT * FROM geofences ORDER BY name"
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(geofences))
+}
+
+#[get("/geofences/{geofence_id}")]
+pub async fn get_geofence(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    geofence_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let geofence = fetch_geofence(pool.get_ref(), geofence_id.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(geofence))
+}
+
+// Deleting a geofence also deletes its events
+#[delete("/geofences/{geofence_id}")]
+pub async fn delete_geofence(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    geofence_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let result = sqlx::query(
+        "DELETE FROM geofences WHERE id = $1"
+    )
+    .bind(geofence_id.into_inner())
+    .execute(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(actix_web::error::ErrorNotFound("Geofence not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/geofences/{geofence_id}/events")]
+pub async fn get_geofence_events(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    geofence_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let geofence_id = geofence_id.into_inner();
+    fetch_geofence(pool.get_ref(), geofence_id).await?;
+
+    let events = sqlx::query_as::<_, GeofenceEvent>(
+        "SELECT * FROM geofence_events WHERE geofence_id = $1 ORDER BY occurred_at ASC"
+    )
+    .bind(geofence_id)
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
+
+async fn fetch_geofence(pool: &DbPool, geofence_id: Uuid) -> Result<Geofence, actix_web::Error> {
+    sqlx::query_as::<_, Geofence>(
+        "SELECT * FROM geofences WHERE id = $1"
+    )
+    .bind(geofence_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Geofence not found"))
+}
@@ -0,0 +1,150 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use crate::models::{CreateInvitationRequest, Invitation, InvitationResponse};
+use crate::auth::{hash_password, verify_password, generate_opaque_token, split_opaque_token, AdminOnly, Authorized};
+use crate::database::DbPool;
+use crate::validation::validate_invitation;
+
+const DEFAULT_INVITATION_HOURS: i64 = 72;
+
+// Only admins can invite users; the invitation pins the role the account will get
+#[post("/invitations")]
+pub async fn create_invitation(
+    pool: web::Data<DbPool>,
+    user: Authorized<AdminOnly>,
+    invitation_data: web::Json<CreateInvitationRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    validate_invitation(&invitation_data)?;
+    let expires_in_hours = invitation_data.expires_in_hours.unwrap_or(DEFAULT_INVITATION_HOURS);
+
+    let invitation_id = Uuid::new_v4();
+    let (token, secret) = generate_opaque_token(invitation_id);
+
+    let token_hash = hash_password(&secret)
+        .map_err(|e| {
+            eprintln!("Password hashing error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password hashing error")
+        })?;
+
+    let invitation = sqlx::query_as::<_, Invitation>(
+        r#"
+        INSERT INTO invitations (id, email, role, token_hash, created_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING *
+        "#
+    )
+    .bind(invitation_id)
+    .bind(&invitation_data.email)
+    .bind(invitation_data.role)
+    .bind(&token_hash)
+    .bind(user.id)
+    .bind(Utc::now() + Duration::hours(expires_in_hours))
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    // The token is only ever shown here; the database keeps its hash
+    Ok(HttpResponse::Created().json(InvitationResponse {
+        invitation,
+        token,
+    }))
+}
+
+#[get("/invitations")]
+pub async fn get_invitations(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOnly>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let invitations = sqlx::query_as::<_, Invitation>(
+        "SELECT * FROM invitations ORDER BY created_at DESC"
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(invitations))
+}
+
+#[delete("/invitations/{invitation_id}")]
+pub async fn revoke_invitation(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOnly>,
+    invitation_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let invitation = sqlx::query_as::<_, Invitation>(
+        r#"
+        UPDATE invitations SET revoked_at = NOW()
+        WHERE id = $1 AND accepted_at IS NULL AND revoked_at IS NULL
+        RETURNING *
+        "#
+    )
+    .bind(invitation_id.into_inner())
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match invitation {
+        Some(invitation) => Ok(HttpResponse::Ok().json(invitation)),
+        None => Err(actix_web::error::ErrorNotFound("No pending invitation with this id")),
+    }
+}
+
+// Look up an invitation token presented at registration and check it can still be used by `email`
+pub async fn find_valid_invitation(
+    pool: &DbPool,
+    token: &str,
+    email: &str,
+) -> Result<Invitation, actix_web::Error> {
+    let (invitation_id, secret) = split_opaque_token(token)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid invitation"))?;
+
+    let invitation = sqlx::query_as::<_, Invitation>(
+        "SELECT * FROM invitations WHERE id = $1"
+    )
+    .bind(invitation_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid invitation"))?;
+
+    if !verify_password(secret, &invitation.token_hash)
+        .map_err(|e| {
+            eprintln!("Password verification error: {}", e);
+            actix_web::error::ErrorInternalServerError("Password verification error")
+        })? {
+        return Err(actix_web::error::ErrorBadRequest("Invalid invitation"));
+    }
+
+    if invitation.accepted_at.is_some() {
+        return Err(actix_web::error::ErrorBadRequest("Invitation has already been used"));
+    }
+
+    if invitation.revoked_at.is_some() {
+        return Err(actix_web::error::ErrorBadRequest("Invitation has been revoked"));
+    }
+
+    if invitation.expires_at <= Utc::now() {
+        return Err(actix_web::error::ErrorBadRequest("Invitation has expired"));
+    }
+
+    if let Some(invited_email) = &invitation.email {
+        if !invited_email.eq_ignore_ascii_case(email) {
+            return Err(actix_web::error::ErrorBadRequest("Invitation was issued for a different email address"));
+        }
+    }
+
+    Ok(invitation)
+}
@@ -1,6 +1,14 @@
-pub mod auth;
-pub mod users;
-pub mod vehicles;
-pub mod cargo;
-pub mod routes;
-pub mod tracking;
+pub mod auth;
+pub mod users;
+pub mod invitations;
+pub mod vehicles;
+pub mod cargo;
+pub mod routes;
+pub mod stops;
+pub mod planning;
+pub mod tracking;
+pub mod geofences;
+pub mod alerts;
+pub mod deviations;
+pub mod etas;
+pub mod statistics;
@@ -0,0 +1,241 @@
+use actix_web::{post, web, HttpResponse};
+use std::collections::HashSet;
+use uuid::Uuid;
+use crate::models::{
+    Cargo, CargoStatus, CommitPlanRequest, CreateRouteRequest, CreateRouteStopRequest, PlanProposal,
+    PlanRequest, PlannedRoute, StopType, UnassignedCargo, Vehicle, VehicleStatus,
+};
+use crate::auth::{AdminManagerOrDispatcher, AdminOrManager, Authorized};
+use crate::database::DbPool;
+use crate::handlers::routes::insert_route;
+use crate::planner::{self, Delivery, Haversine, PlanVehicle, Point};
+use crate::events::publish_status_changes;
+
+// Propose routes for pending cargo without changing anything
+#[post("/planning/preview")]
+pub async fn preview_plan(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    plan_data: web::Json<PlanRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let plan_data = plan_data.into_inner();
+
+    if plan_data.deliveries.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("At least one delivery is required"));
+    }
+
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = plan_data.deliveries.iter().find(|delivery| !seen.insert(delivery.cargo_id)) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Cargo {} is listed more than once",
+            duplicate.cargo_id
+        )));
+    }
+
+    let cargo_ids: Vec<Uuid> = plan_data.deliveries.iter().map(|delivery| delivery.cargo_id).collect();
+    let cargo = sqlx::query_as::<_, Cargo>(
+        "SELECT * FROM cargo WHERE id = ANY($1)"
+    )
+    .bind(&cargo_ids)
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    // Keep the cargo in request order so it lines up with the deliveries
+    let mut items = Vec::with_capacity(cargo_ids.len());
+    for cargo_id in &cargo_ids {
+        let item = cargo
+            .iter()
+            .find(|item| item.id == *cargo_id)
+            .ok_or_else(|| actix_web::error::ErrorNotFound(format!("Cargo {} not found", cargo_id)))?;
+
+        if item.status != CargoStatus::Pending {
+            return Err(actix_web::error::ErrorConflict(format!("Cargo {} is not pending", cargo_id)));
+        }
+        items.push(item);
+    }
+
+    let vehicles = plannable_vehicles(pool.get_ref(), plan_data.vehicle_ids.as_deref()).await?;
+    if vehicles.is_empty() {
+        return Err(actix_web::error::ErrorConflict("No available vehicles with an assigned driver"));
+    }
+
+    let depot = Point {
+        latitude: plan_data.depot.latitude,
+        longitude: plan_data.depot.longitude,
+    };
+    let deliveries: Vec<Delivery> = plan_data
+        .deliveries
+        .iter()
+        .zip(&items)
+        .map(|(delivery, item)| Delivery {
+            cargo_id: item.id,
+            location: Point {
+                latitude: delivery.latitude,
+                longitude: delivery.longitude,
+            },
+            weight: item.weight,
+            volume: item.volume,
+            priority: item.priority,
+        })
+        .collect();
+    let plan_vehicles: Vec<PlanVehicle> = vehicles
+        .iter()
+        .map(|vehicle| PlanVehicle {
+            vehicle_id: vehicle.id,
+            capacity: vehicle.capacity,
+            volume_capacity: vehicle.volume_capacity,
+        })
+        .collect();
+
+    let plan = planner::plan(depot, &deliveries, &plan_vehicles, &Haversine);
+
+    let routes: Vec<PlannedRoute> = plan
+        .tours
+        .iter()
+        .map(|tour| {
+            let vehicle = vehicles
+                .iter()
+                .find(|vehicle| vehicle.id == tour.vehicle_id)
+                .expect("planned vehicle was an input");
+            let loaded: Vec<Uuid> = tour.deliveries.iter().map(|&index| deliveries[index].cargo_id).collect();
+
+            let mut stops = vec![CreateRouteStopRequest {
+                stop_type: StopType::Pickup,
+                address: plan_data.depot.address.clone(),
+                latitude: depot.latitude,
+                longitude: depot.longitude,
+                planned_arrival: None,
+                planned_departure: None,
+                load_cargo_ids: loaded,
+                unload_cargo_ids: Vec::new(),
+            }];
+            stops.extend(tour.deliveries.iter().map(|&index| {
+                let delivery = &plan_data.deliveries[index];
+                CreateRouteStopRequest {
+                    stop_type: StopType::Dropoff,
+                    address: delivery.address.clone(),
+                    latitude: delivery.latitude,
+                    longitude: delivery.longitude,
+                    planned_arrival: None,
+                    planned_departure: None,
+                    load_cargo_ids: Vec::new(),
+                    unload_cargo_ids: vec![delivery.cargo_id],
+                }
+            }));
+
+            PlannedRoute {
+                vehicle_id: vehicle.id,
+                driver_id: vehicle.driver_id.expect("plannable vehicles have a driver"),
+                stops,
+                estimated_distance: tour.distance,
+                total_weight: tour.load,
+                total_volume: tour.volume,
+            }
+        })
+        .collect();
+
+    let unassigned = plan
+        .unassigned
+        .iter()
+        .map(|entry| UnassignedCargo {
+            cargo_id: deliveries[entry.delivery].cargo_id,
+            reason: entry.reason.to_string(),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PlanProposal {
+        total_distance: routes.iter().map(|route| route.estimated_distance).sum(),
+        routes,
+        unassigned,
+    }))
+}
+
+// Create the routes of a (possibly edited) preview in one transaction; each is validated like a
+// route created by hand and nothing is created if any fails
+#[post("/planning/commit")]
+pub async fn commit_plan(
+    pool: web::Data<DbPool>,
+    user: Authorized<AdminOrManager>,
+    plan: web::Json<CommitPlanRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let plan = plan.into_inner();
+
+    if plan.routes.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("At least one route is required"));
+    }
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut created = Vec::with_capacity(plan.routes.len());
+    let mut changes = Vec::new();
+    for planned in plan.routes {
+        let route_data = CreateRouteRequest {
+            vehicle_id: planned.vehicle_id,
+            driver_id: planned.driver_id,
+            stops: Some(planned.stops),
+            source_address: None,
+            source_lat: None,
+            source_lng: None,
+            destination_address: None,
+            destination_lat: None,
+            destination_lng: None,
+            cargo_id: None,
+        };
+        created.push(insert_route(&mut tx, user.id, route_data, &mut changes).await?);
+    }
+
+    publish_status_changes(&mut tx, &changes).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Created().json(created))
+}
+
+// Vehicles that can take a planned route: available and with a driver to drive it
+async fn plannable_vehicles(
+    pool: &DbPool,
+    vehicle_ids: Option<&[Uuid]>,
+) -> Result<Vec<Vehicle>, actix_web::Error> {
+    let vehicles = match vehicle_ids {
+        Some(vehicle_ids) => sqlx::query_as::<_, Vehicle>(
+            "SELECT * FROM vehicles WHERE id = ANY($1) ORDER BY license_plate"
+        )
+        .bind(vehicle_ids)
+        .fetch_all(pool)
+        .await,
+        None => sqlx::query_as::<_, Vehicle>(
+            "SELECT * FROM vehicles WHERE status = 'available' AND driver_id IS NOT NULL ORDER BY license_plate"
+        )
+        .fetch_all(pool)
+        .await,
+    }
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if let Some(vehicle_ids) = vehicle_ids {
+        if let Some(missing) = vehicle_ids.iter().find(|id| !vehicles.iter().any(|vehicle| vehicle.id == **id)) {
+            return Err(actix_web::error::ErrorNotFound(format!("Vehicle {} not found", missing)));
+        }
+
+        if let Some(vehicle) = vehicles.iter().find(|vehicle| vehicle.status != VehicleStatus::Available || vehicle.driver_id.is_none()) {
+            return Err(actix_web::error::ErrorConflict(format!(
+                "Vehicle {} is not available or has no driver",
+                vehicle.license_plate
+            )));
+        }
+    }
+
+    Ok(vehicles)
+}
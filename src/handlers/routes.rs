@@ -1,110 +1,555 @@
-use actix_web::{web, HttpResponse, HttpRequest};
-use sqlx::PgPool;
-use crate::models::{CreateRouteRequest, Route};
-use crate::auth::{extract_token_from_header, validate_token};
-use crate::database::DbPool;
-
-pub async fn create_route(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-    route_data: web::Json<CreateRouteRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify admin or manager token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    // Only admins and managers can create routes
-    if claims.role != "Admin" && claims.role != "Manager" {
-        return Err(actix_web::error::ErrorForbidden("Only admins and managers can create routes"));
-    }
-
-    // Calculate estimated distance and duration (simplified calculation)
-    let distance = calculate_distance(
-        route_data.source_lat,
-        route_data.source_lng,
-        route_data.destination_lat,
-        route_data.destination_lng,
-    );
-    
-    let duration = (distance / 50.0 * 60.0) as i32; // Assuming 50 km/h average speed
-
-    let route = sqlx::query_as::<_, Route>(
-        r#"
-        INSERT INTO routes (
-            source_address, source_lat, source_lng,
-            destination_address, destination_lat, destination_lng,
-            estimated_distance, estimated_duration, vehicle_id, driver_id, cargo_id
-        )
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
-        RETURNING *
-        "#
-    )
-    .bind(&route_data.source_address)
-    .bind(&route_data.source_lat)
-    .bind(&route_data.source_lng)
-    .bind(&route_data.destination_address)
-    .bind(&route_data.destination_lat)
-    .bind(&route_data.destination_lng)
-    .bind(&distance)
-    .bind(&duration)
-    .bind(&route_data.vehicle_id)
-    .bind(&route_data.driver_id)
-    .bind(&route_data.cargo_id)
-    .fetch_one(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Created().json(route))
-}
-
-pub async fn get_routes(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let _claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    let routes = sqlx::query_as::<_, Route>(
-        "SELECT * FROM routes ORDER BY created_at DESC"
-    )
-    .fetch_all(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Ok().json(routes))
-}
-
-// Calculate distance between two points using Haversine formula
-fn calculate_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    let r = 6371.0; // Earth's radius in kilometers
-    let dlat = (lat2 - lat1).to_radians();
-    let dlon = (lon2 - lon1).to_radians();
-    let a = (dlat / 2.0).sin() * (dlat / 2.0).sin() +
-             lat1.to_radians().cos() * lat2.to_radians().cos() *
-             (dlon / 2.0).sin() * (dlon / 2.0).sin();
-    let c = 2.0 * a.sqrt().asin();
-    r * c
-}
+use actix_web::{get, post, web, HttpResponse};
+use uuid::Uuid;
+use crate::models::{CreateRouteRequest, CreateRouteStopRequest, Route, RouteDetails, RouteStatus, RouteActionRequest, CargoStatus, StopType, VehicleStatus, UserRole};
+use crate::auth::{AdminOrManager, AuthUser, Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::handlers::cargo::{transition_cargo, CargoEventDetails};
+use crate::handlers::stops::{fetch_route_stops, insert_route_stops, route_cargo};
+use crate::handlers::vehicles::transition_vehicle;
+use crate::validation::{validate_route, RouteDraft, ValidationErrors};
+use crate::events::{publish_status_changes, StatusChange};
+use crate::trip_stats::record_route_statistics;
+
+// Only admins and managers can create routes
+#[post("/routes")]
+pub async fn create_route(
+    pool: web::Data<DbPool>,
+    user: Authorized<AdminOrManager>,
+    route_data: web::Json<CreateRouteRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut changes = Vec::new();
+    let route = insert_route(&mut tx, user.id, route_data.into_inner(), &mut changes).await?;
+
+    publish_status_changes(&mut tx, &changes).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Created().json(route))
+}
+
+// Drivers only see their own routes
+#[get("/routes")]
+pub async fn get_routes(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let routes = sqlx::query_as::<_, Route>(
+        "SELECT * FROM routes WHERE $1::uuid IS NULL OR driver_id = $1 ORDER BY created_at DESC"
+    )
+    .bind(driver_scope(&user))
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(routes))
+}
+
+#[get("/routes/{route_id}")]
+pub async fn get_route(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut conn = pool.acquire().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = sqlx::query_as::<_, Route>(
+        "SELECT * FROM routes WHERE id = $1"
+    )
+    .bind(route_id.into_inner())
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Route not found"))?;
+
+    if driver_scope(&user).is_some_and(|driver_id| driver_id != route.driver_id) {
+        return Err(actix_web::error::ErrorForbidden("Drivers can only view their own routes"));
+    }
+
+    let stops = fetch_route_stops(&mut conn, route.id).await?;
+
+    Ok(HttpResponse::Ok().json(RouteDetails { route, stops }))
+}
+
+// Starting a route puts its vehicle in use and its cargo in transit; drivers may start their own routes
+#[post("/routes/{route_id}/start")]
+pub async fn start_route(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    route_id: web::Path<Uuid>,
+    action: Option<web::Json<RouteActionRequest>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = lock_route(&mut tx, route_id.into_inner()).await?;
+    ensure_route_operator(&user, &route, true)?;
+    ensure_route_transition(&route, RouteStatus::InProgress)?;
+
+    // Serialize starts for the same driver, then check they are not busy elsewhere
+    sqlx::query("SELECT 1 FROM users WHERE id = $1 FOR UPDATE")
+        .bind(route.driver_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    let vehicle_driver = sqlx::query_scalar::<_, Option<Uuid>>(
+        "SELECT driver_id FROM vehicles WHERE id = $1 FOR UPDATE"
+    )
+    .bind(route.vehicle_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Vehicle not found"))?;
+
+    let busy = sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM routes
+            WHERE status = 'inprogress' AND id <> $1 AND (vehicle_id = $2 OR driver_id = $3)
+        )
+        "#
+    )
+    .bind(route.id)
+    .bind(route.vehicle_id)
+    .bind(route.driver_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if busy {
+        return Err(actix_web::error::ErrorConflict("Vehicle or driver is already on a route in progress"));
+    }
+
+    // The route's driver takes the vehicle if nobody is assigned to it
+    match vehicle_driver {
+        Some(driver_id) if driver_id != route.driver_id => {
+            return Err(actix_web::error::ErrorConflict("Vehicle is assigned to a different driver"));
+        }
+        Some(_) => {}
+        None => {
+            let other_vehicle = sqlx::query_scalar::<_, String>(
+                "SELECT license_plate FROM vehicles WHERE driver_id = $1 AND id <> $2"
+            )
+            .bind(route.driver_id)
+            .bind(route.vehicle_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                eprintln!("Database error: {}", e);
+                actix_web::error::ErrorInternalServerError("Database error")
+            })?;
+
+            if let Some(license_plate) = other_vehicle {
+                return Err(actix_web::error::ErrorConflict(format!(
+                    "Driver is already assigned to vehicle {}",
+                    license_plate
+                )));
+            }
+
+            sqlx::query("UPDATE vehicles SET driver_id = $2, updated_at = NOW() WHERE id = $1")
+                .bind(route.vehicle_id)
+                .bind(route.driver_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| {
+                    if e.as_database_error().is_some_and(|e| e.is_unique_violation()) {
+                        return actix_web::error::ErrorConflict("Driver is already assigned to another vehicle");
+                    }
+                    eprintln!("Database error: {}", e);
+                    actix_web::error::ErrorInternalServerError("Database error")
+                })?;
+        }
+    }
+
+    let vehicle = transition_vehicle(&mut tx, route.vehicle_id, VehicleStatus::InUse).await?;
+    let mut changes = vec![StatusChange::vehicle(&vehicle)];
+
+    // Cargo cancelled since the route was planned is left behind
+    let details = route_event_details(&user, &route, action, "Route started");
+    for (cargo_id, status) in route_cargo(&mut tx, route.id).await? {
+        if status.can_transition_to(CargoStatus::InTransit) {
+            let cargo = transition_cargo(&mut tx, cargo_id, CargoStatus::InTransit, &details).await?;
+            changes.push(StatusChange::cargo(&cargo, Some(route.id)));
+        }
+    }
+
+    let route = update_route_status(&mut tx, route.id, RouteStatus::InProgress).await?;
+    changes.push(StatusChange::route(&route));
+
+    publish_status_changes(&mut tx, &changes).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(route))
+}
+
+// Completing a route releases its vehicle and delivers its cargo; drivers may complete their own routes
+#[post("/routes/{route_id}/complete")]
+pub async fn complete_route(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    route_id: web::Path<Uuid>,
+    action: Option<web::Json<RouteActionRequest>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = lock_route(&mut tx, route_id.into_inner()).await?;
+    ensure_route_operator(&user, &route, true)?;
+
+    let details = route_event_details(&user, &route, action, "Route completed");
+    let mut changes = Vec::new();
+    let route = finish_route(&mut tx, &route, &details, &mut changes).await?;
+
+    publish_status_changes(&mut tx, &changes).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(route))
+}
+
+// Complete a locked route: release its vehicle and deliver its cargo, inside the caller's
+// transaction. Used by the complete endpoint and by stop detection; the status changes made are
+// added to `changes` for the caller to publish.
+pub async fn finish_route(
+    conn: &mut sqlx::PgConnection,
+    route: &Route,
+    details: &CargoEventDetails,
+    changes: &mut Vec<StatusChange>,
+) -> Result<Route, actix_web::Error> {
+    ensure_route_transition(route, RouteStatus::Completed)?;
+
+    let vehicle = transition_vehicle(&mut *conn, route.vehicle_id, VehicleStatus::Available).await?;
+    changes.push(StatusChange::vehicle(&vehicle));
+
+    // Cargo already unloaded at a dropoff stop is delivered; the rest is delivered now
+    for (cargo_id, status) in route_cargo(&mut *conn, route.id).await? {
+        if status == CargoStatus::InTransit {
+            let cargo = transition_cargo(&mut *conn, cargo_id, CargoStatus::Delivered, details).await?;
+            changes.push(StatusChange::cargo(&cargo, Some(route.id)));
+        }
+    }
+
+    let route = update_route_status(&mut *conn, route.id, RouteStatus::Completed).await?;
+    changes.push(StatusChange::route(&route));
+
+    record_route_statistics(conn, &route).await?;
+
+    Ok(route)
+}
+
+// Cancelling a route returns its cargo to Pending and, if it was running, releases its vehicle
+#[post("/routes/{route_id}/cancel")]
+pub async fn cancel_route(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    route_id: web::Path<Uuid>,
+    action: Option<web::Json<RouteActionRequest>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = lock_route(&mut tx, route_id.into_inner()).await?;
+    ensure_route_operator(&user, &route, false)?;
+    ensure_route_transition(&route, RouteStatus::Cancelled)?;
+
+    let mut changes = Vec::new();
+    if route.status == RouteStatus::InProgress {
+        let vehicle = transition_vehicle(&mut tx, route.vehicle_id, VehicleStatus::Available).await?;
+        changes.push(StatusChange::vehicle(&vehicle));
+    }
+
+    // Delivered cargo stays delivered, and cargo cancelled on its own in the meantime stays cancelled
+    let details = route_event_details(&user, &route, action, "Route cancelled");
+    for (cargo_id, status) in route_cargo(&mut tx, route.id).await? {
+        if status.can_transition_to(CargoStatus::Pending) {
+            let cargo = transition_cargo(&mut tx, cargo_id, CargoStatus::Pending, &details).await?;
+            changes.push(StatusChange::cargo(&cargo, Some(route.id)));
+        }
+    }
+
+    let route = update_route_status(&mut tx, route.id, RouteStatus::Cancelled).await?;
+    changes.push(StatusChange::route(&route));
+
+    publish_status_changes(&mut tx, &changes).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(route))
+}
+
+// Create a route with its stops and assign its cargo, inside the caller's transaction. The status
+// changes made are added to `changes` for the caller to publish in the same transaction.
+pub async fn insert_route(
+    conn: &mut sqlx::PgConnection,
+    actor_id: Uuid,
+    mut route_data: CreateRouteRequest,
+    changes: &mut Vec<StatusChange>,
+) -> Result<RouteDetails, actix_web::Error> {
+    let (stops, single_cargo_id) = match route_data.stops.take() {
+        Some(stops) => (stops, None),
+        None => single_leg_stops(&route_data)?,
+    };
+
+    let draft = RouteDraft {
+        vehicle_id: route_data.vehicle_id,
+        driver_id: route_data.driver_id,
+        stops: &stops,
+        single_cargo_id,
+    };
+    let cargo_ids = validate_route(&mut *conn, &draft).await?;
+
+    let source = stops.first().expect("validated route has stops");
+    let destination = stops.last().expect("validated route has stops");
+
+    // Calculate estimated distance and duration over every leg (simplified calculation)
+    let points: Vec<(f64, f64)> = stops.iter().map(|stop| (stop.latitude, stop.longitude)).collect();
+    let distance = calculate_distance(&points);
+    
+    let duration = (distance / 50.0 * 60.0) as i32; // Assuming 50 km/h average speed
+
+    let route = sqlx::query_as::<_, Route>(
+        r#"
+        INSERT INTO routes (
+            source_address, source_lat, source_lng,
+            destination_address, destination_lat, destination_lng,
+            estimated_distance, estimated_duration, vehicle_id, driver_id, cargo_id
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        RETURNING *
+        "#
+    )
+    .bind(&source.address)
+    .bind(source.latitude)
+    .bind(source.longitude)
+    .bind(&destination.address)
+    .bind(destination.latitude)
+    .bind(destination.longitude)
+    .bind(distance)
+    .bind(duration)
+    .bind(route_data.vehicle_id)
+    .bind(route_data.driver_id)
+    .bind(single_cargo_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    insert_route_stops(&mut *conn, route.id, &stops).await?;
+
+    // Planning a route assigns all of its cargo
+    let details = CargoEventDetails {
+        actor_id: Some(actor_id),
+        route_id: Some(route.id),
+        note: Some("Assigned to route".to_string()),
+        ..Default::default()
+    };
+    for cargo_id in cargo_ids {
+        let cargo = transition_cargo(&mut *conn, cargo_id, CargoStatus::Assigned, &details).await?;
+        changes.push(StatusChange::cargo(&cargo, Some(route.id)));
+    }
+    changes.push(StatusChange::route(&route));
+
+    let stops = fetch_route_stops(conn, route.id).await?;
+
+    Ok(RouteDetails { route, stops })
+}
+
+pub async fn lock_route(conn: &mut sqlx::PgConnection, route_id: Uuid) -> Result<Route, actix_web::Error> {
+    sqlx::query_as::<_, Route>(
+        "SELECT * FROM routes WHERE id = $1 FOR UPDATE"
+    )
+    .bind(route_id)
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Route not found"))
+}
+
+// Admins, managers and dispatchers can operate any route; drivers only their own, where allowed
+pub fn ensure_route_operator(user: &AuthUser, route: &Route, drivers_allowed: bool) -> Result<(), actix_web::Error> {
+    match user.role {
+        UserRole::Admin | UserRole::Manager | UserRole::Dispatcher => Ok(()),
+        UserRole::Driver if drivers_allowed && route.driver_id == user.id => Ok(()),
+        UserRole::Driver if drivers_allowed => Err(actix_web::error::ErrorForbidden("Drivers can only operate their own routes")),
+        UserRole::Driver => Err(actix_web::error::ErrorForbidden("Only admins, managers and dispatchers can perform this action")),
+        UserRole::Customer => Err(actix_web::error::ErrorForbidden("Customers cannot operate routes")),
+    }
+}
+
+// The driver whose routes a user is limited to reading, if any
+pub fn driver_scope(user: &AuthUser) -> Option<Uuid> {
+    match user.role {
+        UserRole::Driver => Some(user.id),
+        _ => None,
+    }
+}
+
+// The pre-stops request shape: a pickup at the source and a dropoff at the destination for one cargo item
+fn single_leg_stops(
+    route_data: &CreateRouteRequest,
+) -> Result<(Vec<CreateRouteStopRequest>, Option<Uuid>), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+    let required = [
+        ("source_address", route_data.source_address.is_some()),
+        ("source_lat", route_data.source_lat.is_some()),
+        ("source_lng", route_data.source_lng.is_some()),
+        ("destination_address", route_data.destination_address.is_some()),
+        ("destination_lat", route_data.destination_lat.is_some()),
+        ("destination_lng", route_data.destination_lng.is_some()),
+        ("cargo_id", route_data.cargo_id.is_some()),
+    ];
+    for (field, present) in required {
+        if !present {
+            errors.add(field, "Required unless stops are given");
+        }
+    }
+    errors.into_result()?;
+
+    let cargo_id = route_data.cargo_id.unwrap_or_default();
+
+    let source = CreateRouteStopRequest {
+        stop_type: StopType::Pickup,
+        address: route_data.source_address.clone().unwrap_or_default(),
+        latitude: route_data.source_lat.unwrap_or_default(),
+        longitude: route_data.source_lng.unwrap_or_default(),
+        planned_arrival: None,
+        planned_departure: None,
+        load_cargo_ids: vec![cargo_id],
+        unload_cargo_ids: Vec::new(),
+    };
+
+    let destination = CreateRouteStopRequest {
+        stop_type: StopType::Dropoff,
+        address: route_data.destination_address.clone().unwrap_or_default(),
+        latitude: route_data.destination_lat.unwrap_or_default(),
+        longitude: route_data.destination_lng.unwrap_or_default(),
+        planned_arrival: None,
+        planned_departure: None,
+        load_cargo_ids: Vec::new(),
+        unload_cargo_ids: vec![cargo_id],
+    };
+
+    Ok((vec![source, destination], Some(cargo_id)))
+}
+
+fn ensure_route_transition(route: &Route, next: RouteStatus) -> Result<(), actix_web::Error> {
+    if route.status.can_transition_to(next) {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorConflict(format!(
+            "Cannot change route status from {:?} to {:?}",
+            route.status, next
+        )))
+    }
+}
+
+fn route_event_details(
+    user: &AuthUser,
+    route: &Route,
+    action: Option<web::Json<RouteActionRequest>>,
+    default_note: &str,
+) -> CargoEventDetails {
+    let note = action
+        .and_then(|action| action.into_inner().note)
+        .unwrap_or_else(|| default_note.to_string());
+
+    CargoEventDetails {
+        actor_id: Some(user.id),
+        route_id: Some(route.id),
+        note: Some(note),
+        ..Default::default()
+    }
+}
+
+async fn update_route_status(
+    conn: &mut sqlx::PgConnection,
+    route_id: Uuid,
+    next: RouteStatus,
+) -> Result<Route, actix_web::Error> {
+    sqlx::query_as::<_, Route>(
+        r#"
+        UPDATE routes
+        SET status = $2,
+            started_at = CASE WHEN $2 = 'inprogress'::route_status THEN NOW() ELSE started_at END,
+            completed_at = CASE WHEN $2 IN ('completed'::route_status, 'cancelled'::route_status) THEN NOW() ELSE completed_at END,
+            updated_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(route_id)
+    .bind(next)
+    .fetch_one(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
+
+// Calculate distance along a sequence of (lat, lng) points by summing its legs
+pub fn calculate_distance(points: &[(f64, f64)]) -> f64 {
+    points
+        .windows(2)
+        .map(|leg| haversine_distance(leg[0].0, leg[0].1, leg[1].0, leg[1].1))
+        .sum()
+}
+
+// Calculate distance between two points using Haversine formula
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let r = 6371.0; // Earth's radius in kilometers
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin() * (dlat / 2.0).sin() +
+             lat1.to_radians().cos() * lat2.to_radians().cos() *
+             (dlon / 2.0).sin() * (dlon / 2.0).sin();
+    let c = 2.0 * a.sqrt().asin();
+    r * c
+}
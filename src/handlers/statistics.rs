@@ -0,0 +1,69 @@
+use actix_web::{get, web, HttpResponse};
+use chrono::Utc;
+use uuid::Uuid;
+use crate::models::{RouteStatistics, VehicleStatisticsQuery};
+use crate::auth::{Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::trip_stats::vehicle_day_statistics;
+use crate::validation::validate_statistics_range;
+
+// Recorded when the route is completed
+#[get("/routes/{route_id}/statistics")]
+pub async fn get_route_statistics(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let statistics = sqlx::query_as::<_, RouteStatistics>(
+        "SELECT * FROM route_statistics WHERE route_id = $1"
+    )
+    .bind(route_id.into_inner())
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("No statistics for this route; it has not been completed"))?;
+
+    Ok(HttpResponse::Ok().json(statistics))
+}
+
+// Computed from the vehicle's locations on each request, so days still under way are included
+#[get("/vehicles/{vehicle_id}/statistics")]
+pub async fn get_vehicle_statistics(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    vehicle_id: web::Path<Uuid>,
+    query: web::Query<VehicleStatisticsQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let today = Utc::now().date_naive();
+    let from = query.from.unwrap_or(today);
+    let to = query.to.unwrap_or(today);
+    validate_statistics_range(from, to)?;
+
+    let mut conn = pool.acquire().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let vehicle_id = vehicle_id.into_inner();
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM vehicles WHERE id = $1)"
+    )
+    .bind(vehicle_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if !exists {
+        return Err(actix_web::error::ErrorNotFound("Vehicle not found"));
+    }
+
+    let days = vehicle_day_statistics(&mut conn, vehicle_id, from, to).await?;
+
+    Ok(HttpResponse::Ok().json(days))
+}
@@ -0,0 +1,344 @@
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::models::{CargoStatus, CreateRouteStopRequest, DetectedStop, RouteStatus, RouteStop, RouteStopCargo, RouteStopDetails, StopCargoAction, StopType};
+use crate::auth::{AuthUser, Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::handlers::cargo::{transition_cargo, CargoEventDetails};
+use crate::handlers::routes::{ensure_route_operator, lock_route};
+use crate::events::{publish_status_changes, StatusChange};
+
+#[get("/routes/{route_id}/stops")]
+pub async fn get_route_stops(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut conn = pool.acquire().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let stops = fetch_route_stops(&mut conn, route_id.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(stops))
+}
+
+// Stops detected from the vehicle's locations, planned or not, in the order they happened
+#[get("/routes/{route_id}/detected-stops")]
+pub async fn get_detected_stops(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let stops = sqlx::query_as::<_, DetectedStop>(
+        "SELECT * FROM detected_stops WHERE route_id = $1 ORDER BY arrived_at"
+    )
+    .bind(route_id.into_inner())
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(stops))
+}
+
+#[post("/routes/{route_id}/stops/{stop_id}/arrive")]
+pub async fn arrive_at_stop(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (route_id, stop_id) = path.into_inner();
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = lock_route(&mut tx, route_id).await?;
+    ensure_route_operator(&user, &route, true)?;
+
+    if route.status != RouteStatus::InProgress {
+        return Err(actix_web::error::ErrorConflict("Route is not in progress"));
+    }
+
+    let stop = lock_stop(&mut tx, route_id, stop_id).await?;
+    if stop.actual_arrival.is_some() {
+        return Err(actix_web::error::ErrorConflict("Arrival at this stop has already been recorded"));
+    }
+
+    let stop = record_arrival(&mut tx, stop.id, Utc::now()).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(stop))
+}
+
+// Leaving a dropoff stop delivers the cargo unloaded there
+#[post("/routes/{route_id}/stops/{stop_id}/depart")]
+pub async fn depart_from_stop(
+    pool: web::Data<DbPool>,
+    user: AuthUser,
+    path: web::Path<(Uuid, Uuid)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (route_id, stop_id) = path.into_inner();
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = lock_route(&mut tx, route_id).await?;
+    ensure_route_operator(&user, &route, true)?;
+
+    if route.status != RouteStatus::InProgress {
+        return Err(actix_web::error::ErrorConflict("Route is not in progress"));
+    }
+
+    let stop = lock_stop(&mut tx, route_id, stop_id).await?;
+    if stop.actual_arrival.is_none() {
+        return Err(actix_web::error::ErrorConflict("Arrival at this stop has not been recorded"));
+    }
+    if stop.actual_departure.is_some() {
+        return Err(actix_web::error::ErrorConflict("Departure from this stop has already been recorded"));
+    }
+
+    let mut changes = Vec::new();
+    let stop = record_departure(&mut tx, &stop, Utc::now(), Some(user.id), &mut changes).await?;
+
+    publish_status_changes(&mut tx, &changes).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(stop))
+}
+
+pub async fn record_arrival(
+    conn: &mut sqlx::PgConnection,
+    stop_id: Uuid,
+    arrived_at: DateTime<Utc>,
+) -> Result<RouteStop, actix_web::Error> {
+    sqlx::query_as::<_, RouteStop>(
+        "UPDATE route_stops SET actual_arrival = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(stop_id)
+    .bind(arrived_at)
+    .fetch_one(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
+
+// Record leaving a stop and deliver the cargo unloaded there if it is a dropoff, inside the
+// caller's transaction. `actor_id` is None when the departure was detected from locations.
+pub async fn record_departure(
+    conn: &mut sqlx::PgConnection,
+    stop: &RouteStop,
+    departed_at: DateTime<Utc>,
+    actor_id: Option<Uuid>,
+    changes: &mut Vec<StatusChange>,
+) -> Result<RouteStop, actix_web::Error> {
+    let stop = sqlx::query_as::<_, RouteStop>(
+        "UPDATE route_stops SET actual_departure = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(stop.id)
+    .bind(departed_at)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if stop.stop_type == StopType::Dropoff {
+        let unloaded = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            SELECT c.id FROM cargo c
+            JOIN route_stop_cargo sc ON sc.cargo_id = c.id
+            WHERE sc.stop_id = $1 AND sc.action = 'unload' AND c.status = 'intransit'
+            ORDER BY c.id
+            "#
+        )
+        .bind(stop.id)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        let details = CargoEventDetails {
+            actor_id,
+            route_id: Some(stop.route_id),
+            latitude: Some(stop.latitude),
+            longitude: Some(stop.longitude),
+            note: Some(format!("Unloaded at stop {}", stop.sequence + 1)),
+        };
+        for cargo_id in unloaded {
+            let cargo = transition_cargo(&mut *conn, cargo_id, CargoStatus::Delivered, &details).await?;
+            changes.push(StatusChange::cargo(&cargo, Some(stop.route_id)));
+        }
+    }
+
+    Ok(stop)
+}
+
+pub async fn insert_route_stops(
+    conn: &mut sqlx::PgConnection,
+    route_id: Uuid,
+    stops: &[CreateRouteStopRequest],
+) -> Result<(), actix_web::Error> {
+    for (sequence, stop) in stops.iter().enumerate() {
+        let stop_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            INSERT INTO route_stops (
+                route_id, sequence, stop_type, address, latitude, longitude,
+                planned_arrival, planned_departure
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id
+            "#
+        )
+        .bind(route_id)
+        .bind(sequence as i32)
+        .bind(stop.stop_type)
+        .bind(&stop.address)
+        .bind(stop.latitude)
+        .bind(stop.longitude)
+        .bind(stop.planned_arrival)
+        .bind(stop.planned_departure)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+        let cargo_actions = stop.load_cargo_ids.iter().map(|id| (id, StopCargoAction::Load))
+            .chain(stop.unload_cargo_ids.iter().map(|id| (id, StopCargoAction::Unload)));
+
+        for (cargo_id, action) in cargo_actions {
+            sqlx::query(
+                "INSERT INTO route_stop_cargo (stop_id, cargo_id, action) VALUES ($1, $2, $3)"
+            )
+            .bind(stop_id)
+            .bind(cargo_id)
+            .bind(action)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| {
+                eprintln!("Database error: {}", e);
+                actix_web::error::ErrorInternalServerError("Database error")
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn fetch_route_stops(
+    conn: &mut sqlx::PgConnection,
+    route_id: Uuid,
+) -> Result<Vec<RouteStopDetails>, actix_web::Error> {
+    let stops = sqlx::query_as::<_, RouteStop>(
+        "SELECT * FROM route_stops WHERE route_id = $1 ORDER BY sequence"
+    )
+    .bind(route_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let stop_cargo = sqlx::query_as::<_, RouteStopCargo>(
+        r#"
+        SELECT sc.* FROM route_stop_cargo sc
+        JOIN route_stops s ON s.id = sc.stop_id
+        WHERE s.route_id = $1
+        ORDER BY sc.cargo_id
+        "#
+    )
+    .bind(route_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut cargo_by_stop: HashMap<Uuid, (Vec<Uuid>, Vec<Uuid>)> = HashMap::new();
+    for entry in stop_cargo {
+        let (load, unload) = cargo_by_stop.entry(entry.stop_id).or_default();
+        match entry.action {
+            StopCargoAction::Load => load.push(entry.cargo_id),
+            StopCargoAction::Unload => unload.push(entry.cargo_id),
+        }
+    }
+
+    Ok(stops
+        .into_iter()
+        .map(|stop| {
+            let (load_cargo_ids, unload_cargo_ids) = cargo_by_stop.remove(&stop.id).unwrap_or_default();
+            RouteStopDetails {
+                stop,
+                load_cargo_ids,
+                unload_cargo_ids,
+            }
+        })
+        .collect())
+}
+
+// Every cargo item loaded or unloaded anywhere on the route, with its current status
+pub async fn route_cargo(
+    conn: &mut sqlx::PgConnection,
+    route_id: Uuid,
+) -> Result<Vec<(Uuid, CargoStatus)>, actix_web::Error> {
+    sqlx::query_as::<_, (Uuid, CargoStatus)>(
+        r#"
+        SELECT DISTINCT c.id, c.status FROM cargo c
+        JOIN route_stop_cargo sc ON sc.cargo_id = c.id
+        JOIN route_stops s ON s.id = sc.stop_id
+        WHERE s.route_id = $1
+        ORDER BY c.id
+        "#
+    )
+    .bind(route_id)
+    .fetch_all(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
+
+async fn lock_stop(
+    conn: &mut sqlx::PgConnection,
+    route_id: Uuid,
+    stop_id: Uuid,
+) -> Result<RouteStop, actix_web::Error> {
+    sqlx::query_as::<_, RouteStop>(
+        "SELECT * FROM route_stops WHERE id = $1 AND route_id = $2 FOR UPDATE"
+    )
+    .bind(stop_id)
+    .bind(route_id)
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Stop not found"))
+}
@@ -1,130 +1,401 @@
-use actix_web::{web, HttpResponse, HttpRequest};
-use sqlx::PgPool;
-use crate::models::{UpdateLocationRequest, Location};
-use crate::auth::{extract_token_from_header, validate_token};
-use crate::database::DbPool;
-
-pub async fn update_location(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-    location_data: web::Json<UpdateLocationRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    // Only drivers can update their own location
-    if claims.role != "Driver" {
-        return Err(actix_web::error::ErrorForbidden("Only drivers can update location"));
-    }
-
-    // Verify the driver is updating their own location
-    if claims.sub != location_data.driver_id.to_string() {
-        return Err(actix_web::error::ErrorForbidden("Can only update your own location"));
-    }
-
-    let location = sqlx::query_as::<_, Location>(
-        r#"
-        INSERT INTO locations (route_id, vehicle_id, driver_id, latitude, longitude, speed, heading)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
-        RETURNING *
-        "#
-    )
-    .bind(&location_data.route_id)
-    .bind(&location_data.vehicle_id)
-    .bind(&location_data.driver_id)
-    .bind(&location_data.latitude)
-    .bind(&location_data.longitude)
-    .bind(&location_data.speed)
-    .bind(&location_data.heading)
-    .fetch_one(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Created().json(location))
-}
-
-pub async fn get_location(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-    route_id: web::Path<uuid::Uuid>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let _claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    let location = sqlx::query_as::<_, Location>(
-        r#"
-        SELECT * FROM locations 
-        WHERE route_id = $1 
-        ORDER BY timestamp DESC 
-        LIMIT 1
-        "#
-    )
-    .bind(&route_id.into_inner())
-    .fetch_optional(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    match location {
-        Some(loc) => Ok(HttpResponse::Ok().json(loc)),
-        None => Err(actix_web::error::ErrorNotFound("Location not found for this route")),
-    }
-}
-
-pub async fn get_route_tracking_history(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-    route_id: web::Path<uuid::Uuid>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let _claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    let locations = sqlx::query_as::<_, Location>(
-        r#"
-        SELECT * FROM locations 
-        WHERE route_id = $1 
-        ORDER BY timestamp ASC
-        "#
-    )
-    .bind(&route_id.into_inner())
-    .fetch_all(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Ok().json(locations))
-}
+use actix::Addr;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures::Stream;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::models::{HistoryCursor, TrackExportQuery, TrackingHistoryQuery, TrackingStreamQuery, UpdateLocationRequest, Location};
+use crate::auth::{AuthUser, Authorized, DriverOnly, StaffOrDriver};
+use crate::config::Config;
+use crate::database::DbPool;
+use crate::export::{export_track, TrackFilter};
+use crate::handlers::routes::driver_scope;
+use crate::simplify::simplify_track;
+use crate::validation::validate_history_query;
+use crate::ingest::{commit_locations, process_locations, LocationRules, NOT_DRIVING_ROUTE};
+use crate::websocket::{authorize_topic, Connect, Disconnect, ErrorCode, Frame, SessionSink, Subscribe, Topic, TrackingHub};
+
+// Only drivers can update their own location
+#[post("/tracking/location")]
+pub async fn update_location(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    user: Authorized<DriverOnly>,
+    location_data: web::Json<UpdateLocationRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Verify the driver is updating their own location
+    if user.id != location_data.driver_id {
+        return Err(actix_web::error::ErrorForbidden("Can only update your own location"));
+    }
+
+    let fix = (location_data.into_inner(), Utc::now());
+    let location = commit_locations(&pool, &[Uuid::new_v4()], std::slice::from_ref(&fix))
+        .await?
+        .pop()
+        .ok_or_else(|| actix_web::error::ErrorForbidden(NOT_DRIVING_ROUTE))?;
+
+    process_locations(&pool, std::slice::from_ref(&location), &LocationRules::new(&config)).await;
+
+    Ok(HttpResponse::Created().json(location))
+}
+
+#[get("/tracking/location/{route_id}")]
+pub async fn get_location(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+    route_id: web::Path<uuid::Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let route_id = route_id.into_inner();
+    ensure_route_tracking_reader(pool.get_ref(), &user, route_id).await?;
+
+    let location = sqlx::query_as::<_, Location>(
+        r#"
+        SELECT * FROM locations 
+        WHERE route_id = $1 
+        ORDER BY timestamp DESC 
+        LIMIT 1
+        "#
+    )
+    .bind(route_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match location {
+        Some(loc) => Ok(HttpResponse::Ok().json(loc)),
+        None => Err(actix_web::error::ErrorNotFound("Location not found for this route")),
+    }
+}
+
+// The whole history by default. With `limit`, a full page carries an `X-Next-Cursor` header to pass
+// as `after` for the next one; bucketing and simplification apply within each page, so every page
+// keeps its first and last location and a simplified track depends on `limit`.
+#[get("/tracking/history/{route_id}")]
+pub async fn get_route_tracking_history(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+    route_id: web::Path<uuid::Uuid>,
+    query: web::Query<TrackingHistoryQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    validate_history_query(&query)?;
+    let route_id = route_id.into_inner();
+    ensure_route_tracking_reader(pool.get_ref(), &user, route_id).await?;
+
+    // The latest location of each bucket is kept, so a cursor never falls inside a bucket
+    let sql = match query.bucket_secs {
+        None => {
+            r#"
+            SELECT * FROM locations
+            WHERE route_id = $1 AND ($2::timestamptz IS NULL OR (timestamp, id) > ($2, $3))
+            ORDER BY timestamp ASC, id ASC
+            LIMIT $4
+            "#
+        }
+        Some(_) => {
+            r#"
+            SELECT * FROM (
+                SELECT DISTINCT ON (floor(extract(epoch FROM timestamp)::float8 / $5)) * FROM locations
+                WHERE route_id = $1 AND ($2::timestamptz IS NULL OR (timestamp, id) > ($2, $3))
+                ORDER BY floor(extract(epoch FROM timestamp)::float8 / $5), timestamp DESC, id DESC
+            ) buckets
+            ORDER BY timestamp ASC, id ASC
+            LIMIT $4
+            "#
+        }
+    };
+
+    let mut history = sqlx::query_as::<_, Location>(sql)
+        .bind(route_id)
+        .bind(query.after.map(|cursor| cursor.timestamp))
+        .bind(query.after.map(|cursor| cursor.id))
+        .bind(query.limit);
+    if let Some(bucket) = query.bucket_secs {
+        history = history.bind(bucket as f64);
+    }
+
+    let locations = history
+        .fetch_all(pool.get_ref())
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    let next_cursor = match (query.limit, locations.last()) {
+        (Some(limit), Some(last)) if locations.len() as i64 == limit => Some(HistoryCursor::after(last)),
+        _ => None,
+    };
+
+    let locations = match query.tolerance_meters {
+        Some(tolerance) => simplify_track(locations, tolerance),
+        None => locations,
+    };
+
+    let mut response = HttpResponse::Ok();
+    if let Some(cursor) = next_cursor {
+        response.insert_header(("X-Next-Cursor", cursor.to_string()));
+    }
+
+    Ok(response.json(locations))
+}
+
+// A route's location history as a GPX, KML or GeoJSON file, optionally limited to `from`..`to`
+#[get("/tracking/export/route/{route_id}")]
+pub async fn export_route_track(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+    route_id: web::Path<Uuid>,
+    query: web::Query<TrackExportQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let route_id = route_id.into_inner();
+
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM routes WHERE id = $1)"
+    )
+    .bind(route_id)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if !exists {
+        return Err(actix_web::error::ErrorNotFound("Route not found"));
+    }
+    ensure_route_tracking_reader(pool.get_ref(), &user, route_id).await?;
+
+    let filter = TrackFilter {
+        route_id: Some(route_id),
+        vehicle_id: None,
+        driver_id: None,
+        from: query.from,
+        to: query.to,
+    };
+
+    track_export_response(pool.get_ref(), filter, &query, format!("route-{}", route_id))
+}
+
+// A vehicle's location history across its routes, one track segment per route. Drivers get only
+// their own routes on a vehicle they have driven.
+#[get("/tracking/export/vehicle/{vehicle_id}")]
+pub async fn export_vehicle_track(
+    pool: web::Data<DbPool>,
+    user: Authorized<StaffOrDriver>,
+    vehicle_id: web::Path<Uuid>,
+    query: web::Query<TrackExportQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+
+    let exists = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM vehicles WHERE id = $1)"
+    )
+    .bind(vehicle_id)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if !exists {
+        return Err(actix_web::error::ErrorNotFound("Vehicle not found"));
+    }
+    ensure_vehicle_tracking_reader(pool.get_ref(), &user, vehicle_id).await?;
+
+    let filter = TrackFilter {
+        route_id: None,
+        vehicle_id: Some(vehicle_id),
+        driver_id: driver_scope(&user),
+        from: query.from,
+        to: query.to,
+    };
+
+    track_export_response(pool.get_ref(), filter, &query, format!("vehicle-{}", vehicle_id))
+}
+
+// Drivers may only read the tracking of their own routes, as over `/ws`; staff any route's
+async fn ensure_route_tracking_reader(pool: &DbPool, user: &AuthUser, route_id: Uuid) -> Result<(), actix_web::Error> {
+    let Some(driver_id) = driver_scope(user) else {
+        return Ok(());
+    };
+
+    let allowed = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM routes WHERE id = $1 AND driver_id = $2)"
+    )
+    .bind(route_id)
+    .bind(driver_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorForbidden("Drivers can only view their own routes"))
+    }
+}
+
+// Drivers may only read the tracking of a vehicle they have a route on
+async fn ensure_vehicle_tracking_reader(pool: &DbPool, user: &AuthUser, vehicle_id: Uuid) -> Result<(), actix_web::Error> {
+    let Some(driver_id) = driver_scope(user) else {
+        return Ok(());
+    };
+
+    let allowed = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM routes WHERE vehicle_id = $1 AND driver_id = $2)"
+    )
+    .bind(vehicle_id)
+    .bind(driver_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorForbidden("Drivers can only view vehicles they have a route on"))
+    }
+}
+
+fn track_export_response(
+    pool: &DbPool,
+    filter: TrackFilter,
+    query: &TrackExportQuery,
+    name: String,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(actix_web::error::ErrorBadRequest("from must not be after to"));
+        }
+    }
+
+    let disposition = format!("attachment; filename=\"{}.{}\"", name, query.format.extension());
+
+    Ok(HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header(("Content-Disposition", disposition))
+        .streaming(export_track(pool.clone(), filter, query.format, name)))
+}
+
+// Live tracking over server-sent events, for clients that cannot open a WebSocket. Streams the
+// same frames as `/ws` for the requested route, vehicle or driver, with each event's sequence
+// number as its SSE id so a reconnecting EventSource resumes through `Last-Event-ID`.
+#[get("/tracking/stream")]
+pub async fn stream_tracking(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    hub: web::Data<Addr<TrackingHub>>,
+    config: web::Data<Config>,
+    query: web::Query<TrackingStreamQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = match &query.token {
+        Some(token) => AuthUser::from_token(token, &config)?,
+        None => AuthUser::from_request_headers(&req)?,
+    };
+
+    let mut topics = Vec::new();
+    topics.extend(query.route_id.map(Topic::Route));
+    topics.extend(query.vehicle_id.map(Topic::Vehicle));
+    topics.extend(query.driver_id.map(Topic::Driver));
+    if topics.is_empty() {
+        topics.push(Topic::Fleet);
+    }
+
+    for topic in &topics {
+        authorize_topic(&pool, &user, *topic).await.map_err(|(code, message)| match code {
+            ErrorCode::Forbidden => actix_web::error::ErrorForbidden(message),
+            _ => actix_web::error::ErrorInternalServerError(message),
+        })?;
+    }
+
+    let since = req.headers()
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|id| id.parse::<i64>().ok());
+
+    let remaining = (user.token_expires_at - chrono::Utc::now().timestamp()).max(0) as u64;
+    let session = StreamSession {
+        id: Uuid::new_v4(),
+        hub: hub.get_ref().clone(),
+    };
+    let (sender, receiver) = mpsc::channel(config.ws_send_buffer);
+
+    hub.do_send(Connect {
+        session_id: session.id,
+        user,
+        sink: SessionSink::EventStream(sender),
+    });
+    hub.do_send(Subscribe {
+        session_id: session.id,
+        topics,
+        since,
+    });
+
+    let stream = event_stream(
+        session,
+        receiver,
+        Duration::from_secs(config.ws_heartbeat_interval_secs),
+        Duration::from_secs(remaining),
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream))
+}
+
+// Leaves the hub when the response is dropped, including when the client goes away
+struct StreamSession {
+    id: Uuid,
+    hub: Addr<TrackingHub>,
+}
+
+impl Drop for StreamSession {
+    fn drop(&mut self) {
+        self.hub.do_send(Disconnect { session_id: self.id });
+    }
+}
+
+// SSE body: every frame from the hub, a comment line each heartbeat so proxies keep the
+// connection open, and the end of the stream once the token expires
+fn event_stream(
+    session: StreamSession,
+    receiver: mpsc::Receiver<Frame>,
+    heartbeat: Duration,
+    expires_in: Duration,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let expires_at = tokio::time::Instant::now() + expires_in;
+    let heartbeat = tokio::time::interval(heartbeat);
+
+    futures::stream::unfold(
+        (session, receiver, heartbeat),
+        move |(session, mut receiver, mut heartbeat)| async move {
+            let chunk = tokio::select! {
+                frame = receiver.recv() => sse_event(frame?),
+                _ = heartbeat.tick() => ": ping\n\n".to_string(),
+                _ = tokio::time::sleep_until(expires_at) => return None,
+            };
+
+            Some((Ok(web::Bytes::from(chunk)), (session, receiver, heartbeat)))
+        },
+    )
+}
+
+// Frames are single-line JSON, so each fits in one `data` field
+fn sse_event(frame: Frame) -> String {
+    match frame.seq {
+        Some(seq) => format!("id: {}\ndata: {}\n\n", seq, frame.text),
+        None => format!("data: {}\n\n", frame.text),
+    }
+}
@@ -1,95 +1,447 @@
-use actix_web::{web, HttpResponse, HttpRequest};
-use sqlx::PgPool;
-use crate::models::{CreateVehicleRequest, Vehicle};
-use crate::auth::{extract_token_from_header, validate_token};
-use crate::database::DbPool;
-
-pub async fn register_vehicle(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-    vehicle_data: web::Json<CreateVehicleRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify admin or manager token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    // Only admins and managers can register vehicles
-    if claims.role != "Admin" && claims.role != "Manager" {
-        return Err(actix_web::error::ErrorForbidden("Only admins and managers can register vehicles"));
-    }
-
-    // Check if vehicle already exists
-    let existing_vehicle = sqlx::query_as::<_, Vehicle>(
-        "SELECT * FROM vehicles WHERE license_plate = $1"
-    )
-    .bind(&vehicle_data.license_plate)
-    .fetch_optional(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    if existing_vehicle.is_some() {
-        return Err(actix_web::error::ErrorBadRequest("Vehicle with this license plate already exists"));
-    }
-
-    let vehicle = sqlx::query_as::<_, Vehicle>(
-        r#"
-        INSERT INTO vehicles (license_plate, make, model, year, capacity, fuel_type)
-        VALUES ($1, $2, $3, $4, $5, $6)
-        RETURNING *
-        "#
-    )
-    .bind(&vehicle_data.license_plate)
-    .bind(&vehicle_data.make)
-    .bind(&vehicle_data.model)
-    .bind(&vehicle_data.year)
-    .bind(&vehicle_data.capacity)
-    .bind(&vehicle_data.fuel_type)
-    .fetch_one(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Created().json(vehicle))
-}
-
-pub async fn get_vehicles(
-    pool: web::Data<DbPool>,
-    req: HttpRequest,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Verify token
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing authorization header"))?;
-
-    let token = extract_token_from_header(auth_header)
-        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid authorization header format"))?;
-
-    let _claims = validate_token(&token)
-        .map_err(|_| actix_web::error::ErrorUnauthorized("Invalid token"))?;
-
-    let vehicles = sqlx::query_as::<_, Vehicle>(
-        "SELECT * FROM vehicles ORDER BY created_at DESC"
-    )
-    .fetch_all(pool.get_ref())
-    .await
-    .map_err(|e| {
-        eprintln!("Database error: {}", e);
-        actix_web::error::ErrorInternalServerError("Database error")
-    })?;
-
-    Ok(HttpResponse::Ok().json(vehicles))
-}
+use actix_web::{delete, get, patch, post, put, web, HttpResponse};
+use uuid::Uuid;
+use crate::models::{
+    CreateVehicleRequest, UpdateVehicleRequest, PatchVehicleRequest, AssignDriverRequest,
+    UpdateVehicleStatusRequest, Vehicle, VehicleStatus, User, UserRole,
+};
+use crate::auth::{AdminManagerOrDispatcher, AdminOrManager, Authorized, StaffOrDriver};
+use crate::database::DbPool;
+use crate::events::{publish_status_changes, StatusChange};
+
+// Only admins and managers can register vehicles
+#[post("/vehicles")]
+pub async fn register_vehicle(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOrManager>,
+    vehicle_data: web::Json<CreateVehicleRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Check if vehicle already exists
+    let existing_vehicle = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE license_plate = $1"
+    )
+    .bind(&vehicle_data.license_plate)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if existing_vehicle.is_some() {
+        return Err(actix_web::error::ErrorBadRequest("Vehicle with this license plate already exists"));
+    }
+
+    let vehicle = sqlx::query_as::<_, Vehicle>(
+        r#"
+        INSERT INTO vehicles (license_plate, make, model, year, capacity, volume_capacity, fuel_type, vehicle_type)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING *
+        "#
+    )
+    .bind(&vehicle_data.license_plate)
+    .bind(&vehicle_data.make)
+    .bind(&vehicle_data.model)
+    .bind(vehicle_data.year)
+    .bind(vehicle_data.capacity)
+    .bind(vehicle_data.volume_capacity)
+    .bind(&vehicle_data.fuel_type)
+    .bind(&vehicle_data.vehicle_type)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Created().json(vehicle))
+}
+
+#[get("/vehicles")]
+pub async fn get_vehicles(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicles = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles ORDER BY created_at DESC"
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(vehicles))
+}
+
+#[get("/vehicles/{vehicle_id}")]
+pub async fn get_vehicle(
+    pool: web::Data<DbPool>,
+    _user: Authorized<StaffOrDriver>,
+    vehicle_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle = fetch_vehicle(pool.get_ref(), vehicle_id.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(vehicle))
+}
+
+// Only admins and managers can edit vehicles; status and driver have their own endpoints
+#[put("/vehicles/{vehicle_id}")]
+pub async fn update_vehicle(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOrManager>,
+    vehicle_id: web::Path<Uuid>,
+    vehicle_data: web::Json<UpdateVehicleRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+    fetch_vehicle(pool.get_ref(), vehicle_id).await?;
+
+    let vehicle = save_vehicle(pool.get_ref(), vehicle_id, &vehicle_data).await?;
+
+    Ok(HttpResponse::Ok().json(vehicle))
+}
+
+#[patch("/vehicles/{vehicle_id}")]
+pub async fn patch_vehicle(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOrManager>,
+    vehicle_id: web::Path<Uuid>,
+    vehicle_data: web::Json<PatchVehicleRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+    let current = fetch_vehicle(pool.get_ref(), vehicle_id).await?;
+    let patch = vehicle_data.into_inner();
+
+    let merged = UpdateVehicleRequest {
+        license_plate: patch.license_plate.unwrap_or(current.license_plate),
+        make: patch.make.unwrap_or(current.make),
+        model: patch.model.unwrap_or(current.model),
+        year: patch.year.unwrap_or(current.year),
+        capacity: patch.capacity.unwrap_or(current.capacity),
+        volume_capacity: patch.volume_capacity.unwrap_or(current.volume_capacity),
+        fuel_type: patch.fuel_type.unwrap_or(current.fuel_type),
+        vehicle_type: patch.vehicle_type.unwrap_or(current.vehicle_type),
+    };
+
+    let vehicle = save_vehicle(pool.get_ref(), vehicle_id, &merged).await?;
+
+    Ok(HttpResponse::Ok().json(vehicle))
+}
+
+// Vehicles with route history cannot be deleted; retire them with the OutOfService status instead
+#[delete("/vehicles/{vehicle_id}")]
+pub async fn delete_vehicle(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminOrManager>,
+    vehicle_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+    let vehicle = fetch_vehicle(pool.get_ref(), vehicle_id).await?;
+
+    if vehicle.status == VehicleStatus::InUse {
+        return Err(actix_web::error::ErrorConflict("Cannot delete a vehicle that is in use"));
+    }
+
+    let has_history = sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS(SELECT 1 FROM routes WHERE vehicle_id = $1)
+            OR EXISTS(SELECT 1 FROM locations WHERE vehicle_id = $1)
+        "#
+    )
+    .bind(vehicle_id)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if has_history {
+        return Err(actix_web::error::ErrorConflict(
+            "Vehicle has route history; set its status to OutOfService to retire it",
+        ));
+    }
+
+    sqlx::query("DELETE FROM vehicles WHERE id = $1")
+        .bind(vehicle_id)
+        .execute(pool.get_ref())
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[put("/vehicles/{vehicle_id}/driver")]
+pub async fn assign_driver(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    vehicle_id: web::Path<Uuid>,
+    assignment: web::Json<AssignDriverRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+    let vehicle = fetch_vehicle(pool.get_ref(), vehicle_id).await?;
+
+    let driver = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE id = $1"
+    )
+    .bind(assignment.driver_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Driver not found"))?;
+
+    if driver.role != UserRole::Driver {
+        return Err(actix_web::error::ErrorBadRequest("User is not a driver"));
+    }
+
+    if vehicle.status == VehicleStatus::OutOfService {
+        return Err(actix_web::error::ErrorConflict("Cannot assign a driver to a vehicle that is out of service"));
+    }
+
+    if vehicle.status == VehicleStatus::InUse && vehicle.driver_id != Some(driver.id) {
+        return Err(actix_web::error::ErrorConflict("Cannot change the driver of a vehicle that is in use"));
+    }
+
+    let other_vehicle = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE driver_id = $1 AND id <> $2"
+    )
+    .bind(driver.id)
+    .bind(vehicle_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if let Some(other_vehicle) = other_vehicle {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "Driver is already assigned to vehicle {}",
+            other_vehicle.license_plate
+        )));
+    }
+
+    // The unique index on driver_id catches an assignment racing this one past the check above
+    let vehicle = sqlx::query_as::<_, Vehicle>(
+        "UPDATE vehicles SET driver_id = $2, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(vehicle_id)
+    .bind(driver.id)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(|e| {
+        if e.as_database_error().is_some_and(|e| e.is_unique_violation()) {
+            return actix_web::error::ErrorConflict("Driver is already assigned to another vehicle");
+        }
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(vehicle))
+}
+
+#[delete("/vehicles/{vehicle_id}/driver")]
+pub async fn unassign_driver(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    vehicle_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+
+    // Only release the driver if the vehicle is not in use at the time of the update
+    let vehicle = sqlx::query_as::<_, Vehicle>(
+        r#"
+        UPDATE vehicles SET driver_id = NULL, updated_at = NOW()
+        WHERE id = $1 AND status <> 'inuse'
+        RETURNING *
+        "#
+    )
+    .bind(vehicle_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match vehicle {
+        Some(vehicle) => Ok(HttpResponse::Ok().json(vehicle)),
+        None => {
+            fetch_vehicle(pool.get_ref(), vehicle_id).await?;
+            Err(actix_web::error::ErrorConflict("Cannot unassign the driver of a vehicle that is in use"))
+        }
+    }
+}
+
+#[put("/vehicles/{vehicle_id}/status")]
+pub async fn update_vehicle_status(
+    pool: web::Data<DbPool>,
+    _user: Authorized<AdminManagerOrDispatcher>,
+    vehicle_id: web::Path<Uuid>,
+    status_data: web::Json<UpdateVehicleStatusRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let vehicle_id = vehicle_id.into_inner();
+    let next = status_data.status;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    // Starting a route locks the vehicle too, so the route check below cannot be overtaken
+    let vehicle = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE id = $1 FOR UPDATE"
+    )
+    .bind(vehicle_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Vehicle not found"))?;
+
+    if vehicle.status == next {
+        return Ok(HttpResponse::Ok().json(vehicle));
+    }
+
+    // A vehicle on a running route is released by completing or cancelling the route
+    let on_route = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM routes WHERE vehicle_id = $1 AND status = 'inprogress')"
+    )
+    .bind(vehicle_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if on_route {
+        return Err(actix_web::error::ErrorConflict("Vehicle is on a route in progress; complete or cancel the route instead"));
+    }
+
+    let vehicle = transition_vehicle(&mut tx, vehicle_id, next).await?;
+
+    publish_status_changes(&mut tx, &[StatusChange::vehicle(&vehicle)]).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(HttpResponse::Ok().json(vehicle))
+}
+
+// Change a vehicle's status inside the caller's transaction, enforcing the state machine
+pub async fn transition_vehicle(
+    conn: &mut sqlx::PgConnection,
+    vehicle_id: Uuid,
+    next: VehicleStatus,
+) -> Result<Vehicle, actix_web::Error> {
+    let vehicle = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE id = $1 FOR UPDATE"
+    )
+    .bind(vehicle_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Vehicle not found"))?;
+
+    if !vehicle.status.can_transition_to(next) {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "Cannot change vehicle status from {:?} to {:?}",
+            vehicle.status, next
+        )));
+    }
+
+    if next == VehicleStatus::InUse && vehicle.driver_id.is_none() {
+        return Err(actix_web::error::ErrorConflict("A driver must be assigned before the vehicle can be put in use"));
+    }
+
+    // Retired vehicles release their driver
+    let driver_id = if next == VehicleStatus::OutOfService { None } else { vehicle.driver_id };
+
+    sqlx::query_as::<_, Vehicle>(
+        "UPDATE vehicles SET status = $2, driver_id = $3, updated_at = NOW() WHERE id = $1 RETURNING *"
+    )
+    .bind(vehicle_id)
+    .bind(next)
+    .bind(driver_id)
+    .fetch_one(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
+
+async fn fetch_vehicle(pool: &DbPool, vehicle_id: Uuid) -> Result<Vehicle, actix_web::Error> {
+    sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE id = $1"
+    )
+    .bind(vehicle_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("Vehicle not found"))
+}
+
+async fn save_vehicle(
+    pool: &DbPool,
+    vehicle_id: Uuid,
+    vehicle_data: &UpdateVehicleRequest,
+) -> Result<Vehicle, actix_web::Error> {
+    let existing_vehicle = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE license_plate = $1 AND id <> $2"
+    )
+    .bind(&vehicle_data.license_plate)
+    .bind(vehicle_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    if existing_vehicle.is_some() {
+        return Err(actix_web::error::ErrorBadRequest("Vehicle with this license plate already exists"));
+    }
+
+    sqlx::query_as::<_, Vehicle>(
+        r#"
+        UPDATE vehicles
+        SET license_plate = $2, make = $3, model = $4, year = $5, capacity = $6, volume_capacity = $7,
+            fuel_type = $8, vehicle_type = $9, updated_at = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#
+    )
+    .bind(vehicle_id)
+    .bind(&vehicle_data.license_plate)
+    .bind(&vehicle_data.make)
+    .bind(&vehicle_data.model)
+    .bind(vehicle_data.year)
+    .bind(vehicle_data.capacity)
+    .bind(vehicle_data.volume_capacity)
+    .bind(&vehicle_data.fuel_type)
+    .bind(&vehicle_data.vehicle_type)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
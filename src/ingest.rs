@@ -0,0 +1,277 @@
+// Batched persistence of driver locations reported over the WebSocket. Fixes are buffered and
+// written with one INSERT per batch, then published to every instance and acknowledged.
+// `commit_locations` and `process_locations` are shared with `POST /api/tracking/location`.
+use actix::{Actor, ActorFutureExt, Addr, AsyncContext, Context, Handler, Message, WrapFuture};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use uuid::Uuid;
+use crate::alerts::{evaluate_alerts, AlertRules};
+use crate::config::Config;
+use crate::database::DbPool;
+use crate::deviation::{evaluate_deviations, DeviationRules};
+use crate::eta::{estimate_arrivals, EtaRules};
+use crate::events::{
+    publish_alerts, publish_deviation_events, publish_etas, publish_geofence_events, publish_locations,
+    publish_status_changes,
+};
+use crate::geofence::evaluate_locations;
+use crate::models::{Location, UpdateLocationRequest};
+use crate::stop_detection::{detect_stops, StopDetection};
+use crate::websocket::{ErrorCode, Reply, WebSocketSession};
+
+// Buffered fixes are written once this many are waiting or the flush interval passes
+const LOCATION_BATCH_SIZE: usize = 100;
+const LOCATION_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+// Why a fix is missing from what `commit_locations` returns
+pub const NOT_DRIVING_ROUTE: &str = "Locations can only be reported for your own route in progress and its vehicle";
+
+// A validated fix from a driver's session; `recorded_at` is when it arrived, not when it is written
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct IngestLocation {
+    pub location: UpdateLocationRequest,
+    pub recorded_at: DateTime<Utc>,
+    pub session: Addr<WebSocketSession>,
+    pub request_id: Option<String>,
+}
+
+// Settings of everything derived from stored locations
+#[derive(Debug, Clone, Copy)]
+pub struct LocationRules {
+    pub stop_detection: StopDetection,
+    pub alerts: AlertRules,
+    pub deviation: DeviationRules,
+    pub eta: EtaRules,
+}
+
+impl LocationRules {
+    pub fn new(config: &Config) -> Self {
+        LocationRules {
+            stop_detection: StopDetection::new(config),
+            alerts: AlertRules::new(config),
+            deviation: DeviationRules::new(config),
+            eta: EtaRules::new(config),
+        }
+    }
+}
+
+pub struct LocationWriter {
+    pool: DbPool,
+    rules: LocationRules,
+    pending: Vec<IngestLocation>,
+}
+
+impl LocationWriter {
+    pub fn new(pool: DbPool, rules: LocationRules) -> Self {
+        LocationWriter {
+            pool,
+            rules,
+            pending: Vec::new(),
+        }
+    }
+
+    // Write the buffered fixes. The actor waits for the write so batches land in arrival order;
+    // fixes reported meanwhile queue up in its mailbox.
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let batch = std::mem::take(&mut self.pending);
+        let ids: Vec<Uuid> = batch.iter().map(|_| Uuid::new_v4()).collect();
+        let pool = self.pool.clone();
+
+        let write = insert_locations(
+            pool,
+            self.rules,
+            ids.clone(),
+            batch.iter().map(|fix| (fix.location.clone(), fix.recorded_at)).collect(),
+        );
+        ctx.wait(write.into_actor(self).map(move |result, _act, _ctx| {
+            let stored = match result {
+                Ok(stored) => stored,
+                // Already logged where it failed
+                Err(_) => {
+                    for fix in batch {
+                        fix.session.do_send(Reply {
+                            id: fix.request_id,
+                            request: "location_update",
+                            result: Err((ErrorCode::Internal, "Failed to store location".to_string())),
+                        });
+                    }
+                    return;
+                }
+            };
+
+            for (fix, id) in batch.into_iter().zip(ids) {
+                let location = stored.iter().find(|location| location.id == id);
+                let result = match location {
+                    Some(_) => Ok(()),
+                    None => Err((ErrorCode::Forbidden, NOT_DRIVING_ROUTE.to_string())),
+                };
+
+                fix.session.do_send(Reply {
+                    id: fix.request_id,
+                    request: "location_update",
+                    result,
+                });
+            }
+        }));
+    }
+}
+
+impl Actor for LocationWriter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(LOCATION_FLUSH_INTERVAL, |act, ctx| act.flush(ctx));
+    }
+}
+
+impl Handler<IngestLocation> for LocationWriter {
+    type Result = ();
+
+    fn handle(&mut self, msg: IngestLocation, ctx: &mut Self::Context) {
+        self.pending.push(msg);
+        if self.pending.len() >= LOCATION_BATCH_SIZE {
+            self.flush(ctx);
+        }
+    }
+}
+
+// Store a batch, then work out everything that follows from it
+async fn insert_locations(
+    pool: DbPool,
+    rules: LocationRules,
+    ids: Vec<Uuid>,
+    fixes: Vec<(UpdateLocationRequest, DateTime<Utc>)>,
+) -> Result<Vec<Location>, actix_web::Error> {
+    let stored = commit_locations(&pool, &ids, &fixes).await?;
+    process_locations(&pool, &stored, &rules).await;
+    Ok(stored)
+}
+
+// Store and publish fixes in a transaction of their own, so nothing derived from them can roll
+// them back
+pub async fn commit_locations(
+    pool: &DbPool,
+    ids: &[Uuid],
+    fixes: &[(UpdateLocationRequest, DateTime<Utc>)],
+) -> Result<Vec<Location>, actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let stored = store_locations(&mut tx, ids, fixes).await?;
+    publish_locations(&mut tx, &stored).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    Ok(stored)
+}
+
+// One row per fix with the given ids. A fix is only stored when its route is in progress with
+// that driver and vehicle; the others are skipped rather than failing the whole batch, so callers
+// find them missing from the result. Callers check that the driver is the one reporting.
+async fn store_locations(
+    conn: &mut sqlx::PgConnection,
+    ids: &[Uuid],
+    fixes: &[(UpdateLocationRequest, DateTime<Utc>)],
+) -> Result<Vec<Location>, actix_web::Error> {
+    let route_ids: Vec<Uuid> = fixes.iter().map(|(fix, _)| fix.route_id).collect();
+    let vehicle_ids: Vec<Uuid> = fixes.iter().map(|(fix, _)| fix.vehicle_id).collect();
+    let driver_ids: Vec<Uuid> = fixes.iter().map(|(fix, _)| fix.driver_id).collect();
+    let latitudes: Vec<f64> = fixes.iter().map(|(fix, _)| fix.latitude).collect();
+    let longitudes: Vec<f64> = fixes.iter().map(|(fix, _)| fix.longitude).collect();
+    let speeds: Vec<f64> = fixes.iter().map(|(fix, _)| fix.speed).collect();
+    let headings: Vec<f64> = fixes.iter().map(|(fix, _)| fix.heading).collect();
+    let timestamps: Vec<DateTime<Utc>> = fixes.iter().map(|(_, recorded_at)| *recorded_at).collect();
+
+    sqlx::query_as::<_, Location>(
+        r#"
+        INSERT INTO locations (id, route_id, vehicle_id, driver_id, latitude, longitude, speed, heading, timestamp)
+        SELECT f.* FROM UNNEST(
+            $1::uuid[], $2::uuid[], $3::uuid[], $4::uuid[],
+            $5::float8[], $6::float8[], $7::float8[], $8::float8[], $9::timestamptz[]
+        ) AS f(id, route_id, vehicle_id, driver_id, latitude, longitude, speed, heading, timestamp)
+        WHERE EXISTS(
+            SELECT 1 FROM routes r
+            WHERE r.id = f.route_id AND r.status = 'inprogress'
+              AND r.driver_id = f.driver_id AND r.vehicle_id = f.vehicle_id
+        )
+        RETURNING *
+        "#
+    )
+    .bind(ids)
+    .bind(route_ids)
+    .bind(vehicle_ids)
+    .bind(driver_ids)
+    .bind(latitudes)
+    .bind(longitudes)
+    .bind(speeds)
+    .bind(headings)
+    .bind(timestamps)
+    .fetch_all(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
+
+// Everything that follows from committed locations: geofence events, detected stops, telemetry
+// alerts, route deviations and live ETAs. Each vehicle's locations are processed in a transaction
+// of their own, so a failure only loses what follows from that vehicle's locations; it is logged
+// rather than returned, since the locations themselves are already stored.
+pub async fn process_locations(pool: &DbPool, locations: &[Location], rules: &LocationRules) {
+    let mut vehicle_ids: Vec<Uuid> = locations.iter().map(|location| location.vehicle_id).collect();
+    vehicle_ids.sort();
+    vehicle_ids.dedup();
+
+    for vehicle_id in vehicle_ids {
+        let vehicle_locations: Vec<Location> = locations
+            .iter()
+            .filter(|location| location.vehicle_id == vehicle_id)
+            .cloned()
+            .collect();
+
+        if let Err(e) = process_vehicle_locations(pool, &vehicle_locations, rules).await {
+            eprintln!("Failed to process locations of vehicle {}: {}", vehicle_id, e);
+        }
+    }
+}
+
+// ETAs come after stop detection so they see the arrivals it records
+async fn process_vehicle_locations(
+    pool: &DbPool,
+    locations: &[Location],
+    rules: &LocationRules,
+) -> Result<(), actix_web::Error> {
+    let mut tx = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let geofence_events = evaluate_locations(&mut tx, locations).await?;
+    let mut changes = Vec::new();
+    detect_stops(&mut tx, locations, &rules.stop_detection, &mut changes).await?;
+    let alerts = evaluate_alerts(&mut tx, locations, &rules.alerts).await?;
+    let deviation_events = evaluate_deviations(&mut tx, locations, &rules.deviation).await?;
+    let etas = estimate_arrivals(&mut tx, locations, &rules.eta).await?;
+
+    publish_geofence_events(&mut tx, &geofence_events).await?;
+    publish_status_changes(&mut tx, &changes).await?;
+    publish_alerts(&mut tx, &alerts).await?;
+    publish_deviation_events(&mut tx, &deviation_events).await?;
+    publish_etas(&mut tx, &etas).await?;
+
+    tx.commit().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
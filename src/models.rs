@@ -1,188 +1,847 @@
-use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
-use uuid::Uuid;
-use chrono::{DateTime, Utc};
-
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct User {
-    pub id: Uuid,
-    pub username: String,
-    pub email: String,
-    pub password_hash: String,
-    pub role: UserRole,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "user_role", rename_all = "lowercase")]
-pub enum UserRole {
-    Admin,
-    Manager,
-    Driver,
-    Dispatcher,
-}
-
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct Vehicle {
-    pub id: Uuid,
-    pub license_plate: String,
-    pub make: String,
-    pub model: String,
-    pub year: i32,
-    pub capacity: f64,
-    pub fuel_type: String,
-    pub status: VehicleStatus,
-    pub driver_id: Option<Uuid>,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "vehicle_status", rename_all = "lowercase")]
-pub enum VehicleStatus {
-    Available,
-    InUse,
-    Maintenance,
-    OutOfService,
-}
-
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct Cargo {
-    pub id: Uuid,
-    pub description: String,
-    pub weight: f64,
-    pub volume: f64,
-    pub cargo_type: String,
-    pub priority: CargoPriority,
-    pub status: CargoStatus,
-    pub shipper_id: Uuid,
-    pub consignee_id: Uuid,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "cargo_priority", rename_all = "lowercase")]
-pub enum CargoPriority {
-    Low,
-    Medium,
-    High,
-    Urgent,
-}
-
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "cargo_status", rename_all = "lowercase")]
-pub enum CargoStatus {
-    Pending,
-    Assigned,
-    InTransit,
-    Delivered,
-    Cancelled,
-}
-
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct Route {
-    pub id: Uuid,
-    pub source_address: String,
-    pub source_lat: f64,
-    pub source_lng: f64,
-    pub destination_address: String,
-    pub destination_lat: f64,
-    pub destination_lng: f64,
-    pub estimated_distance: f64,
-    pub estimated_duration: i32,
-    pub vehicle_id: Uuid,
-    pub driver_id: Uuid,
-    pub cargo_id: Uuid,
-    pub status: RouteStatus,
-    pub created_at: DateTime<Utc>,
-    pub updated_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
-#[sqlx(type_name = "route_status", rename_all = "lowercase")]
-pub enum RouteStatus {
-    Planned,
-    InProgress,
-    Completed,
-    Cancelled,
-}
-
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct Location {
-    pub id: Uuid,
-    pub route_id: Uuid,
-    pub vehicle_id: Uuid,
-    pub driver_id: Uuid,
-    pub latitude: f64,
-    pub longitude: f64,
-    pub speed: f64,
-    pub heading: f64,
-    pub timestamp: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CreateUserRequest {
-    pub username: String,
-    pub email: String,
-    pub password: String,
-    pub role: UserRole,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LoginRequest {
-    pub username: String,
-    pub password: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AuthResponse {
-    pub token: String,
-    pub user: User,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CreateVehicleRequest {
-    pub license_plate: String,
-    pub make: String,
-    pub model: String,
-    pub year: i32,
-    pub capacity: f64,
-    pub fuel_type: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CreateCargoRequest {
-    pub description: String,
-    pub weight: f64,
-    pub volume: f64,
-    pub cargo_type: String,
-    pub priority: CargoPriority,
-    pub shipper_id: Uuid,
-    pub consignee_id: Uuid,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CreateRouteRequest {
-    pub source_address: String,
-    pub source_lat: f64,
-    pub source_lng: f64,
-    pub destination_address: String,
-    pub destination_lat: f64,
-    pub destination_lng: f64,
-    pub vehicle_id: Uuid,
-    pub driver_id: Uuid,
-    pub cargo_id: Uuid,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct UpdateLocationRequest {
-    pub route_id: Uuid,
-    pub vehicle_id: Uuid,
-    pub driver_id: Uuid,
-    pub latitude: f64,
-    pub longitude: f64,
-    pub speed: f64,
-    pub heading: f64,
-}
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+use chrono::{DateTime, NaiveDate, Utc};
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub password_hash: String,
+    pub role: UserRole,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+pub enum UserRole {
+    Admin,
+    Manager,
+    Driver,
+    Dispatcher,
+    Customer,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Vehicle {
+    pub id: Uuid,
+    pub license_plate: String,
+    pub make: String,
+    pub model: String,
+    pub year: i32,
+    pub capacity: f64,
+    pub volume_capacity: Option<f64>,
+    pub fuel_type: String,
+    // Groups vehicles that share a speed limit, e.g. "truck" or "van"
+    pub vehicle_type: Option<String>,
+    pub status: VehicleStatus,
+    pub driver_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "vehicle_status", rename_all = "lowercase")]
+pub enum VehicleStatus {
+    Available,
+    InUse,
+    Maintenance,
+    OutOfService,
+}
+
+impl VehicleStatus {
+    // Legal status changes; an out-of-service vehicle has to pass maintenance before returning
+    pub fn can_transition_to(self, next: VehicleStatus) -> bool {
+        use VehicleStatus::*;
+        matches!(
+            (self, next),
+            (Available, InUse)
+                | (Available, Maintenance)
+                | (Available, OutOfService)
+                | (InUse, Available)
+                | (InUse, Maintenance)
+                | (Maintenance, Available)
+                | (Maintenance, OutOfService)
+                | (OutOfService, Maintenance)
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Cargo {
+    pub id: Uuid,
+    pub description: String,
+    pub weight: f64,
+    pub volume: f64,
+    pub cargo_type: String,
+    pub priority: CargoPriority,
+    pub status: CargoStatus,
+    pub shipper_id: Uuid,
+    pub consignee_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Declared from lowest to highest so the derived ordering ranks priorities
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "cargo_priority", rename_all = "lowercase")]
+pub enum CargoPriority {
+    Low,
+    Medium,
+    High,
+    Urgent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "cargo_status", rename_all = "lowercase")]
+pub enum CargoStatus {
+    Pending,
+    Assigned,
+    InTransit,
+    Delivered,
+    Cancelled,
+}
+
+impl CargoStatus {
+    // Legal status changes; Delivered and Cancelled are final. Cargo in transit returns to
+    // Pending when its route is cancelled
+    pub fn can_transition_to(self, next: CargoStatus) -> bool {
+        use CargoStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Assigned)
+                | (Pending, Cancelled)
+                | (Assigned, Pending)
+                | (Assigned, InTransit)
+                | (Assigned, Cancelled)
+                | (InTransit, Delivered)
+                | (InTransit, Pending)
+        )
+    }
+
+    // Changes the transition endpoint may make. Assigning, loading and returning cargo to Pending
+    // follow its route, so only creating, starting and cancelling routes make those
+    pub fn can_set_manually(self, next: CargoStatus) -> bool {
+        use CargoStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Cancelled) | (Assigned, Cancelled) | (InTransit, Delivered)
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct CargoEvent {
+    pub id: Uuid,
+    pub cargo_id: Uuid,
+    pub from_status: Option<CargoStatus>,
+    pub to_status: CargoStatus,
+    pub actor_id: Option<Uuid>,
+    pub route_id: Option<Uuid>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Route {
+    pub id: Uuid,
+    pub source_address: String,
+    pub source_lat: f64,
+    pub source_lng: f64,
+    pub destination_address: String,
+    pub destination_lat: f64,
+    pub destination_lng: f64,
+    pub estimated_distance: f64,
+    pub estimated_duration: i32,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub cargo_id: Option<Uuid>,
+    pub status: RouteStatus,
+    pub started_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "route_status", rename_all = "lowercase")]
+pub enum RouteStatus {
+    Planned,
+    InProgress,
+    Completed,
+    Cancelled,
+}
+
+impl RouteStatus {
+    // Legal status changes; Completed and Cancelled are final
+    pub fn can_transition_to(self, next: RouteStatus) -> bool {
+        use RouteStatus::*;
+        matches!(
+            (self, next),
+            (Planned, InProgress)
+                | (Planned, Cancelled)
+                | (InProgress, Completed)
+                | (InProgress, Cancelled)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "stop_type", rename_all = "lowercase")]
+pub enum StopType {
+    Pickup,
+    Dropoff,
+    Break,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "stop_cargo_action", rename_all = "lowercase")]
+pub enum StopCargoAction {
+    Load,
+    Unload,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RouteStop {
+    pub id: Uuid,
+    pub route_id: Uuid,
+    pub sequence: i32,
+    pub stop_type: StopType,
+    pub address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub planned_arrival: Option<DateTime<Utc>>,
+    pub planned_departure: Option<DateTime<Utc>>,
+    pub actual_arrival: Option<DateTime<Utc>>,
+    pub actual_departure: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RouteStopCargo {
+    pub stop_id: Uuid,
+    pub cargo_id: Uuid,
+    pub action: StopCargoAction,
+}
+
+// A stationary period detected from a vehicle's locations, at a planned stop or elsewhere
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct DetectedStop {
+    pub id: Uuid,
+    pub route_id: Uuid,
+    // None for an unplanned stop
+    pub route_stop_id: Option<Uuid>,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub arrived_at: DateTime<Utc>,
+    // None while the vehicle is still there
+    pub departed_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteStopDetails {
+    #[serde(flatten)]
+    pub stop: RouteStop,
+    pub load_cargo_ids: Vec<Uuid>,
+    pub unload_cargo_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteDetails {
+    #[serde(flatten)]
+    pub route: Route,
+    pub stops: Vec<RouteStopDetails>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Location {
+    pub id: Uuid,
+    pub route_id: Uuid,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub speed: f64,
+    pub heading: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateUserRequest {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub role: UserRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub invitation_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Invitation {
+    pub id: Uuid,
+    pub email: Option<String>,
+    pub role: UserRole,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_by: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub accepted_by: Option<Uuid>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateInvitationRequest {
+    pub email: Option<String>,
+    pub role: UserRole,
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvitationResponse {
+    pub invitation: Invitation,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthResponse {
+    pub token: String,
+    pub refresh_token: String,
+    pub user: User,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub replaced_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateVehicleRequest {
+    pub license_plate: String,
+    pub make: String,
+    pub model: String,
+    pub year: i32,
+    pub capacity: f64,
+    // Cargo volume the vehicle can carry; routes only check volume when this is set
+    pub volume_capacity: Option<f64>,
+    pub fuel_type: String,
+    pub vehicle_type: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateVehicleRequest {
+    pub license_plate: String,
+    pub make: String,
+    pub model: String,
+    pub year: i32,
+    pub capacity: f64,
+    pub volume_capacity: Option<f64>,
+    pub fuel_type: String,
+    pub vehicle_type: Option<String>,
+}
+
+// Absent fields are left as they are; `null` clears the optional ones
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatchVehicleRequest {
+    pub license_plate: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub year: Option<i32>,
+    pub capacity: Option<f64>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub volume_capacity: Option<Option<f64>>,
+    pub fuel_type: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub vehicle_type: Option<Option<String>>,
+}
+
+// Present fields become `Some`, `null` included, so a missing field stays `None`
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssignDriverRequest {
+    pub driver_id: Uuid,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateVehicleStatusRequest {
+    pub status: VehicleStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateCargoRequest {
+    pub description: String,
+    pub weight: f64,
+    pub volume: f64,
+    pub cargo_type: String,
+    pub priority: CargoPriority,
+    pub shipper_id: Uuid,
+    pub consignee_id: Uuid,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CargoTransitionRequest {
+    pub status: CargoStatus,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRouteRequest {
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    // Ordered stops; when omitted the single-leg fields below describe a pickup and a dropoff
+    pub stops: Option<Vec<CreateRouteStopRequest>>,
+    pub source_address: Option<String>,
+    pub source_lat: Option<f64>,
+    pub source_lng: Option<f64>,
+    pub destination_address: Option<String>,
+    pub destination_lat: Option<f64>,
+    pub destination_lng: Option<f64>,
+    pub cargo_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRouteStopRequest {
+    pub stop_type: StopType,
+    pub address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub planned_arrival: Option<DateTime<Utc>>,
+    pub planned_departure: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub load_cargo_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub unload_cargo_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteActionRequest {
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanRequest {
+    pub depot: PlanLocation,
+    pub deliveries: Vec<PlanDelivery>,
+    // Defaults to every available vehicle with an assigned driver
+    pub vehicle_ids: Option<Vec<Uuid>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanLocation {
+    pub address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanDelivery {
+    pub cargo_id: Uuid,
+    pub address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlannedRoute {
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub stops: Vec<CreateRouteStopRequest>,
+    #[serde(default)]
+    pub estimated_distance: f64,
+    #[serde(default)]
+    pub total_weight: f64,
+    #[serde(default)]
+    pub total_volume: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnassignedCargo {
+    pub cargo_id: Uuid,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanProposal {
+    pub routes: Vec<PlannedRoute>,
+    pub unassigned: Vec<UnassignedCargo>,
+    pub total_distance: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommitPlanRequest {
+    pub routes: Vec<PlannedRoute>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateLocationRequest {
+    pub route_id: Uuid,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub speed: f64,
+    pub heading: f64,
+}
+
+// Filters for `GET /api/tracking/stream`; with none given the stream follows the whole fleet.
+// `token` is for EventSource clients, which cannot set an Authorization header.
+#[derive(Debug, Deserialize)]
+pub struct TrackingStreamQuery {
+    pub token: Option<String>,
+    pub route_id: Option<Uuid>,
+    pub vehicle_id: Option<Uuid>,
+    pub driver_id: Option<Uuid>,
+}
+
+// Optional reduction of a route's history: a page after the `after` cursor, one location per
+// `bucket_secs`, then Douglas-Peucker simplification within `tolerance_meters`
+#[derive(Debug, Deserialize)]
+pub struct TrackingHistoryQuery {
+    pub after: Option<HistoryCursor>,
+    pub limit: Option<i64>,
+    pub bucket_secs: Option<i64>,
+    pub tolerance_meters: Option<f64>,
+}
+
+// Where a page of history ends: its last location's timestamp and id, as `<timestamp>_<id>`.
+// Locations sharing a timestamp are ordered by id, so a page boundary between them loses none.
+// A bare timestamp starts after every location at that time.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryCursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl HistoryCursor {
+    pub fn after(location: &Location) -> Self {
+        HistoryCursor {
+            timestamp: location.timestamp,
+            id: location.id,
+        }
+    }
+}
+
+impl std::fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = self.timestamp.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true);
+        write!(f, "{}_{}", timestamp, self.id)
+    }
+}
+
+impl std::str::FromStr for HistoryCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timestamp, id) = match s.split_once('_') {
+            Some((timestamp, id)) => (timestamp, Uuid::parse_str(id).map_err(|e| e.to_string())?),
+            None => (s, Uuid::max()),
+        };
+        let timestamp = DateTime::parse_from_rfc3339(timestamp).map_err(|e| e.to_string())?;
+
+        Ok(HistoryCursor {
+            timestamp: timestamp.with_timezone(&Utc),
+            id,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for HistoryCursor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackFormat {
+    Gpx,
+    Kml,
+    GeoJson,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrackExportQuery {
+    pub format: TrackFormat,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "geofence_kind", rename_all = "lowercase")]
+pub enum GeofenceKind {
+    Depot,
+    CustomerSite,
+    Restricted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "geofence_shape", rename_all = "lowercase")]
+pub enum GeofenceShape {
+    Circle,
+    Polygon,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// A circle uses the center and radius columns, a polygon its vertices; the others are NULL
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Geofence {
+    pub id: Uuid,
+    pub name: String,
+    pub kind: GeofenceKind,
+    pub shape: GeofenceShape,
+    pub center_latitude: Option<f64>,
+    pub center_longitude: Option<f64>,
+    pub radius_meters: Option<f64>,
+    pub vertices: Option<sqlx::types::Json<Vec<GeoPoint>>>,
+    // A vehicle staying inside this long gets one dwell event per visit; no dwell events if unset
+    pub dwell_seconds: Option<i32>,
+    // Overrides the vehicle type and global speed limits inside the geofence
+    pub speed_limit_kmh: Option<f64>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "geofence_event_type", rename_all = "lowercase")]
+pub enum GeofenceEventType {
+    Enter,
+    Exit,
+    Dwell,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct GeofenceEvent {
+    pub id: Uuid,
+    pub geofence_id: Uuid,
+    pub event_type: GeofenceEventType,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub route_id: Uuid,
+    // The fix that triggered the event
+    pub location_id: Uuid,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateGeofenceRequest {
+    pub name: String,
+    pub kind: GeofenceKind,
+    pub shape: GeofenceShape,
+    pub center_latitude: Option<f64>,
+    pub center_longitude: Option<f64>,
+    pub radius_meters: Option<f64>,
+    pub vertices: Option<Vec<GeoPoint>>,
+    pub dwell_seconds: Option<i32>,
+    pub speed_limit_kmh: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct SpeedLimit {
+    pub vehicle_type: String,
+    pub speed_limit_kmh: f64,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetSpeedLimitRequest {
+    pub speed_limit_kmh: f64,
+}
+
+// The unit of an alert's `value` and `threshold` depends on its type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "alert_type", rename_all = "lowercase")]
+pub enum AlertType {
+    // Speed in km/h
+    Speeding,
+    // Deceleration in km/h per second
+    HarshBraking,
+    // Heading change in degrees per second
+    HarshTurn,
+    // Seconds at speed 0
+    Idling,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Alert {
+    pub id: Uuid,
+    pub alert_type: AlertType,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub route_id: Uuid,
+    pub location_id: Uuid,
+    // The geofence whose speed limit was broken, if the limit came from one
+    pub geofence_id: Option<Uuid>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub value: f64,
+    pub threshold: f64,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertQuery {
+    pub vehicle_id: Option<Uuid>,
+    pub driver_id: Option<Uuid>,
+    pub alert_type: Option<AlertType>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Road geometry of a route, replacing the straight legs between its stops
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RoutePath {
+    pub route_id: Uuid,
+    pub points: sqlx::types::Json<Vec<GeoPoint>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRoutePathRequest {
+    pub points: Vec<GeoPoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "deviation_event_type", rename_all = "lowercase")]
+pub enum DeviationEventType {
+    OffRoute,
+    OnRoute,
+}
+
+// A vehicle leaving its route's corridor for long enough, or coming back to it
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RouteDeviationEvent {
+    pub id: Uuid,
+    pub route_id: Uuid,
+    pub event_type: DeviationEventType,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub location_id: Uuid,
+    // From the planned path, at the triggering location
+    pub distance_meters: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub occurred_at: DateTime<Utc>,
+}
+
+// Estimated arrival at the next stop not yet left and at the route's last stop, from the vehicle's
+// position at `location_id`. Distances are along straight legs between stops.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RouteEta {
+    pub id: Uuid,
+    pub route_id: Uuid,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub location_id: Uuid,
+    pub next_stop_id: Uuid,
+    pub next_stop_distance_km: f64,
+    pub next_stop_eta: DateTime<Utc>,
+    pub remaining_distance_km: f64,
+    pub destination_eta: DateTime<Utc>,
+    // Blend of recently observed and planned speed the estimate assumes
+    pub speed_kmh: f64,
+    pub computed_at: DateTime<Utc>,
+}
+
+// Summary of a completed route's locations next to its plan. Durations are in seconds, planned ones
+// included, so the two can be compared directly.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RouteStatistics {
+    pub route_id: Uuid,
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub distance_km: f64,
+    pub planned_distance_km: f64,
+    // From start to completion, or between the first and last location if the route has no start time
+    pub duration_secs: i64,
+    pub planned_duration_secs: i64,
+    pub moving_secs: i64,
+    pub stopped_secs: i64,
+    pub max_speed_kmh: f64,
+    // Distance over moving time
+    pub avg_speed_kmh: f64,
+    pub stop_count: i64,
+    pub location_count: i64,
+    pub computed_at: DateTime<Utc>,
+}
+
+// The same summary for one vehicle over one UTC day, across its routes
+#[derive(Debug, Serialize)]
+pub struct VehicleDayStatistics {
+    pub vehicle_id: Uuid,
+    pub date: NaiveDate,
+    pub distance_km: f64,
+    pub moving_secs: i64,
+    pub stopped_secs: i64,
+    pub max_speed_kmh: f64,
+    pub avg_speed_kmh: f64,
+    pub stop_count: i64,
+    pub location_count: i64,
+    pub route_count: i64,
+}
+
+// Both dates are inclusive and default to today
+#[derive(Debug, Deserialize)]
+pub struct VehicleStatisticsQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
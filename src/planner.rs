@@ -0,0 +1,325 @@
+// Daily dispatch planning: split deliveries from a depot across vehicles as multi-stop routes,
+// keeping each vehicle within its capacity and visiting higher-priority cargo first
+use uuid::Uuid;
+use crate::handlers::routes::haversine_distance;
+use crate::models::CargoPriority;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Cost of driving between two points; the planner minimizes the sum over every leg
+pub trait CostMetric {
+    fn cost(&self, from: Point, to: Point) -> f64;
+}
+
+// Great-circle distance in kilometers, the same measure used for route estimates
+pub struct Haversine;
+
+impl CostMetric for Haversine {
+    fn cost(&self, from: Point, to: Point) -> f64 {
+        haversine_distance(from.latitude, from.longitude, to.latitude, to.longitude)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub cargo_id: Uuid,
+    pub location: Point,
+    pub weight: f64,
+    pub volume: f64,
+    pub priority: CargoPriority,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanVehicle {
+    pub vehicle_id: Uuid,
+    pub capacity: f64,
+    pub volume_capacity: Option<f64>,
+}
+
+// Deliveries for one vehicle, as indexes into the planner input in visiting order
+#[derive(Debug)]
+pub struct Tour {
+    pub vehicle_id: Uuid,
+    pub deliveries: Vec<usize>,
+    pub load: f64,
+    pub volume: f64,
+    pub distance: f64,
+}
+
+#[derive(Debug)]
+pub struct Unassigned {
+    pub delivery: usize,
+    pub reason: &'static str,
+}
+
+#[derive(Debug)]
+pub struct Plan {
+    pub tours: Vec<Tour>,
+    pub unassigned: Vec<Unassigned>,
+}
+
+// Cheapest insertion in priority order followed by 2-opt within each priority band. Tours start
+// at the depot and end at their last delivery; vehicles left without deliveries get no tour.
+pub fn plan(
+    depot: Point,
+    deliveries: &[Delivery],
+    vehicles: &[PlanVehicle],
+    metric: &impl CostMetric,
+) -> Plan {
+    let mut order: Vec<usize> = (0..deliveries.len()).collect();
+    order.sort_by(|&a, &b| {
+        deliveries[b].priority.cmp(&deliveries[a].priority)
+            .then(deliveries[b].weight.total_cmp(&deliveries[a].weight))
+    });
+
+    let mut tours: Vec<Vec<usize>> = vec![Vec::new(); vehicles.len()];
+    let mut loads = vec![0.0; vehicles.len()];
+    let mut volumes = vec![0.0; vehicles.len()];
+    let mut unassigned = Vec::new();
+
+    for index in order {
+        let delivery = &deliveries[index];
+        let mut best: Option<(usize, usize, f64)> = None;
+
+        for (v, vehicle) in vehicles.iter().enumerate() {
+            if !fits(vehicle, loads[v] + delivery.weight, volumes[v] + delivery.volume) {
+                continue;
+            }
+
+            // Everything already in the tour has at least this priority, so it goes after the
+            // last stop of higher priority
+            let tour = &tours[v];
+            let first = tour
+                .iter()
+                .rposition(|&other| deliveries[other].priority > delivery.priority)
+                .map_or(0, |position| position + 1);
+
+            for position in first..=tour.len() {
+                let previous = if position == 0 { depot } else { deliveries[tour[position - 1]].location };
+                let mut added = metric.cost(previous, delivery.location);
+                if let Some(&next) = tour.get(position) {
+                    added += metric.cost(delivery.location, deliveries[next].location)
+                        - metric.cost(previous, deliveries[next].location);
+                }
+
+                if best.is_none_or(|(_, _, cost)| added < cost) {
+                    best = Some((v, position, added));
+                }
+            }
+        }
+
+        match best {
+            Some((v, position, _)) => {
+                tours[v].insert(position, index);
+                loads[v] += delivery.weight;
+                volumes[v] += delivery.volume;
+            }
+            None => {
+                let fits_any = vehicles.iter().any(|vehicle| fits(vehicle, delivery.weight, delivery.volume));
+                unassigned.push(Unassigned {
+                    delivery: index,
+                    reason: if fits_any {
+                        "No vehicle has enough remaining capacity"
+                    } else {
+                        "Heavier than the capacity of every vehicle"
+                    },
+                });
+            }
+        }
+    }
+
+    let tours = tours
+        .into_iter()
+        .zip(loads.into_iter().zip(volumes))
+        .zip(vehicles)
+        .filter(|((tour, _), _)| !tour.is_empty())
+        .map(|((mut tour, (load, volume)), vehicle)| {
+            improve_tour(depot, deliveries, &mut tour, metric);
+            Tour {
+                vehicle_id: vehicle.vehicle_id,
+                distance: tour_distance(depot, deliveries, &tour, metric),
+                deliveries: tour,
+                load,
+                volume,
+            }
+        })
+        .collect();
+
+    Plan { tours, unassigned }
+}
+
+// Volume is only limited for vehicles that declare a volume capacity
+fn fits(vehicle: &PlanVehicle, weight: f64, volume: f64) -> bool {
+    weight <= vehicle.capacity && vehicle.volume_capacity.is_none_or(|capacity| volume <= capacity)
+}
+
+pub fn tour_distance(depot: Point, deliveries: &[Delivery], tour: &[usize], metric: &impl CostMetric) -> f64 {
+    let mut previous = depot;
+    let mut distance = 0.0;
+    for &index in tour {
+        distance += metric.cost(previous, deliveries[index].location);
+        previous = deliveries[index].location;
+    }
+    distance
+}
+
+// 2-opt: reverse stretches of the tour while that shortens it, never mixing priority bands
+fn improve_tour(depot: Point, deliveries: &[Delivery], tour: &mut [usize], metric: &impl CostMetric) {
+    let mut best = tour_distance(depot, deliveries, tour, metric);
+    let mut improved = true;
+
+    while improved {
+        improved = false;
+        for i in 0..tour.len() {
+            for j in i + 1..tour.len() {
+                if deliveries[tour[i]].priority != deliveries[tour[j]].priority {
+                    break;
+                }
+
+                tour[i..=j].reverse();
+                let distance = tour_distance(depot, deliveries, tour, metric);
+                if distance + 1e-9 < best {
+                    best = distance;
+                    improved = true;
+                } else {
+                    tour[i..=j].reverse();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Straight-line distance on the plane, so expected costs are easy to work out
+    struct Planar;
+
+    impl CostMetric for Planar {
+        fn cost(&self, from: Point, to: Point) -> f64 {
+            (from.latitude - to.latitude).hypot(from.longitude - to.longitude)
+        }
+    }
+
+    const DEPOT: Point = Point { latitude: 0.0, longitude: 0.0 };
+
+    fn delivery(x: f64, weight: f64, priority: CargoPriority) -> Delivery {
+        Delivery {
+            cargo_id: Uuid::new_v4(),
+            location: Point { latitude: x, longitude: 0.0 },
+            weight,
+            volume: 1.0,
+            priority,
+        }
+    }
+
+    fn vehicle(capacity: f64, volume_capacity: Option<f64>) -> PlanVehicle {
+        PlanVehicle {
+            vehicle_id: Uuid::new_v4(),
+            capacity,
+            volume_capacity,
+        }
+    }
+
+    #[test]
+    fn nothing_to_deliver_gives_an_empty_plan() {
+        let plan = plan(DEPOT, &[], &[vehicle(100.0, None)], &Planar);
+
+        assert!(plan.tours.is_empty());
+        assert!(plan.unassigned.is_empty());
+    }
+
+    #[test]
+    fn same_priority_deliveries_are_visited_nearest_first() {
+        let deliveries = vec![
+            delivery(3.0, 1.0, CargoPriority::Medium),
+            delivery(1.0, 1.0, CargoPriority::Medium),
+            delivery(2.0, 1.0, CargoPriority::Medium),
+        ];
+
+        let plan = plan(DEPOT, &deliveries, &[vehicle(100.0, None)], &Planar);
+
+        assert_eq!(plan.tours.len(), 1);
+        assert_eq!(plan.tours[0].deliveries, vec![1, 2, 0]);
+        assert_eq!(plan.tours[0].distance, 3.0);
+        assert_eq!(plan.tours[0].load, 3.0);
+    }
+
+    #[test]
+    fn higher_priority_is_visited_first_even_when_further() {
+        let deliveries = vec![
+            delivery(1.0, 1.0, CargoPriority::Low),
+            delivery(5.0, 1.0, CargoPriority::Urgent),
+        ];
+
+        let plan = plan(DEPOT, &deliveries, &[vehicle(100.0, None)], &Planar);
+
+        assert_eq!(plan.tours[0].deliveries, vec![1, 0]);
+        assert_eq!(plan.tours[0].distance, 9.0);
+    }
+
+    #[test]
+    fn load_up_to_exactly_the_capacity_fits() {
+        let deliveries = vec![
+            delivery(1.0, 60.0, CargoPriority::Medium),
+            delivery(2.0, 40.0, CargoPriority::Medium),
+        ];
+
+        let plan = plan(DEPOT, &deliveries, &[vehicle(100.0, None)], &Planar);
+
+        assert!(plan.unassigned.is_empty());
+        assert_eq!(plan.tours[0].load, 100.0);
+    }
+
+    #[test]
+    fn overflow_goes_to_the_next_vehicle_and_idle_vehicles_get_no_tour() {
+        let vehicles = vec![vehicle(50.0, None), vehicle(50.0, None), vehicle(50.0, None)];
+        let deliveries = vec![
+            delivery(1.0, 40.0, CargoPriority::Medium),
+            delivery(2.0, 40.0, CargoPriority::Medium),
+        ];
+
+        let plan = plan(DEPOT, &deliveries, &vehicles, &Planar);
+
+        assert_eq!(plan.tours.len(), 2);
+        assert!(plan.tours.iter().all(|tour| tour.deliveries.len() == 1));
+        assert!(plan.unassigned.is_empty());
+    }
+
+    #[test]
+    fn unassigned_deliveries_say_why() {
+        let deliveries = vec![
+            delivery(1.0, 80.0, CargoPriority::Medium),
+            delivery(2.0, 30.0, CargoPriority::Medium),
+            delivery(3.0, 150.0, CargoPriority::Medium),
+        ];
+
+        let plan = plan(DEPOT, &deliveries, &[vehicle(100.0, None)], &Planar);
+
+        assert_eq!(plan.tours[0].deliveries, vec![0]);
+        let reasons: Vec<(usize, &str)> = plan.unassigned.iter().map(|u| (u.delivery, u.reason)).collect();
+        assert!(reasons.contains(&(1, "No vehicle has enough remaining capacity")));
+        assert!(reasons.contains(&(2, "Heavier than the capacity of every vehicle")));
+    }
+
+    #[test]
+    fn volume_is_only_limited_when_the_vehicle_declares_it() {
+        let deliveries = vec![
+            delivery(1.0, 1.0, CargoPriority::Medium),
+            delivery(2.0, 1.0, CargoPriority::Medium),
+        ];
+
+        let limited = plan(DEPOT, &deliveries, &[vehicle(100.0, Some(1.0))], &Planar);
+        let unlimited = plan(DEPOT, &deliveries, &[vehicle(100.0, None)], &Planar);
+
+        assert_eq!(limited.tours[0].volume, 1.0);
+        assert_eq!(limited.unassigned.len(), 1);
+        assert_eq!(unlimited.tours[0].volume, 2.0);
+        assert!(unlimited.unassigned.is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+// Douglas-Peucker simplification of a location track: drops every location that lies within the
+// tolerance of the line between the locations kept around it. The first and last are always kept.
+use crate::deviation::distance_to_path;
+use crate::models::{GeoPoint, Location};
+
+fn point(location: &Location) -> GeoPoint {
+    GeoPoint {
+        latitude: location.latitude,
+        longitude: location.longitude,
+    }
+}
+
+pub fn simplify_track(locations: Vec<Location>, tolerance_meters: f64) -> Vec<Location> {
+    if locations.len() < 3 {
+        return locations;
+    }
+
+    let mut keep = vec![false; locations.len()];
+    keep[0] = true;
+    keep[locations.len() - 1] = true;
+
+    // Spans still to split, worked off a stack since week-long tracks would recurse too deep
+    let mut spans = vec![(0, locations.len() - 1)];
+    while let Some((first, last)) = spans.pop() {
+        let chord = [point(&locations[first]), point(&locations[last])];
+
+        let farthest = (first + 1..last)
+            .map(|index| (index, distance_to_path(&chord, point(&locations[index]))))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        if let Some((index, distance)) = farthest {
+            if distance > tolerance_meters {
+                keep[index] = true;
+                spans.push((first, index));
+                spans.push((index, last));
+            }
+        }
+    }
+
+    locations
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(location, keep)| keep.then_some(location))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn location(latitude: f64, longitude: f64) -> Location {
+        Location {
+            id: Uuid::new_v4(),
+            route_id: Uuid::nil(),
+            vehicle_id: Uuid::nil(),
+            driver_id: Uuid::nil(),
+            latitude,
+            longitude,
+            speed: 0.0,
+            heading: 0.0,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn coordinates(locations: &[Location]) -> Vec<(f64, f64)> {
+        locations.iter().map(|location| (location.latitude, location.longitude)).collect()
+    }
+
+    #[test]
+    fn straight_line_collapses_to_its_ends() {
+        let track: Vec<Location> = (0..5).map(|i| location(52.0 + i as f64 * 0.001, 4.0)).collect();
+
+        let simplified = simplify_track(track, 1.0);
+
+        assert_eq!(coordinates(&simplified), vec![(52.0, 4.0), (52.004, 4.0)]);
+    }
+
+    #[test]
+    fn zero_tolerance_keeps_every_turn() {
+        let track = vec![
+            location(52.0, 4.0),
+            location(52.001, 4.001),
+            location(52.002, 4.0),
+            location(52.003, 4.001),
+        ];
+
+        let simplified = simplify_track(track.clone(), 0.0);
+
+        assert_eq!(coordinates(&simplified), coordinates(&track));
+    }
+
+    #[test]
+    fn spike_beyond_tolerance_is_kept() {
+        // The spike is about 68 m east of the line running north; its neighbours are about 33 m
+        // off the lines to it, so they go
+        let track = vec![
+            location(52.0, 4.0),
+            location(52.001, 4.0),
+            location(52.002, 4.001),
+            location(52.003, 4.0),
+            location(52.004, 4.0),
+        ];
+
+        let simplified = simplify_track(track, 40.0);
+
+        assert_eq!(coordinates(&simplified), vec![(52.0, 4.0), (52.002, 4.001), (52.004, 4.0)]);
+    }
+
+    #[test]
+    fn fewer_than_three_locations_are_returned_as_is() {
+        assert!(simplify_track(Vec::new(), 10.0).is_empty());
+        assert_eq!(coordinates(&simplify_track(vec![location(52.0, 4.0)], 10.0)), vec![(52.0, 4.0)]);
+
+        let pair = vec![location(52.0, 4.0), location(52.0, 4.0)];
+        assert_eq!(coordinates(&simplify_track(pair, 10.0)), vec![(52.0, 4.0), (52.0, 4.0)]);
+    }
+}
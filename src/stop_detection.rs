@@ -0,0 +1,371 @@
+// Stop detection from locations: a vehicle that stays within a small radius for long enough has
+// stopped. A stop close to a planned route stop records its actual arrival and departure; any
+// other stop is kept as an unplanned stop.
+use chrono::{DateTime, Utc};
+use sqlx::Connection;
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::config::Config;
+use crate::events::StatusChange;
+use crate::handlers::cargo::CargoEventDetails;
+use crate::handlers::routes::{finish_route, haversine_distance, lock_route};
+use crate::handlers::stops::{record_arrival, record_departure};
+use crate::models::{Location, RouteStop};
+
+// Class of the per-vehicle advisory locks, kept apart from the geofence ones
+const MOTION_LOCK_CLASS: i32 = 0x7374_6f70;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StopDetection {
+    pub radius_meters: f64,
+    pub dwell: chrono::Duration,
+    pub auto_complete_routes: bool,
+}
+
+impl StopDetection {
+    pub fn new(config: &Config) -> Self {
+        StopDetection {
+            radius_meters: config.stop_radius_meters,
+            dwell: chrono::Duration::seconds(config.stop_dwell_secs as i64),
+            auto_complete_routes: config.auto_complete_routes,
+        }
+    }
+
+    fn within_radius(&self, latitude: f64, longitude: f64, other_latitude: f64, other_longitude: f64) -> bool {
+        haversine_distance(latitude, longitude, other_latitude, other_longitude) * 1000.0 <= self.radius_meters
+    }
+}
+
+// Where a vehicle has stayed since it last moved, as stored in `vehicle_motion`
+#[derive(sqlx::FromRow)]
+struct Motion {
+    vehicle_id: Uuid,
+    route_id: Uuid,
+    latitude: f64,
+    longitude: f64,
+    since: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    // Set once the vehicle has stayed long enough to count as stopped
+    detected_stop_id: Option<Uuid>,
+}
+
+// Route stops of the running routes in a batch, updated as arrivals and departures are recorded
+struct RunningRoutes {
+    stops: HashMap<Uuid, Vec<RouteStop>>,
+}
+
+impl RunningRoutes {
+    fn contains(&self, route_id: Uuid) -> bool {
+        self.stops.contains_key(&route_id)
+    }
+}
+
+// Follow newly stored locations, in timestamp order per vehicle, and record the stops they
+// reveal inside the caller's transaction. Cargo delivered and routes completed along the way are
+// added to `changes` for the caller to publish.
+pub async fn detect_stops(
+    conn: &mut sqlx::PgConnection,
+    locations: &[Location],
+    settings: &StopDetection,
+    changes: &mut Vec<StatusChange>,
+) -> Result<(), actix_web::Error> {
+    if locations.is_empty() {
+        return Ok(());
+    }
+
+    let mut vehicle_ids: Vec<Uuid> = locations.iter().map(|location| location.vehicle_id).collect();
+    vehicle_ids.sort();
+    vehicle_ids.dedup();
+
+    let mut route_ids: Vec<Uuid> = locations.iter().map(|location| location.route_id).collect();
+    route_ids.sort();
+    route_ids.dedup();
+
+    // Another writer handling the same vehicle would otherwise start from the same stale state
+    sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext(v::text)) FROM UNNEST($2::uuid[]) AS v")
+        .bind(MOTION_LOCK_CLASS)
+        .bind(&vehicle_ids)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+
+    // Locked like the stop and route endpoints do, so manual arrivals cannot race detected ones
+    let running_ids = sqlx::query_scalar::<_, Uuid>(
+        "SELECT id FROM routes WHERE id = ANY($1) AND status = 'inprogress' ORDER BY id FOR NO KEY UPDATE"
+    )
+    .bind(&route_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route_stops = sqlx::query_as::<_, RouteStop>(
+        "SELECT * FROM route_stops WHERE route_id = ANY($1) ORDER BY route_id, sequence"
+    )
+    .bind(&running_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let mut running = RunningRoutes {
+        stops: running_ids.into_iter().map(|route_id| (route_id, Vec::new())).collect(),
+    };
+    for stop in route_stops {
+        if let Some(stops) = running.stops.get_mut(&stop.route_id) {
+            stops.push(stop);
+        }
+    }
+
+    let mut motions: HashMap<Uuid, Motion> = sqlx::query_as::<_, Motion>(
+        "SELECT * FROM vehicle_motion WHERE vehicle_id = ANY($1)"
+    )
+    .bind(&vehicle_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .map(|motion| (motion.vehicle_id, motion))
+    .collect();
+
+    let mut ordered: Vec<&Location> = locations.iter().collect();
+    ordered.sort_by_key(|location| location.timestamp);
+
+    for location in ordered {
+        let moved = match motions.get(&location.vehicle_id) {
+            // Fixes arriving late say nothing about where the vehicle is now
+            Some(motion) if location.timestamp < motion.last_seen_at => continue,
+            Some(motion) => {
+                motion.route_id != location.route_id
+                    || !settings.within_radius(motion.latitude, motion.longitude, location.latitude, location.longitude)
+            }
+            None => true,
+        };
+
+        if moved {
+            if let Some(motion) = motions.remove(&location.vehicle_id) {
+                if let Some(detected_stop_id) = motion.detected_stop_id {
+                    depart(&mut *conn, detected_stop_id, &motion, &mut running, changes).await?;
+                }
+            }
+
+            if running.contains(location.route_id) {
+                motions.insert(location.vehicle_id, Motion {
+                    vehicle_id: location.vehicle_id,
+                    route_id: location.route_id,
+                    latitude: location.latitude,
+                    longitude: location.longitude,
+                    since: location.timestamp,
+                    last_seen_at: location.timestamp,
+                    detected_stop_id: None,
+                });
+            }
+            continue;
+        }
+
+        let Some(motion) = motions.get_mut(&location.vehicle_id) else {
+            continue;
+        };
+        motion.last_seen_at = location.timestamp;
+
+        if motion.detected_stop_id.is_none()
+            && location.timestamp - motion.since >= settings.dwell
+            && running.contains(motion.route_id)
+        {
+            arrive(&mut *conn, motion, location.driver_id, settings, &mut running, changes).await?;
+        }
+    }
+
+    for vehicle_id in vehicle_ids {
+        let result = match motions.get(&vehicle_id) {
+            Some(motion) => sqlx::query(
+                r#"
+                INSERT INTO vehicle_motion (vehicle_id, route_id, latitude, longitude, since, last_seen_at, detected_stop_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (vehicle_id) DO UPDATE
+                SET route_id = EXCLUDED.route_id,
+                    latitude = EXCLUDED.latitude,
+                    longitude = EXCLUDED.longitude,
+                    since = EXCLUDED.since,
+                    last_seen_at = EXCLUDED.last_seen_at,
+                    detected_stop_id = EXCLUDED.detected_stop_id
+                "#
+            )
+            .bind(motion.vehicle_id)
+            .bind(motion.route_id)
+            .bind(motion.latitude)
+            .bind(motion.longitude)
+            .bind(motion.since)
+            .bind(motion.last_seen_at)
+            .bind(motion.detected_stop_id)
+            .execute(&mut *conn)
+            .await,
+            None => sqlx::query(
+                "DELETE FROM vehicle_motion WHERE vehicle_id = $1"
+            )
+            .bind(vehicle_id)
+            .execute(&mut *conn)
+            .await,
+        };
+
+        result.map_err(|e| {
+            eprintln!("Database error: {}", e);
+            actix_web::error::ErrorInternalServerError("Database error")
+        })?;
+    }
+
+    Ok(())
+}
+
+// The vehicle has stayed put long enough: record the stop, matched to the nearest planned stop
+// it has not left yet. Arrival counts from when it stopped moving, not from when it was detected.
+async fn arrive(
+    conn: &mut sqlx::PgConnection,
+    motion: &mut Motion,
+    driver_id: Uuid,
+    settings: &StopDetection,
+    running: &mut RunningRoutes,
+    changes: &mut Vec<StatusChange>,
+) -> Result<(), actix_web::Error> {
+    let stops = running.stops.get_mut(&motion.route_id).map(Vec::as_mut_slice).unwrap_or_default();
+
+    let planned = stops
+        .iter()
+        .enumerate()
+        .filter(|(_, stop)| stop.actual_departure.is_none())
+        .filter(|(_, stop)| settings.within_radius(motion.latitude, motion.longitude, stop.latitude, stop.longitude))
+        .min_by(|(_, a), (_, b)| {
+            let to_a = haversine_distance(motion.latitude, motion.longitude, a.latitude, a.longitude);
+            let to_b = haversine_distance(motion.latitude, motion.longitude, b.latitude, b.longitude);
+            to_a.total_cmp(&to_b)
+        })
+        .map(|(index, _)| index);
+
+    let detected_stop_id = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        INSERT INTO detected_stops (route_id, route_stop_id, vehicle_id, driver_id, latitude, longitude, arrived_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id
+        "#
+    )
+    .bind(motion.route_id)
+    .bind(planned.map(|index| stops[index].id))
+    .bind(motion.vehicle_id)
+    .bind(driver_id)
+    .bind(motion.latitude)
+    .bind(motion.longitude)
+    .bind(motion.since)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    motion.detected_stop_id = Some(detected_stop_id);
+
+    let Some(index) = planned else {
+        return Ok(());
+    };
+
+    // A driver may already have marked the arrival by hand
+    if stops[index].actual_arrival.is_none() {
+        stops[index] = record_arrival(&mut *conn, stops[index].id, motion.since).await?;
+    }
+
+    if settings.auto_complete_routes && index == stops.len() - 1 && complete_route(conn, motion.route_id, changes).await? {
+        running.stops.remove(&motion.route_id);
+    }
+
+    Ok(())
+}
+
+// The vehicle moved on from a detected stop; it left when it was last seen there
+async fn depart(
+    conn: &mut sqlx::PgConnection,
+    detected_stop_id: Uuid,
+    motion: &Motion,
+    running: &mut RunningRoutes,
+    changes: &mut Vec<StatusChange>,
+) -> Result<(), actix_web::Error> {
+    let route_stop_id = sqlx::query_scalar::<_, Option<Uuid>>(
+        "UPDATE detected_stops SET departed_at = $2 WHERE id = $1 RETURNING route_stop_id"
+    )
+    .bind(detected_stop_id)
+    .bind(motion.last_seen_at)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .flatten();
+
+    let Some(route_stop_id) = route_stop_id else {
+        return Ok(());
+    };
+
+    let Some(stop) = running
+        .stops
+        .get_mut(&motion.route_id)
+        .and_then(|stops| stops.iter_mut().find(|stop| stop.id == route_stop_id))
+    else {
+        return Ok(());
+    };
+
+    if stop.actual_arrival.is_some() && stop.actual_departure.is_none() {
+        *stop = record_departure(conn, stop, motion.last_seen_at, None, changes).await?;
+    }
+
+    Ok(())
+}
+
+// Complete a route on arrival at its last stop. Failing to (say, because its vehicle was moved
+// to maintenance meanwhile) leaves the route running rather than losing the location batch.
+async fn complete_route(
+    conn: &mut sqlx::PgConnection,
+    route_id: Uuid,
+    changes: &mut Vec<StatusChange>,
+) -> Result<bool, actix_web::Error> {
+    let mut savepoint = conn.begin().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let route = lock_route(&mut savepoint, route_id).await?;
+    let details = CargoEventDetails {
+        route_id: Some(route.id),
+        note: Some("Route completed on arrival at the last stop".to_string()),
+        ..Default::default()
+    };
+
+    let mut completed = Vec::new();
+    match finish_route(&mut savepoint, &route, &details, &mut completed).await {
+        Ok(_) => {
+            savepoint.commit().await.map_err(|e| {
+                eprintln!("Database error: {}", e);
+                actix_web::error::ErrorInternalServerError("Database error")
+            })?;
+            changes.extend(completed);
+            Ok(true)
+        }
+        Err(e) => {
+            eprintln!("Failed to complete route {} on arrival: {}", route_id, e);
+            savepoint.rollback().await.map_err(|e| {
+                eprintln!("Database error: {}", e);
+                actix_web::error::ErrorInternalServerError("Database error")
+            })?;
+            Ok(false)
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Trip statistics from location history: distance driven, time moving and stopped, and speeds.
+// Recorded for each route on completion and computed on request for a vehicle's days.
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::TryStreamExt;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+use crate::handlers::routes::haversine_distance;
+use crate::models::{Route, RouteStatistics, VehicleDayStatistics};
+
+// Between two fixes averaging at least this speed the vehicle counts as moving
+const MOVING_SPEED_KMH: f64 = 3.0;
+
+#[derive(Debug, Default)]
+pub struct TripSummary {
+    // Set when summarizing by day
+    pub day: Option<NaiveDate>,
+    pub distance_km: f64,
+    pub moving_secs: i64,
+    pub stopped_secs: i64,
+    pub max_speed_kmh: f64,
+    pub location_count: i64,
+    pub route_count: i64,
+    pub first_at: Option<DateTime<Utc>>,
+    pub last_at: Option<DateTime<Utc>>,
+}
+
+impl TripSummary {
+    pub fn avg_speed_kmh(&self) -> f64 {
+        if self.moving_secs > 0 {
+            self.distance_km / (self.moving_secs as f64 / 3600.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TrackPoint {
+    route_id: Uuid,
+    latitude: f64,
+    longitude: f64,
+    speed: f64,
+    timestamp: DateTime<Utc>,
+}
+
+// Summarize the locations of a route or of a vehicle in [start, end), as a whole or per UTC day.
+// Rows are streamed in timestamp order and folded one at a time, so long histories are never
+// loaded at once. Consecutive locations of different routes are not joined up, so driving between
+// routes untracked adds neither distance nor time. By day, a leg across midnight counts towards
+// the day it ends on, including a leg from the last fix before `start` into the range.
+async fn summarize(
+    conn: &mut sqlx::PgConnection,
+    route_id: Option<Uuid>,
+    vehicle_id: Option<Uuid>,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    by_day: bool,
+) -> Result<Vec<TripSummary>, actix_web::Error> {
+    let mut points = sqlx::query_as::<_, TrackPoint>(
+        r#"
+        (
+            SELECT route_id, latitude, longitude, speed, timestamp, id FROM locations
+            WHERE $5 AND ($1::uuid IS NULL OR route_id = $1)
+              AND ($2::uuid IS NULL OR vehicle_id = $2)
+              AND timestamp < $3
+            ORDER BY timestamp DESC, id DESC
+            LIMIT 1
+        )
+        UNION ALL
+        (
+            SELECT route_id, latitude, longitude, speed, timestamp, id FROM locations
+            WHERE ($1::uuid IS NULL OR route_id = $1)
+              AND ($2::uuid IS NULL OR vehicle_id = $2)
+              AND ($3::timestamptz IS NULL OR timestamp >= $3)
+              AND ($4::timestamptz IS NULL OR timestamp < $4)
+        )
+        ORDER BY timestamp, id
+        "#
+    )
+    .bind(route_id)
+    .bind(vehicle_id)
+    .bind(range.map(|(start, _)| start))
+    .bind(range.map(|(_, end)| end))
+    .bind(by_day)
+    .fetch(conn);
+
+    let mut summaries: Vec<TripSummary> = Vec::new();
+    let mut routes = HashSet::new();
+    let mut previous: Option<TrackPoint> = None;
+
+    while let Some(point) = points.try_next().await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })? {
+        // The fix before the range only starts the first leg
+        if range.is_some_and(|(start, _)| point.timestamp < start) {
+            previous = Some(point);
+            continue;
+        }
+
+        let day = by_day.then(|| point.timestamp.date_naive());
+        if summaries.last().is_none_or(|summary| summary.day != day) {
+            routes.clear();
+            summaries.push(TripSummary {
+                day,
+                first_at: Some(point.timestamp),
+                ..TripSummary::default()
+            });
+        }
+        let summary = summaries.last_mut().expect("pushed above");
+
+        if let Some(previous) = previous.as_ref().filter(|previous| previous.route_id == point.route_id) {
+            summary.distance_km +=
+                haversine_distance(previous.latitude, previous.longitude, point.latitude, point.longitude);
+
+            let elapsed = (point.timestamp - previous.timestamp).num_seconds();
+            if (previous.speed + point.speed) / 2.0 >= MOVING_SPEED_KMH {
+                summary.moving_secs += elapsed;
+            } else {
+                summary.stopped_secs += elapsed;
+            }
+        }
+
+        routes.insert(point.route_id);
+        summary.route_count = routes.len() as i64;
+        summary.location_count += 1;
+        summary.max_speed_kmh = summary.max_speed_kmh.max(point.speed);
+        summary.last_at = Some(point.timestamp);
+        previous = Some(point);
+    }
+
+    Ok(summaries)
+}
+
+// Summarize a route that is being completed, inside the caller's transaction. Completing is final,
+// but recording again simply replaces the earlier numbers.
+pub async fn record_route_statistics(
+    conn: &mut sqlx::PgConnection,
+    route: &Route,
+) -> Result<RouteStatistics, actix_web::Error> {
+    let summary = summarize(&mut *conn, Some(route.id), None, None, false)
+        .await?
+        .pop()
+        .unwrap_or_default();
+
+    let stop_count = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM detected_stops WHERE route_id = $1"
+    )
+    .bind(route.id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    let duration = match (route.started_at, route.completed_at, summary.first_at, summary.last_at) {
+        (Some(started_at), Some(completed_at), _, _) => completed_at - started_at,
+        (_, _, Some(first_at), Some(last_at)) => last_at - first_at,
+        _ => chrono::Duration::zero(),
+    };
+
+    sqlx::query_as::<_, RouteStatistics>(
+        r#"
+        INSERT INTO route_statistics (
+            route_id, vehicle_id, driver_id, distance_km, planned_distance_km, duration_secs, planned_duration_secs,
+            moving_secs, stopped_secs, max_speed_kmh, avg_speed_kmh, stop_count, location_count
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        ON CONFLICT (route_id) DO UPDATE
+        SET distance_km = EXCLUDED.distance_km,
+            planned_distance_km = EXCLUDED.planned_distance_km,
+            duration_secs = EXCLUDED.duration_secs,
+            planned_duration_secs = EXCLUDED.planned_duration_secs,
+            moving_secs = EXCLUDED.moving_secs,
+            stopped_secs = EXCLUDED.stopped_secs,
+            max_speed_kmh = EXCLUDED.max_speed_kmh,
+            avg_speed_kmh = EXCLUDED.avg_speed_kmh,
+            stop_count = EXCLUDED.stop_count,
+            location_count = EXCLUDED.location_count,
+            computed_at = NOW()
+        RETURNING *
+        "#
+    )
+    .bind(route.id)
+    .bind(route.vehicle_id)
+    .bind(route.driver_id)
+    .bind(summary.distance_km)
+    .bind(route.estimated_distance)
+    .bind(duration.num_seconds())
+    .bind(route.estimated_duration as i64 * 60)
+    .bind(summary.moving_secs)
+    .bind(summary.stopped_secs)
+    .bind(summary.max_speed_kmh)
+    .bind(summary.avg_speed_kmh())
+    .bind(stop_count)
+    .bind(summary.location_count)
+    .fetch_one(conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })
+}
+
+// One summary per UTC day from `from` to `to` inclusive on which the vehicle reported locations
+pub async fn vehicle_day_statistics(
+    conn: &mut sqlx::PgConnection,
+    vehicle_id: Uuid,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<VehicleDayStatistics>, actix_web::Error> {
+    let start = from.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc();
+    let end = to.succ_opt().unwrap_or(to).and_hms_opt(0, 0, 0).expect("midnight exists").and_utc();
+
+    let summaries = summarize(&mut *conn, None, Some(vehicle_id), Some((start, end)), true).await?;
+
+    let stop_counts: HashMap<NaiveDate, i64> = sqlx::query_as::<_, (NaiveDate, i64)>(
+        r#"
+        SELECT (arrived_at AT TIME ZONE 'UTC')::date, COUNT(*) FROM detected_stops
+        WHERE vehicle_id = $1 AND arrived_at >= $2 AND arrived_at < $3
+        GROUP BY 1
+        "#
+    )
+    .bind(vehicle_id)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?
+    .into_iter()
+    .collect();
+
+    let days = summaries
+        .into_iter()
+        .filter_map(|summary| {
+            let date = summary.day?;
+            Some(VehicleDayStatistics {
+                vehicle_id,
+                date,
+                distance_km: summary.distance_km,
+                moving_secs: summary.moving_secs,
+                stopped_secs: summary.stopped_secs,
+                max_speed_kmh: summary.max_speed_kmh,
+                avg_speed_kmh: summary.avg_speed_kmh(),
+                stop_count: stop_counts.get(&date).copied().unwrap_or(0),
+                location_count: summary.location_count,
+                route_count: summary.route_count,
+            })
+        })
+        .collect();
+
+    Ok(days)
+}
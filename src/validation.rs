@@ -0,0 +1,704 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::fmt;
+use uuid::Uuid;
+use crate::models::{
+    Cargo, CargoStatus, CreateGeofenceRequest, CreateInvitationRequest, CreateRouteStopRequest, GeofenceShape, SetRoutePathRequest,
+    SetSpeedLimitRequest, StopType, TrackingHistoryQuery, UserRole, Vehicle, VehicleStatus,
+};
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// Every problem found in a request, reported together as 422 Unprocessable Entity
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn has_errors_for(&self, field: &str) -> bool {
+        self.errors.iter().any(|error| error.field == field)
+    }
+
+    pub fn into_result(self) -> Result<(), actix_web::Error> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.into())
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Validation failed")?;
+        for error in &self.errors {
+            write!(f, "; {}: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl ResponseError for ValidationErrors {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNPROCESSABLE_ENTITY
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(serde_json::json!({
+            "error": "Validation failed",
+            "fields": self.errors,
+        }))
+    }
+}
+
+// What a route will use, in the shape `validate_route` needs
+pub struct RouteDraft<'a> {
+    pub vehicle_id: Uuid,
+    pub driver_id: Uuid,
+    pub stops: &'a [CreateRouteStopRequest],
+    // Set for single-leg requests so cargo errors point at `cargo_id` instead of generated stops
+    pub single_cargo_id: Option<Uuid>,
+}
+
+// Check a route before it is created and return the cargo it carries in loading order. Locks the
+// vehicle, driver and cargo rows so concurrent route creation cannot double-book them.
+pub async fn validate_route(
+    conn: &mut sqlx::PgConnection,
+    route: &RouteDraft<'_>,
+) -> Result<Vec<Uuid>, actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    let cargo_fields = validate_stop_sequence(route, &mut errors);
+    let window = route_window(route.stops);
+
+    let vehicle = sqlx::query_as::<_, Vehicle>(
+        "SELECT * FROM vehicles WHERE id = $1 FOR UPDATE"
+    )
+    .bind(route.vehicle_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match &vehicle {
+        None => errors.add("vehicle_id", "Vehicle not found"),
+        Some(vehicle) => {
+            match vehicle.status {
+                VehicleStatus::Maintenance => errors.add("vehicle_id", "Vehicle is in maintenance"),
+                VehicleStatus::OutOfService => errors.add("vehicle_id", "Vehicle is out of service"),
+                VehicleStatus::Available | VehicleStatus::InUse => {}
+            }
+
+            if vehicle.driver_id.is_some_and(|driver_id| driver_id != route.driver_id) {
+                errors.add("vehicle_id", "Vehicle is assigned to a different driver");
+            }
+        }
+    }
+
+    let driver_role = sqlx::query_scalar::<_, UserRole>(
+        "SELECT role FROM users WHERE id = $1 FOR UPDATE"
+    )
+    .bind(route.driver_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    match driver_role {
+        None => errors.add("driver_id", "User not found"),
+        Some(UserRole::Driver) => {}
+        Some(_) => errors.add("driver_id", "User is not a driver"),
+    }
+
+    // Routes without planned times occupy the vehicle and driver until they finish
+    let active_routes = sqlx::query_as::<_, (Uuid, Uuid, Uuid, Option<DateTime<Utc>>, Option<DateTime<Utc>>)>(
+        r#"
+        SELECT r.id, r.vehicle_id, r.driver_id,
+               MIN(LEAST(s.planned_arrival, s.planned_departure)),
+               MAX(GREATEST(s.planned_arrival, s.planned_departure))
+        FROM routes r
+        LEFT JOIN route_stops s ON s.route_id = r.id
+        WHERE r.status IN ('planned', 'inprogress') AND (r.vehicle_id = $1 OR r.driver_id = $2)
+        GROUP BY r.id, r.vehicle_id, r.driver_id
+        ORDER BY r.created_at
+        "#
+    )
+    .bind(route.vehicle_id)
+    .bind(route.driver_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    for (route_id, vehicle_id, driver_id, start, end) in active_routes {
+        if !windows_overlap(window, start.zip(end)) {
+            continue;
+        }
+
+        if vehicle_id == route.vehicle_id && !errors.has_errors_for("vehicle_id") {
+            errors.add("vehicle_id", format!("Vehicle is already scheduled on route {} at this time", route_id));
+        }
+        if driver_id == route.driver_id && !errors.has_errors_for("driver_id") {
+            errors.add("driver_id", format!("Driver is already scheduled on route {} at this time", route_id));
+        }
+    }
+
+    let cargo_ids: Vec<Uuid> = cargo_fields.iter().map(|(cargo_id, _)| *cargo_id).collect();
+    let cargo = sqlx::query_as::<_, Cargo>(
+        "SELECT * FROM cargo WHERE id = ANY($1) FOR UPDATE"
+    )
+    .bind(&cargo_ids)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        actix_web::error::ErrorInternalServerError("Database error")
+    })?;
+
+    for (cargo_id, field) in &cargo_fields {
+        match cargo.iter().find(|item| item.id == *cargo_id) {
+            None => errors.add(field.clone(), "Cargo not found"),
+            Some(item) if item.status != CargoStatus::Pending => {
+                errors.add(field.clone(), format!("Cargo is {:?}; only pending cargo can be routed", item.status));
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Some(vehicle) = &vehicle {
+        let load_field = if route.single_cargo_id.is_some() { "cargo_id" } else { "stops" };
+        validate_load(vehicle, &cargo, load_field, &mut errors);
+    }
+
+    errors.into_result()?;
+
+    Ok(cargo_ids)
+}
+
+// Everything on the route counts against capacity, whatever is unloaded along the way
+fn validate_load(vehicle: &Vehicle, cargo: &[Cargo], field: &str, errors: &mut ValidationErrors) {
+    let weight: f64 = cargo.iter().map(|item| item.weight).sum();
+    if weight > vehicle.capacity {
+        errors.add(field, format!(
+            "Total cargo weight {} exceeds vehicle capacity {}",
+            weight, vehicle.capacity
+        ));
+    }
+
+    if let Some(volume_capacity) = vehicle.volume_capacity {
+        let volume: f64 = cargo.iter().map(|item| item.volume).sum();
+        if volume > volume_capacity {
+            errors.add(field, format!(
+                "Total cargo volume {} exceeds vehicle volume capacity {}",
+                volume, volume_capacity
+            ));
+        }
+    }
+}
+
+// Windows sharing an instant overlap; a route without planned times overlaps everything
+fn windows_overlap(
+    window: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    other: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> bool {
+    match (window, other) {
+        (Some((window_start, window_end)), Some((start, end))) => start <= window_end && window_start <= end,
+        _ => true,
+    }
+}
+
+// Cargo is loaded once at a pickup and unloaded once at a later dropoff; breaks carry no cargo.
+// Returns each loaded cargo item with the field it was loaded from.
+fn validate_stop_sequence(route: &RouteDraft<'_>, errors: &mut ValidationErrors) -> Vec<(Uuid, String)> {
+    let stops = route.stops;
+    if stops.len() < 2 {
+        errors.add("stops", "A route needs at least two stops");
+    }
+
+    let cargo_field = |index: usize, list: &str, position: usize| match route.single_cargo_id {
+        Some(_) => "cargo_id".to_string(),
+        None => format!("stops[{}].{}[{}]", index, list, position),
+    };
+
+    let mut loaded: Vec<(Uuid, String)> = Vec::new();
+    let mut unloaded: Vec<Uuid> = Vec::new();
+
+    for (index, stop) in stops.iter().enumerate() {
+        if stop.address.trim().is_empty() {
+            errors.add(format!("stops[{}].address", index), "Address is required");
+        }
+        if !(-90.0..=90.0).contains(&stop.latitude) {
+            errors.add(format!("stops[{}].latitude", index), "Latitude must be between -90 and 90");
+        }
+        if !(-180.0..=180.0).contains(&stop.longitude) {
+            errors.add(format!("stops[{}].longitude", index), "Longitude must be between -180 and 180");
+        }
+
+        if let (Some(arrival), Some(departure)) = (stop.planned_arrival, stop.planned_departure) {
+            if departure < arrival {
+                errors.add(format!("stops[{}].planned_departure", index), "Departure must not be before arrival");
+            }
+        }
+
+        match stop.stop_type {
+            StopType::Break if !stop.load_cargo_ids.is_empty() || !stop.unload_cargo_ids.is_empty() => {
+                errors.add(format!("stops[{}]", index), "Break stops cannot load or unload cargo");
+            }
+            StopType::Pickup if !stop.unload_cargo_ids.is_empty() => {
+                errors.add(format!("stops[{}].unload_cargo_ids", index), "Cargo can only be unloaded at dropoff stops");
+            }
+            StopType::Dropoff if !stop.load_cargo_ids.is_empty() => {
+                errors.add(format!("stops[{}].load_cargo_ids", index), "Cargo can only be loaded at pickup stops");
+            }
+            _ => {}
+        }
+
+        for (position, cargo_id) in stop.load_cargo_ids.iter().enumerate() {
+            if loaded.iter().any(|(loaded_id, _)| loaded_id == cargo_id) {
+                errors.add(cargo_field(index, "load_cargo_ids", position), "Cargo is loaded more than once");
+            } else {
+                loaded.push((*cargo_id, cargo_field(index, "load_cargo_ids", position)));
+            }
+        }
+
+        for (position, cargo_id) in stop.unload_cargo_ids.iter().enumerate() {
+            let field = cargo_field(index, "unload_cargo_ids", position);
+            if !loaded.iter().any(|(loaded_id, _)| loaded_id == cargo_id) {
+                errors.add(field, "Cargo is unloaded before it is loaded");
+            } else if unloaded.contains(cargo_id) {
+                errors.add(field, "Cargo is unloaded more than once");
+            } else {
+                unloaded.push(*cargo_id);
+            }
+        }
+    }
+
+    for (cargo_id, field) in &loaded {
+        if !unloaded.contains(cargo_id) {
+            errors.add(field.clone(), "Cargo is never unloaded");
+        }
+    }
+
+    // Planned times must not go backwards from one stop to the next
+    let mut previous: Option<DateTime<Utc>> = None;
+    for (index, stop) in stops.iter().enumerate() {
+        let first = stop.planned_arrival.or(stop.planned_departure);
+        if let (Some(previous), Some(first)) = (previous, first) {
+            if first < previous {
+                errors.add(format!("stops[{}]", index), "Planned times must follow the stop order");
+            }
+        }
+        if let Some(last) = stop.planned_departure.or(stop.planned_arrival) {
+            previous = Some(last);
+        }
+    }
+
+    loaded
+}
+
+// Earliest and latest planned time on the route, or None when no stop is scheduled
+fn route_window(stops: &[CreateRouteStopRequest]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let times = stops
+        .iter()
+        .flat_map(|stop| [stop.planned_arrival, stop.planned_departure])
+        .flatten();
+
+    times.fold(None, |window, time| match window {
+        None => Some((time, time)),
+        Some((start, end)) => Some((start.min(time), end.max(time))),
+    })
+}
+
+// A circle needs a center and a positive radius, a polygon at least three vertices
+pub fn validate_geofence(geofence: &CreateGeofenceRequest) -> Result<(), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    if geofence.name.trim().is_empty() {
+        errors.add("name", "Name is required");
+    }
+    if geofence.dwell_seconds.is_some_and(|dwell| dwell <= 0) {
+        errors.add("dwell_seconds", "Dwell time must be positive");
+    }
+    if geofence.speed_limit_kmh.is_some_and(|limit| limit <= 0.0) {
+        errors.add("speed_limit_kmh", "Speed limit must be positive");
+    }
+
+    match geofence.shape {
+        GeofenceShape::Circle => {
+            match geofence.center_latitude {
+                None => errors.add("center_latitude", "Center latitude is required for a circle"),
+                Some(latitude) if !(-90.0..=90.0).contains(&latitude) => {
+                    errors.add("center_latitude", "Latitude must be between -90 and 90");
+                }
+                Some(_) => {}
+            }
+            match geofence.center_longitude {
+                None => errors.add("center_longitude", "Center longitude is required for a circle"),
+                Some(longitude) if !(-180.0..=180.0).contains(&longitude) => {
+                    errors.add("center_longitude", "Longitude must be between -180 and 180");
+                }
+                Some(_) => {}
+            }
+            match geofence.radius_meters {
+                None => errors.add("radius_meters", "Radius is required for a circle"),
+                Some(radius) if radius <= 0.0 => errors.add("radius_meters", "Radius must be positive"),
+                Some(_) => {}
+            }
+            if geofence.vertices.is_some() {
+                errors.add("vertices", "A circle has no vertices");
+            }
+        }
+        GeofenceShape::Polygon => {
+            match &geofence.vertices {
+                None => errors.add("vertices", "Vertices are required for a polygon"),
+                Some(vertices) if vertices.len() < 3 => errors.add("vertices", "A polygon needs at least three vertices"),
+                Some(vertices) => {
+                    for (index, vertex) in vertices.iter().enumerate() {
+                        if !(-90.0..=90.0).contains(&vertex.latitude) {
+                            errors.add(format!("vertices[{}].latitude", index), "Latitude must be between -90 and 90");
+                        }
+                        if !(-180.0..=180.0).contains(&vertex.longitude) {
+                            errors.add(format!("vertices[{}].longitude", index), "Longitude must be between -180 and 180");
+                        }
+                    }
+                }
+            }
+            if geofence.center_latitude.is_some() || geofence.center_longitude.is_some() || geofence.radius_meters.is_some() {
+                errors.add("shape", "A polygon has no center or radius");
+            }
+        }
+    }
+
+    errors.into_result()
+}
+
+pub fn validate_speed_limit(limit: &SetSpeedLimitRequest) -> Result<(), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    if !limit.speed_limit_kmh.is_finite() || limit.speed_limit_kmh <= 0.0 {
+        errors.add("speed_limit_kmh", "Speed limit must be positive");
+    }
+
+    errors.into_result()
+}
+
+pub fn validate_route_path(path: &SetRoutePathRequest) -> Result<(), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    if path.points.len() < 2 {
+        errors.add("points", "A path needs at least two points");
+    }
+    for (index, point) in path.points.iter().enumerate() {
+        if !(-90.0..=90.0).contains(&point.latitude) {
+            errors.add(format!("points[{}].latitude", index), "Latitude must be between -90 and 90");
+        }
+        if !(-180.0..=180.0).contains(&point.longitude) {
+            errors.add(format!("points[{}].longitude", index), "Longitude must be between -180 and 180");
+        }
+    }
+
+    errors.into_result()
+}
+
+// Largest page of tracking history one request may ask for
+const MAX_HISTORY_PAGE: i64 = 10000;
+
+pub fn validate_history_query(query: &TrackingHistoryQuery) -> Result<(), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    if query.limit.is_some_and(|limit| !(1..=MAX_HISTORY_PAGE).contains(&limit)) {
+        errors.add("limit", format!("Limit must be between 1 and {}", MAX_HISTORY_PAGE));
+    }
+    if query.bucket_secs.is_some_and(|bucket| bucket < 1) {
+        errors.add("bucket_secs", "Bucket must be at least one second");
+    }
+    if query.tolerance_meters.is_some_and(|tolerance| !tolerance.is_finite() || tolerance <= 0.0) {
+        errors.add("tolerance_meters", "Tolerance must be positive");
+    }
+
+    errors.into_result()
+}
+
+// Longest an invitation may stay valid
+const MAX_INVITATION_HOURS: i64 = 30 * 24;
+
+pub fn validate_invitation(invitation: &CreateInvitationRequest) -> Result<(), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    if invitation.expires_in_hours.is_some_and(|hours| !(1..=MAX_INVITATION_HOURS).contains(&hours)) {
+        errors.add("expires_in_hours", format!("Expiry must be between 1 and {} hours", MAX_INVITATION_HOURS));
+    }
+
+    errors.into_result()
+}
+
+// Longest range of days whose statistics one request may ask for
+const MAX_STATISTICS_DAYS: i64 = 92;
+
+pub fn validate_statistics_range(from: NaiveDate, to: NaiveDate) -> Result<(), actix_web::Error> {
+    let mut errors = ValidationErrors::default();
+
+    if from > to {
+        errors.add("from", "From must not be after to");
+    } else if (to - from).num_days() >= MAX_STATISTICS_DAYS {
+        errors.add("to", format!("At most {} days can be requested at once", MAX_STATISTICS_DAYS));
+    }
+
+    errors.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use crate::models::CargoPriority;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, hour, 0, 0).unwrap()
+    }
+
+    fn stop(stop_type: StopType, load: &[Uuid], unload: &[Uuid]) -> CreateRouteStopRequest {
+        CreateRouteStopRequest {
+            stop_type,
+            address: "Dock 1".to_string(),
+            latitude: 52.0,
+            longitude: 4.0,
+            planned_arrival: None,
+            planned_departure: None,
+            load_cargo_ids: load.to_vec(),
+            unload_cargo_ids: unload.to_vec(),
+        }
+    }
+
+    fn draft(stops: &[CreateRouteStopRequest]) -> RouteDraft<'_> {
+        RouteDraft {
+            vehicle_id: Uuid::new_v4(),
+            driver_id: Uuid::new_v4(),
+            stops,
+            single_cargo_id: None,
+        }
+    }
+
+    fn sequence_errors(stops: &[CreateRouteStopRequest]) -> Vec<(String, String)> {
+        let mut errors = ValidationErrors::default();
+        validate_stop_sequence(&draft(stops), &mut errors);
+        errors.errors.into_iter().map(|error| (error.field, error.message)).collect()
+    }
+
+    fn vehicle(capacity: f64, volume_capacity: Option<f64>) -> Vehicle {
+        Vehicle {
+            id: Uuid::new_v4(),
+            license_plate: "AB-123-C".to_string(),
+            make: "Volvo".to_string(),
+            model: "FH".to_string(),
+            year: 2020,
+            capacity,
+            volume_capacity,
+            fuel_type: "diesel".to_string(),
+            vehicle_type: None,
+            status: VehicleStatus::Available,
+            driver_id: None,
+            created_at: at(0),
+            updated_at: at(0),
+        }
+    }
+
+    fn cargo(weight: f64, volume: f64) -> Cargo {
+        Cargo {
+            id: Uuid::new_v4(),
+            description: "Pallets".to_string(),
+            weight,
+            volume,
+            cargo_type: "general".to_string(),
+            priority: CargoPriority::Medium,
+            status: CargoStatus::Pending,
+            shipper_id: Uuid::new_v4(),
+            consignee_id: Uuid::new_v4(),
+            created_at: at(0),
+            updated_at: at(0),
+        }
+    }
+
+    fn load_errors(vehicle: &Vehicle, cargo: &[Cargo]) -> Vec<String> {
+        let mut errors = ValidationErrors::default();
+        validate_load(vehicle, cargo, "stops", &mut errors);
+        errors.errors.into_iter().map(|error| error.message).collect()
+    }
+
+    #[test]
+    fn pickup_then_dropoff_is_valid() {
+        let cargo_id = Uuid::new_v4();
+        let stops = [stop(StopType::Pickup, &[cargo_id], &[]), stop(StopType::Dropoff, &[], &[cargo_id])];
+
+        let mut errors = ValidationErrors::default();
+        let loaded = validate_stop_sequence(&draft(&stops), &mut errors);
+
+        assert!(errors.errors.is_empty());
+        assert_eq!(loaded, vec![(cargo_id, "stops[0].load_cargo_ids[0]".to_string())]);
+    }
+
+    #[test]
+    fn a_single_stop_is_not_a_route() {
+        let errors = sequence_errors(&[stop(StopType::Pickup, &[], &[])]);
+
+        assert_eq!(errors, vec![("stops".to_string(), "A route needs at least two stops".to_string())]);
+    }
+
+    #[test]
+    fn coordinates_on_the_bounds_are_accepted_and_beyond_them_rejected() {
+        let mut edge = stop(StopType::Pickup, &[], &[]);
+        edge.latitude = -90.0;
+        edge.longitude = 180.0;
+        let mut beyond = stop(StopType::Dropoff, &[], &[]);
+        beyond.latitude = 90.5;
+        beyond.longitude = -180.5;
+
+        let fields: Vec<String> = sequence_errors(&[edge, beyond]).into_iter().map(|(field, _)| field).collect();
+
+        assert_eq!(fields, vec!["stops[1].latitude", "stops[1].longitude"]);
+    }
+
+    #[test]
+    fn cargo_must_be_loaded_before_and_unloaded_exactly_once() {
+        let early = Uuid::new_v4();
+        let twice = Uuid::new_v4();
+        let never = Uuid::new_v4();
+        let stops = [
+            stop(StopType::Dropoff, &[], &[early]),
+            stop(StopType::Pickup, &[early, twice, never], &[]),
+            stop(StopType::Dropoff, &[], &[early, twice, twice]),
+        ];
+
+        let errors = sequence_errors(&stops);
+
+        assert!(errors.contains(&(
+            "stops[0].unload_cargo_ids[0]".to_string(),
+            "Cargo is unloaded before it is loaded".to_string(),
+        )));
+        assert!(errors.contains(&(
+            "stops[2].unload_cargo_ids[2]".to_string(),
+            "Cargo is unloaded more than once".to_string(),
+        )));
+        assert!(errors.contains(&(
+            "stops[1].load_cargo_ids[2]".to_string(),
+            "Cargo is never unloaded".to_string(),
+        )));
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn stop_types_restrict_what_is_handled_there() {
+        let cargo_id = Uuid::new_v4();
+        let stops = [
+            stop(StopType::Dropoff, &[cargo_id], &[]),
+            stop(StopType::Break, &[], &[cargo_id]),
+        ];
+
+        let fields: Vec<String> = sequence_errors(&stops).into_iter().map(|(field, _)| field).collect();
+
+        assert!(fields.contains(&"stops[0].load_cargo_ids".to_string()));
+        assert!(fields.contains(&"stops[1]".to_string()));
+    }
+
+    #[test]
+    fn single_leg_cargo_errors_point_at_cargo_id() {
+        let cargo_id = Uuid::new_v4();
+        let stops = [stop(StopType::Pickup, &[cargo_id], &[]), stop(StopType::Dropoff, &[], &[])];
+        let route = RouteDraft { single_cargo_id: Some(cargo_id), ..draft(&stops) };
+
+        let mut errors = ValidationErrors::default();
+        validate_stop_sequence(&route, &mut errors);
+
+        assert!(errors.has_errors_for("cargo_id"));
+    }
+
+    #[test]
+    fn planned_times_must_follow_the_stop_order() {
+        let mut first = stop(StopType::Pickup, &[], &[]);
+        first.planned_arrival = Some(at(10));
+        first.planned_departure = Some(at(9));
+        let mut second = stop(StopType::Dropoff, &[], &[]);
+        second.planned_arrival = Some(at(9));
+
+        let fields: Vec<String> = sequence_errors(&[first, second]).into_iter().map(|(field, _)| field).collect();
+
+        // The next stop is compared with the departure, so only the departure itself is reported
+        assert_eq!(fields, vec!["stops[0].planned_departure"]);
+    }
+
+    #[test]
+    fn route_window_spans_every_planned_time() {
+        let mut first = stop(StopType::Pickup, &[], &[]);
+        first.planned_departure = Some(at(8));
+        let unplanned = stop(StopType::Break, &[], &[]);
+        let mut last = stop(StopType::Dropoff, &[], &[]);
+        last.planned_arrival = Some(at(14));
+
+        assert_eq!(route_window(&[first, unplanned, last]), Some((at(8), at(14))));
+        assert_eq!(route_window(&[stop(StopType::Pickup, &[], &[])]), None);
+    }
+
+    #[test]
+    fn windows_touching_at_an_instant_overlap() {
+        let morning = Some((at(8), at(12)));
+
+        assert!(windows_overlap(morning, Some((at(12), at(16)))));
+        assert!(!windows_overlap(morning, Some((at(12) + Duration::seconds(1), at(16)))));
+        assert!(windows_overlap(morning, None));
+        assert!(windows_overlap(None, Some((at(20), at(21)))));
+    }
+
+    #[test]
+    fn load_up_to_the_capacity_fits() {
+        let vehicle = vehicle(3.0, Some(10.0));
+
+        assert!(load_errors(&vehicle, &[cargo(1.0, 5.0), cargo(2.0, 5.0)]).is_empty());
+        assert!(load_errors(&vehicle, &[]).is_empty());
+    }
+
+    #[test]
+    fn weight_and_volume_are_summed_over_all_cargo() {
+        let vehicle = vehicle(3.0, Some(10.0));
+
+        let errors = load_errors(&vehicle, &[cargo(2.0, 6.0), cargo(2.0, 6.0)]);
+
+        assert_eq!(errors, vec![
+            "Total cargo weight 4 exceeds vehicle capacity 3".to_string(),
+            "Total cargo volume 12 exceeds vehicle volume capacity 10".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn volume_is_unlimited_without_a_volume_capacity() {
+        assert!(load_errors(&vehicle(3.0, None), &[cargo(1.0, 1000.0)]).is_empty());
+    }
+
+    #[test]
+    fn statistics_range_is_inclusive_and_bounded() {
+        let day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+
+        assert!(validate_statistics_range(day, day).is_ok());
+        assert!(validate_statistics_range(day, day + Duration::days(MAX_STATISTICS_DAYS - 1)).is_ok());
+        assert!(validate_statistics_range(day, day + Duration::days(MAX_STATISTICS_DAYS)).is_err());
+        assert!(validate_statistics_range(day + Duration::days(1), day).is_err());
+    }
+}
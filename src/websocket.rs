@@ -1,168 +1,1014 @@
-use actix::{Actor, StreamHandler, Handler, Message};
-use actix_web_actors::ws;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use uuid::Uuid;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LocationUpdate {
-    pub route_id: Uuid,
-    pub vehicle_id: Uuid,
-    pub driver_id: Uuid,
-    pub latitude: f64,
-    pub longitude: f64,
-    pub speed: f64,
-    pub heading: f64,
-    pub timestamp: chrono::DateTime<chrono::Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    pub message_type: String,
-    pub data: serde_json::Value,
-}
-
-pub struct WebSocketSession {
-    pub id: Uuid,
-    pub route_id: Option<Uuid>,
-    pub vehicle_id: Option<Uuid>,
-    pub driver_id: Option<Uuid>,
-}
-
-impl Actor for WebSocketSession {
-    type Context = ws::WebsocketContext<Self>;
-
-    fn started(&mut self, ctx: &mut Self::Context) {
-        println!("WebSocket session started: {}", self.id);
-    }
-
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
-        println!("WebSocket session stopped: {}", self.id);
-    }
-}
-
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
-    fn handle(
-        &mut self,
-        msg: Result<ws::Message, ws::ProtocolError>,
-        ctx: &mut Self::Context,
-    ) {
-        match msg {
-            Ok(ws::Message::Text(text)) => {
-                if let Ok(message) = serde_json::from_str::<WebSocketMessage>(&text) {
-                    match message.message_type.as_str() {
-                        "subscribe_route" => {
-                            if let Some(route_id) = message.data.get("route_id") {
-                                if let Some(route_uuid) = route_id.as_str().and_then(|s| Uuid::parse_str(s).ok()) {
-                                    self.route_id = Some(route_uuid);
-                                    println!("Session {} subscribed to route {}", self.id, route_uuid);
-                                }
-                            }
-                        }
-                        "subscribe_vehicle" => {
-                            if let Some(vehicle_id) = message.data.get("vehicle_id") {
-                                if let Some(vehicle_uuid) = vehicle_id.as_str().and_then(|s| Uuid::parse_str(s).ok()) {
-                                    self.vehicle_id = Some(vehicle_uuid);
-                                    println!("Session {} subscribed to vehicle {}", self.id, vehicle_uuid);
-                                }
-                            }
-                        }
-                        "subscribe_driver" => {
-                            if let Some(driver_id) = message.data.get("driver_id") {
-                                if let Some(driver_uuid) = driver_id.as_str().and_then(|s| Uuid::parse_str(s).ok()) {
-                                    self.driver_id = Some(driver_uuid);
-                                    println!("Session {} subscribed to driver {}", self.id, driver_uuid);
-                                }
-                            }
-                        }
-                        _ => {
-                            println!("Unknown message type: {}", message.message_type);
-                        }
-                    }
-                }
-            }
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Close(reason)) => ctx.close(reason),
-            _ => (),
-        }
-    }
-}
-
-// Global session manager
-pub struct SessionManager {
-    sessions: Arc<Mutex<HashMap<Uuid, actix::Addr<WebSocketSession>>>>,
-}
-
-impl SessionManager {
-    pub fn new() -> Self {
-        Self {
-            sessions: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    pub fn add_session(&self, id: Uuid, addr: actix::Addr<WebSocketSession>) {
-        if let Ok(mut sessions) = self.sessions.lock() {
-            sessions.insert(id, addr);
-        }
-    }
-
-    pub fn remove_session(&self, id: &Uuid) {
-        if let Ok(mut sessions) = self.sessions.lock() {
-            sessions.remove(id);
-        }
-    }
-
-    pub fn broadcast_location_update(&self, update: &LocationUpdate) {
-        if let Ok(sessions) = self.sessions.lock() {
-            let message = serde_json::to_string(&WebSocketMessage {
-                message_type: "location_update".to_string(),
-                data: serde_json::to_value(update).unwrap(),
-            }).unwrap();
-
-            for (session_id, addr) in sessions.iter() {
-                // Send to sessions subscribed to this route, vehicle, or driver
-                if let Some(route_id) = update.route_id {
-                    if let Some(session) = sessions.get(&session_id) {
-                        if let Some(session_route_id) = session.route_id {
-                            if session_route_id == route_id {
-                                session.do_send(ws::Message::Text(message.clone()));
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
-impl Clone for SessionManager {
-    fn clone(&self) -> Self {
-        Self {
-            sessions: Arc::clone(&self.sessions),
-        }
-    }
-}
-
-pub async fn ws_index(
-    req: actix_web::HttpRequest,
-    stream: actix_web::web::Payload,
-    session_manager: actix_web::web::Data<SessionManager>,
-) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    let session_id = Uuid::new_v4();
-    let session = WebSocketSession {
-        id: session_id,
-        route_id: None,
-        vehicle_id: None,
-        driver_id: None,
-    };
-
-    let session_manager = session_manager.into_inner().clone();
-    
-    let resp = ws::start(session, &req, stream)?;
-    
-    // Add session to manager
-    session_manager.add_session(session_id, resp.1.clone());
-    
-    Ok(resp.0)
-}
+use actix::dev::SendError;
+use actix::{Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, StreamHandler, Handler, Message, WrapFuture};
+use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+use crate::auth::AuthUser;
+use crate::config::Config;
+use crate::database::DbPool;
+use crate::ingest::{IngestLocation, LocationWriter};
+use crate::events::{SequencedEvent, StatusChange, TrackingEvent};
+use crate::models::{Alert, GeofenceEvent, Location, RouteDeviationEvent, RouteEta, UpdateLocationRequest, UserRole};
+
+// Bumped on incompatible protocol changes; every frame in either direction carries it as `v`
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Close code telling clients to refresh their access token before reconnecting
+pub const AUTH_FAILED_CLOSE_CODE: u16 = 4001;
+
+// How long a connection may stay open without authenticating
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the hub retries frames held back for sessions that fell behind
+const OUTBOX_DRAIN_INTERVAL: Duration = Duration::from_millis(250);
+
+// What a session can follow; `Fleet` receives everything. Sent as `{"kind": "route", "id": "..."}`
+// or `{"kind": "fleet"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum Topic {
+    Fleet,
+    Route(Uuid),
+    Vehicle(Uuid),
+    Driver(Uuid),
+}
+
+impl Topic {
+    fn matches_location(&self, location: &Location) -> bool {
+        match self {
+            Topic::Fleet => true,
+            Topic::Route(route_id) => *route_id == location.route_id,
+            Topic::Vehicle(vehicle_id) => *vehicle_id == location.vehicle_id,
+            Topic::Driver(driver_id) => *driver_id == location.driver_id,
+        }
+    }
+
+    fn matches(&self, event: &TrackingEvent) -> bool {
+        match event {
+            TrackingEvent::Location { location } => self.matches_location(location),
+            TrackingEvent::StatusChange { change } => self.matches_change(change),
+            TrackingEvent::Geofence { event } => self.matches_geofence_event(event),
+            TrackingEvent::Alert { alert } => self.matches_alert(alert),
+            TrackingEvent::Deviation { event } => self.matches_deviation_event(event),
+            TrackingEvent::Eta { eta } => self.matches_eta(eta),
+        }
+    }
+
+    fn matches_geofence_event(&self, event: &GeofenceEvent) -> bool {
+        match self {
+            Topic::Fleet => true,
+            Topic::Route(route_id) => *route_id == event.route_id,
+            Topic::Vehicle(vehicle_id) => *vehicle_id == event.vehicle_id,
+            Topic::Driver(driver_id) => *driver_id == event.driver_id,
+        }
+    }
+
+    fn matches_alert(&self, alert: &Alert) -> bool {
+        match self {
+            Topic::Fleet => true,
+            Topic::Route(route_id) => *route_id == alert.route_id,
+            Topic::Vehicle(vehicle_id) => *vehicle_id == alert.vehicle_id,
+            Topic::Driver(driver_id) => *driver_id == alert.driver_id,
+        }
+    }
+
+    fn matches_deviation_event(&self, event: &RouteDeviationEvent) -> bool {
+        match self {
+            Topic::Fleet => true,
+            Topic::Route(route_id) => *route_id == event.route_id,
+            Topic::Vehicle(vehicle_id) => *vehicle_id == event.vehicle_id,
+            Topic::Driver(driver_id) => *driver_id == event.driver_id,
+        }
+    }
+
+    fn matches_eta(&self, eta: &RouteEta) -> bool {
+        match self {
+            Topic::Fleet => true,
+            Topic::Route(route_id) => *route_id == eta.route_id,
+            Topic::Vehicle(vehicle_id) => *vehicle_id == eta.vehicle_id,
+            Topic::Driver(driver_id) => *driver_id == eta.driver_id,
+        }
+    }
+
+    fn matches_change(&self, change: &StatusChange) -> bool {
+        match (self, change) {
+            (Topic::Fleet, _) => true,
+            (Topic::Route(id), StatusChange::Route { route_id, .. }) => id == route_id,
+            (Topic::Route(id), StatusChange::Cargo { route_id, .. }) => Some(*id) == *route_id,
+            (Topic::Vehicle(id), StatusChange::Route { vehicle_id, .. }) => id == vehicle_id,
+            (Topic::Vehicle(id), StatusChange::Vehicle { vehicle_id, .. }) => id == vehicle_id,
+            (Topic::Driver(id), StatusChange::Route { driver_id, .. }) => id == driver_id,
+            (Topic::Driver(id), StatusChange::Vehicle { driver_id, .. }) => Some(*id) == *driver_id,
+            _ => false,
+        }
+    }
+}
+
+// Client frame: `{"v": 1, "id": "...", "type": "subscribe", ...}`. The optional `id` is echoed
+// back in the `ack` or `error` answering it.
+#[derive(Debug, Deserialize)]
+pub struct ClientFrame {
+    #[serde(default = "protocol_version")]
+    pub v: u32,
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+fn protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Authenticate { token: String },
+    // `since` resumes after the last event seen, replaying what was missed in between
+    Subscribe {
+        topic: Topic,
+        #[serde(default)]
+        since: Option<i64>,
+    },
+    Unsubscribe { topic: Topic },
+    // A GPS fix from a driver, stored and published like `POST /api/tracking/location`
+    LocationUpdate { location: UpdateLocationRequest },
+}
+
+impl ClientMessage {
+    fn name(&self) -> &'static str {
+        match self {
+            ClientMessage::Authenticate { .. } => "authenticate",
+            ClientMessage::Subscribe { .. } => "subscribe",
+            ClientMessage::Unsubscribe { .. } => "unsubscribe",
+            ClientMessage::LocationUpdate { .. } => "location_update",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    UnsupportedVersion,
+    Unauthenticated,
+    Forbidden,
+    Internal,
+}
+
+// Server frame: `{"v": 1, "type": "ack", ...}`
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Hello { version: u32, authenticated: bool },
+    Ack { id: Option<String>, request: &'static str },
+    Error { id: Option<String>, code: ErrorCode, message: String },
+    LocationUpdate { seq: i64, location: &'a Location },
+    StatusChange { seq: i64, change: &'a StatusChange },
+    GeofenceEvent { seq: i64, event: &'a GeofenceEvent },
+    Alert { seq: i64, alert: &'a Alert },
+    RouteDeviation { seq: i64, event: &'a RouteDeviationEvent },
+    EtaUpdate { seq: i64, eta: &'a RouteEta },
+    // Events after `since` on `topic` are no longer buffered; refetch its state over HTTP
+    ResyncRequired { topic: Topic, since: i64 },
+}
+
+#[derive(Serialize)]
+struct ServerFrame<'a> {
+    v: u32,
+    #[serde(flatten)]
+    message: &'a ServerMessage<'a>,
+}
+
+impl<'a> ServerMessage<'a> {
+    fn to_frame(&self) -> Option<String> {
+        serde_json::to_string(&ServerFrame {
+            v: PROTOCOL_VERSION,
+            message: self,
+        })
+        .map_err(|e| eprintln!("Failed to serialize WebSocket message: {}", e))
+        .ok()
+    }
+
+    fn event(event: &'a SequencedEvent) -> Self {
+        match &event.event {
+            TrackingEvent::Location { location } => ServerMessage::LocationUpdate { seq: event.seq, location },
+            TrackingEvent::StatusChange { change } => ServerMessage::StatusChange { seq: event.seq, change },
+            TrackingEvent::Geofence { event: geofence_event } => {
+                ServerMessage::GeofenceEvent { seq: event.seq, event: geofence_event }
+            }
+            TrackingEvent::Alert { alert } => ServerMessage::Alert { seq: event.seq, alert },
+            TrackingEvent::Deviation { event: deviation_event } => {
+                ServerMessage::RouteDeviation { seq: event.seq, event: deviation_event }
+            }
+            TrackingEvent::Eta { eta } => ServerMessage::EtaUpdate { seq: event.seq, eta },
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Connect {
+    pub session_id: Uuid,
+    pub user: AuthUser,
+    pub sink: SessionSink,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub session_id: Uuid,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub session_id: Uuid,
+    pub topics: Vec<Topic>,
+    pub since: Option<i64>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub session_id: Uuid,
+    pub topic: Topic,
+}
+
+// An event to push to every session subscribed to what it concerns
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish(pub SequencedEvent);
+
+// Sent when the listener reconnects: events may have been missed, so nothing buffered before
+// now can be replayed as complete
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ResetHistory;
+
+// Sent when tokens were revoked: sessions opened with any of them are disconnected
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DropRevoked;
+
+// Answer to a client request completed outside the session, e.g. by the location writer
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reply {
+    pub id: Option<String>,
+    pub request: &'static str,
+    pub result: Result<(), (ErrorCode, String)>,
+}
+
+// Text frame the hub asks a session to send to its client
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Push(pub String);
+
+// Sent by the hub to a session that stopped reading before dropping it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Evict;
+
+// Sent by the hub to a session whose access token was revoked before dropping it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Revoked;
+
+// A serialized server message, with the sequence number of the event it carries if any
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub seq: Option<i64>,
+    pub text: String,
+}
+
+// Where the hub sends a session's frames
+pub enum SessionSink {
+    WebSocket(Addr<WebSocketSession>),
+    // A server-sent events response; dropping the sender ends it
+    EventStream(mpsc::Sender<Frame>),
+}
+
+enum SinkError {
+    Full(Frame),
+    Closed,
+}
+
+impl SessionSink {
+    // A full mailbox or channel means the client is not reading as fast as frames arrive
+    fn try_send(&self, frame: Frame) -> Result<(), SinkError> {
+        match self {
+            SessionSink::WebSocket(addr) => {
+                let seq = frame.seq;
+                addr.try_send(Push(frame.text)).map_err(|e| match e {
+                    SendError::Full(Push(text)) => SinkError::Full(Frame { seq, text }),
+                    SendError::Closed(_) => SinkError::Closed,
+                })
+            }
+            SessionSink::EventStream(sender) => sender.try_send(frame).map_err(|e| match e {
+                TrySendError::Full(frame) => SinkError::Full(frame),
+                TrySendError::Closed(_) => SinkError::Closed,
+            }),
+        }
+    }
+
+    fn evict(&self) {
+        if let SessionSink::WebSocket(addr) = self {
+            addr.do_send(Evict);
+        }
+    }
+
+    fn revoke(&self) {
+        if let SessionSink::WebSocket(addr) = self {
+            addr.do_send(Revoked);
+        }
+    }
+}
+
+// Frames held back while a session's sink is full. Location updates are coalesced to the
+// latest per vehicle; anything else is kept in order up to the configured limit.
+#[derive(Default)]
+struct Outbox {
+    frames: VecDeque<Frame>,
+    locations: HashMap<Uuid, Frame>,
+    // Last time a frame got through while frames were waiting
+    stalled_since: Option<Instant>,
+}
+
+impl Outbox {
+    fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.locations.is_empty()
+    }
+}
+
+struct HubSession {
+    sink: SessionSink,
+    user: AuthUser,
+    topics: HashSet<Topic>,
+    outbox: Outbox,
+}
+
+impl HubSession {
+    // Send a frame, or hold it back if the session's sink is full because its client is not
+    // keeping up. Returns false once the session cannot take any more.
+    fn deliver(&mut self, frame: Frame, vehicle_id: Option<Uuid>, max_frames: usize) -> bool {
+        if self.outbox.is_empty() {
+            match self.sink.try_send(frame) {
+                Ok(()) => return true,
+                Err(SinkError::Closed) => return false,
+                Err(SinkError::Full(frame)) => {
+                    self.outbox.stalled_since = Some(Instant::now());
+                    return self.hold(frame, vehicle_id, max_frames);
+                }
+            }
+        }
+
+        self.hold(frame, vehicle_id, max_frames)
+    }
+
+    fn hold(&mut self, frame: Frame, vehicle_id: Option<Uuid>, max_frames: usize) -> bool {
+        match vehicle_id {
+            Some(vehicle_id) => {
+                self.outbox.locations.insert(vehicle_id, frame);
+                true
+            }
+            None => {
+                self.outbox.frames.push_back(frame);
+                self.outbox.frames.len() <= max_frames
+            }
+        }
+    }
+
+    // Send held-back frames until the sink is full again. Returns false if the session is gone.
+    fn drain(&mut self) -> bool {
+        let mut sent = false;
+
+        while let Some(frame) = self.outbox.frames.pop_front() {
+            match self.sink.try_send(frame) {
+                Ok(()) => sent = true,
+                Err(SinkError::Closed) => return false,
+                Err(SinkError::Full(frame)) => {
+                    self.outbox.frames.push_front(frame);
+                    break;
+                }
+            }
+        }
+
+        if self.outbox.frames.is_empty() {
+            let vehicle_ids: Vec<Uuid> = self.outbox.locations.keys().copied().collect();
+            for vehicle_id in vehicle_ids {
+                let Some(frame) = self.outbox.locations.remove(&vehicle_id) else {
+                    continue;
+                };
+                match self.sink.try_send(frame) {
+                    Ok(()) => sent = true,
+                    Err(SinkError::Closed) => return false,
+                    Err(SinkError::Full(frame)) => {
+                        self.outbox.locations.insert(vehicle_id, frame);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.outbox.is_empty() {
+            self.outbox.stalled_since = None;
+        } else if sent {
+            self.outbox.stalled_since = Some(Instant::now());
+        }
+
+        true
+    }
+}
+
+// A published event kept for replay, with its frame serialized once
+struct RecordedEvent {
+    event: SequencedEvent,
+    frame: String,
+}
+
+// Pub/sub hub for live tracking; it owns every session's subscriptions so a publish only
+// reaches the sessions that asked for it
+pub struct TrackingHub {
+    sessions: HashMap<Uuid, HubSession>,
+    // The latest events, oldest first, for sessions resuming after a reconnect
+    history: VecDeque<RecordedEvent>,
+    history_size: usize,
+    // Events after this sequence number are all in `history`; None until the first event
+    replay_floor: Option<i64>,
+    // Frames other than location updates a session may fall behind by before it is dropped
+    send_buffer: usize,
+    // How long a session may go without accepting a frame before it is dropped
+    client_timeout: Duration,
+}
+
+impl TrackingHub {
+    pub fn new(config: &Config) -> Self {
+        TrackingHub {
+            sessions: HashMap::new(),
+            history: VecDeque::new(),
+            history_size: config.ws_replay_buffer,
+            replay_floor: None,
+            send_buffer: config.ws_send_buffer,
+            client_timeout: Duration::from_secs(config.ws_client_timeout_secs),
+        }
+    }
+
+    fn evict(&mut self, session_id: Uuid) {
+        if let Some(session) = self.sessions.remove(&session_id) {
+            println!("Dropping tracking session {} that stopped reading", session_id);
+            session.sink.evict();
+        }
+    }
+
+    fn drop_revoked(&mut self) {
+        let revoked: Vec<Uuid> = self.sessions.iter()
+            .filter(|(_, session)| session.user.is_revoked())
+            .map(|(session_id, _)| *session_id)
+            .collect();
+
+        for session_id in revoked {
+            if let Some(session) = self.sessions.remove(&session_id) {
+                println!("Dropping tracking session {} whose token was revoked", session_id);
+                session.sink.revoke();
+            }
+        }
+    }
+
+    fn record(&mut self, event: SequencedEvent, frame: String) {
+        if self.replay_floor.is_none() {
+            self.replay_floor = Some(event.seq - 1);
+        }
+
+        self.history.push_back(RecordedEvent { event, frame });
+        while self.history.len() > self.history_size {
+            if let Some(evicted) = self.history.pop_front() {
+                self.replay_floor = Some(evicted.event.seq);
+            }
+        }
+    }
+
+    // Send a resuming session the buffered events on `topics` it missed, each once, or tell it
+    // to resync when some of them are no longer buffered
+    fn replay(&mut self, session_id: Uuid, topics: &[Topic], since: i64) {
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            return;
+        };
+
+        let complete = self.replay_floor.is_some_and(|floor| since >= floor);
+        if !complete {
+            for &topic in topics {
+                if let Some(text) = (ServerMessage::ResyncRequired { topic, since }).to_frame() {
+                    session.deliver(Frame { seq: None, text }, None, self.send_buffer);
+                }
+            }
+        }
+
+        let mut alive = true;
+        for recorded in self.history.iter().filter(|recorded| recorded.event.seq > since) {
+            if !recorded.event.event.visible_to(&session.user)
+                || !topics.iter().any(|topic| topic.matches(&recorded.event.event))
+            {
+                continue;
+            }
+            let frame = Frame {
+                seq: Some(recorded.event.seq),
+                text: recorded.frame.clone(),
+            };
+            alive = session.deliver(frame, recorded.event.event.vehicle_id(), self.send_buffer);
+            if !alive {
+                break;
+            }
+        }
+
+        if !alive {
+            self.evict(session_id);
+        }
+    }
+
+    fn drain_outboxes(&mut self) {
+        let now = Instant::now();
+        let mut evicted = Vec::new();
+
+        for (session_id, session) in self.sessions.iter_mut() {
+            if session.outbox.is_empty() {
+                continue;
+            }
+            let stalled = session.outbox.stalled_since
+                .is_some_and(|since| now.duration_since(since) > self.client_timeout);
+            if !session.drain() || stalled {
+                evicted.push(*session_id);
+            }
+        }
+
+        for session_id in evicted {
+            self.evict(session_id);
+        }
+    }
+}
+
+impl Actor for TrackingHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(OUTBOX_DRAIN_INTERVAL, |act, _ctx| act.drain_outboxes());
+    }
+}
+
+impl Handler<Connect> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) {
+        self.sessions.insert(msg.session_id, HubSession {
+            sink: msg.sink,
+            user: msg.user,
+            topics: HashSet::new(),
+            outbox: Outbox::default(),
+        });
+        // The token may have been revoked after the session validated it
+        self.drop_revoked();
+    }
+}
+
+impl Handler<Disconnect> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) {
+        self.sessions.remove(&msg.session_id);
+    }
+}
+
+impl Handler<DropRevoked> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DropRevoked, _ctx: &mut Self::Context) {
+        self.drop_revoked();
+    }
+}
+
+impl Handler<Subscribe> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Self::Context) {
+        let Some(session) = self.sessions.get_mut(&msg.session_id) else {
+            return;
+        };
+
+        // Replay and subscribe in one step so nothing is missed or sent twice in between
+        let added: Vec<Topic> = msg.topics
+            .into_iter()
+            .filter(|topic| session.topics.insert(*topic))
+            .collect();
+        if let Some(since) = msg.since {
+            if !added.is_empty() {
+                self.replay(msg.session_id, &added, since);
+            }
+        }
+    }
+}
+
+impl Handler<Unsubscribe> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _ctx: &mut Self::Context) {
+        if let Some(session) = self.sessions.get_mut(&msg.session_id) {
+            session.topics.remove(&msg.topic);
+        }
+    }
+}
+
+impl Handler<Publish> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _ctx: &mut Self::Context) {
+        let event = msg.0;
+        let Some(frame) = ServerMessage::event(&event).to_frame() else {
+            return;
+        };
+
+        let mut evicted = Vec::new();
+        for (session_id, session) in self.sessions.iter_mut() {
+            if event.event.visible_to(&session.user)
+                && session.topics.iter().any(|topic| topic.matches(&event.event))
+                && !session.deliver(Frame { seq: Some(event.seq), text: frame.clone() }, event.event.vehicle_id(), self.send_buffer)
+            {
+                evicted.push(*session_id);
+            }
+        }
+
+        for session_id in evicted {
+            self.evict(session_id);
+        }
+
+        self.record(event, frame);
+    }
+}
+
+impl Handler<ResetHistory> for TrackingHub {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ResetHistory, _ctx: &mut Self::Context) {
+        self.history.clear();
+        self.replay_floor = None;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsAuthQuery {
+    pub token: Option<String>,
+}
+
+pub struct WebSocketSession {
+    pub id: Uuid,
+    pub hub: Addr<TrackingHub>,
+    pub writer: Addr<LocationWriter>,
+    pub pool: DbPool,
+    pub config: actix_web::web::Data<Config>,
+    // None until the client authenticates with its first message
+    pub user: Option<AuthUser>,
+    pub heartbeat_interval: Duration,
+    pub client_timeout: Duration,
+    pub send_buffer: usize,
+    // Last time anything, including a pong, arrived from the client
+    pub last_seen: Instant,
+}
+
+impl Actor for WebSocketSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        println!("WebSocket session started: {}", self.id);
+        // A full mailbox is how the hub notices the client is not reading
+        ctx.set_mailbox_capacity(self.send_buffer);
+        self.heartbeat(ctx);
+        self.send(ctx, &ServerMessage::Hello {
+            version: PROTOCOL_VERSION,
+            authenticated: self.user.is_some(),
+        });
+
+        if self.user.is_some() {
+            self.authenticated(ctx);
+        } else {
+            ctx.run_later(AUTH_TIMEOUT, |act, ctx| {
+                if act.user.is_none() {
+                    act.close_unauthenticated(ctx, None, "Authentication timed out");
+                }
+            });
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        println!("WebSocket session stopped: {}", self.id);
+        self.hub.do_send(Disconnect { session_id: self.id });
+    }
+}
+
+impl WebSocketSession {
+    // Ping the client every interval and close the connection once it has been silent too long
+    fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.last_seen) > act.client_timeout {
+                println!("WebSocket session {} timed out", act.id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Away,
+                    description: Some("Heartbeat timed out".to_string()),
+                }));
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, message: &ServerMessage<'_>) {
+        if let Some(frame) = message.to_frame() {
+            ctx.text(frame);
+        }
+    }
+
+    fn send_ack(&self, ctx: &mut ws::WebsocketContext<Self>, id: Option<String>, request: &'static str) {
+        self.send(ctx, &ServerMessage::Ack { id, request });
+    }
+
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, id: Option<String>, code: ErrorCode, message: impl Into<String>) {
+        self.send(ctx, &ServerMessage::Error {
+            id,
+            code,
+            message: message.into(),
+        });
+    }
+
+    // Join the hub and drop the connection when the access token expires
+    fn authenticated(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(user) = self.user.clone() else {
+            return;
+        };
+
+        let remaining = (user.token_expires_at - chrono::Utc::now().timestamp()).max(0) as u64;
+        ctx.run_later(Duration::from_secs(remaining), |act, ctx| {
+            act.close_unauthenticated(ctx, None, "Token expired");
+        });
+
+        self.hub.do_send(Connect {
+            session_id: self.id,
+            user,
+            sink: SessionSink::WebSocket(ctx.address()),
+        });
+    }
+
+    // Say why in an error frame before closing, since not every client surfaces close reasons
+    fn close_unauthenticated(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<String>, reason: &str) {
+        self.send_error(ctx, id, ErrorCode::Unauthenticated, reason);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Other(AUTH_FAILED_CLOSE_CODE),
+            description: Some(reason.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    fn handle_text(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: &str) {
+        // Parse as plain JSON first so a frame of unknown type still gets its id echoed back
+        let value = match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => value,
+            Err(e) => {
+                self.send_error(ctx, None, ErrorCode::InvalidMessage, format!("Malformed JSON: {}", e));
+                return;
+            }
+        };
+        let id = value.get("id").and_then(|id| id.as_str()).map(str::to_string);
+
+        let frame = match serde_json::from_value::<ClientFrame>(value) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.send_error(ctx, id, ErrorCode::InvalidMessage, e.to_string());
+                return;
+            }
+        };
+
+        if frame.v != PROTOCOL_VERSION {
+            self.send_error(ctx, frame.id, ErrorCode::UnsupportedVersion, format!(
+                "Protocol version {} is not supported; use {}",
+                frame.v, PROTOCOL_VERSION
+            ));
+            return;
+        }
+
+        let request = frame.message.name();
+        match frame.message {
+            ClientMessage::Authenticate { .. } if self.user.is_some() => {
+                self.send_error(ctx, frame.id, ErrorCode::InvalidMessage, "Already authenticated");
+            }
+            ClientMessage::Authenticate { token } => match AuthUser::from_token(&token, &self.config) {
+                Ok(user) => {
+                    self.user = Some(user);
+                    self.authenticated(ctx);
+                    self.send_ack(ctx, frame.id, request);
+                }
+                Err(_) => self.close_unauthenticated(ctx, frame.id, "Invalid token"),
+            },
+            _ if self.user.is_none() => {
+                self.close_unauthenticated(ctx, frame.id, "Authentication required");
+            }
+            ClientMessage::Subscribe { topic, since } => self.subscribe(ctx, frame.id, topic, since),
+            ClientMessage::Unsubscribe { topic } => {
+                println!("Session {} unsubscribed from {:?}", self.id, topic);
+                self.hub.do_send(Unsubscribe { session_id: self.id, topic });
+                self.send_ack(ctx, frame.id, request);
+            }
+            ClientMessage::LocationUpdate { location } => self.report_location(ctx, frame.id, location),
+        }
+    }
+
+    // Drivers report their own position; the writer acknowledges once the fix is stored
+    fn report_location(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<String>, location: UpdateLocationRequest) {
+        let Some(user) = &self.user else {
+            return;
+        };
+
+        if user.role != UserRole::Driver {
+            self.send_error(ctx, id, ErrorCode::Forbidden, "Only drivers can report locations");
+            return;
+        }
+        if user.id != location.driver_id {
+            self.send_error(ctx, id, ErrorCode::Forbidden, "Can only update your own location");
+            return;
+        }
+
+        self.writer.do_send(IngestLocation {
+            location,
+            recorded_at: chrono::Utc::now(),
+            session: ctx.address(),
+            request_id: id,
+        });
+    }
+
+    // Subscriptions are checked against the caller's role before the hub hears of them; the ack
+    // is only sent once the hub has the topic
+    fn subscribe(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<String>, topic: Topic, since: Option<i64>) {
+        let Some(user) = self.user.clone() else {
+            return;
+        };
+        let pool = self.pool.clone();
+
+        let authorization = async move { authorize_topic(&pool, &user, topic).await };
+        ctx.spawn(authorization.into_actor(self).map(move |result, act, ctx| match result {
+            Ok(()) => {
+                println!("Session {} subscribed to {:?}", act.id, topic);
+                act.hub.do_send(Subscribe { session_id: act.id, topics: vec![topic], since });
+                act.send_ack(ctx, id, "subscribe");
+            }
+            Err((code, message)) => act.send_error(ctx, id, code, message),
+        }));
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
+    fn handle(
+        &mut self,
+        msg: Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut Self::Context,
+    ) {
+        if msg.is_ok() {
+            self.last_seen = Instant::now();
+        }
+
+        match msg {
+            Ok(ws::Message::Text(text)) => self.handle_text(ctx, &text),
+            Ok(ws::Message::Binary(_)) => {
+                self.send_error(ctx, None, ErrorCode::InvalidMessage, "Binary frames are not supported");
+            }
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Handler<Reply> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reply, ctx: &mut Self::Context) {
+        match msg.result {
+            Ok(()) => self.send_ack(ctx, msg.id, msg.request),
+            Err((code, message)) => self.send_error(ctx, msg.id, code, message),
+        }
+    }
+}
+
+impl Handler<Evict> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Evict, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("Client is not keeping up with updates".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<Revoked> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Revoked, ctx: &mut Self::Context) {
+        self.close_unauthenticated(ctx, None, "Token revoked");
+    }
+}
+
+impl Handler<Push> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Push, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+// What each role may follow: staff the whole fleet, drivers their own routes, vehicles and
+// positions, customers the routes carrying their shipments
+pub async fn authorize_topic(pool: &DbPool, user: &AuthUser, topic: Topic) -> Result<(), (ErrorCode, &'static str)> {
+    let query = match (user.role, topic) {
+        (UserRole::Admin | UserRole::Manager | UserRole::Dispatcher, _) => return Ok(()),
+        (UserRole::Driver, Topic::Driver(driver_id)) if driver_id == user.id => return Ok(()),
+        (UserRole::Driver, Topic::Route(route_id)) => sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM routes WHERE id = $1 AND driver_id = $2)"
+        )
+        .bind(route_id),
+        (UserRole::Driver, Topic::Vehicle(vehicle_id)) => sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM vehicles WHERE id = $1 AND driver_id = $2)"
+        )
+        .bind(vehicle_id),
+        (UserRole::Driver, _) => {
+            return Err((ErrorCode::Forbidden, "Drivers can only follow their own routes and vehicles"));
+        }
+        (UserRole::Customer, Topic::Route(route_id)) => sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM route_stops s
+                JOIN route_stop_cargo sc ON sc.stop_id = s.id
+                JOIN cargo c ON c.id = sc.cargo_id
+                WHERE s.route_id = $1 AND (c.shipper_id = $2 OR c.consignee_id = $2)
+            )
+            "#
+        )
+        .bind(route_id),
+        (UserRole::Customer, _) => {
+            return Err((ErrorCode::Forbidden, "Customers can only follow routes carrying their shipments"));
+        }
+    };
+
+    let allowed = query
+        .bind(user.id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            (ErrorCode::Internal, "Database error")
+        })?;
+
+    if allowed {
+        Ok(())
+    } else {
+        Err((ErrorCode::Forbidden, "Not allowed to follow this topic"))
+    }
+}
+
+// The access token may come from the `token` query parameter, the `Authorization` header or,
+// preferably since URLs end up in logs, an `authenticate` message sent right after connecting
+pub async fn ws_index(
+    req: actix_web::HttpRequest,
+    stream: actix_web::web::Payload,
+    hub: actix_web::web::Data<Addr<TrackingHub>>,
+    writer: actix_web::web::Data<Addr<LocationWriter>>,
+    pool: actix_web::web::Data<DbPool>,
+    config: actix_web::web::Data<Config>,
+) -> Result<actix_web::HttpResponse, actix_web::Error> {
+    let query = actix_web::web::Query::<WsAuthQuery>::from_query(req.query_string())
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid query string"))?;
+
+    let user = match &query.token {
+        Some(token) => Some(AuthUser::from_token(token, &config)?),
+        None if req.headers().contains_key("Authorization") => Some(AuthUser::from_request_headers(&req)?),
+        None => None,
+    };
+
+    let session = WebSocketSession {
+        id: Uuid::new_v4(),
+        hub: hub.get_ref().clone(),
+        writer: writer.get_ref().clone(),
+        pool: pool.get_ref().clone(),
+        config: config.clone(),
+        user,
+        heartbeat_interval: Duration::from_secs(config.ws_heartbeat_interval_secs),
+        client_timeout: Duration::from_secs(config.ws_client_timeout_secs),
+        send_buffer: config.ws_send_buffer,
+        last_seen: Instant::now(),
+    };
+
+    ws::start(session, &req, stream)
+}